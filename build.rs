@@ -2,6 +2,7 @@ use std::path::Path;
 
 use base64::prelude::*;
 use k256::ecdsa::SigningKey;
+use k256::pkcs8::{EncodePrivateKey, LineEnding};
 use serde::Serialize;
 
 // use rand::thread_rng;
@@ -13,7 +14,37 @@ struct DeviceConfig {
     lock_char_uuid: String,
     meta_char_uuid: String,
     logs_char_uuid: String,
+    admin_char_uuid: String,
+    ota_ctrl_char_uuid: String,
+    ota_data_char_uuid: String,
+    proximity_char_uuid: String,
+    command_char_uuid: String,
+    response_char_uuid: String,
     open_time_in_ms: u32,
+    adv_rotation_in_s: u32,
+    admin_requires_bond: bool,
+    lock_requires_encryption: bool,
+    status_led: String,
+    status_led_on_time_in_ms: u32,
+    status_led_gap_in_ms: u32,
+    status_led_brightness: u8,
+    buzzer: bool,
+    buzzer_quiet_from_hour: u8,
+    buzzer_quiet_until_hour: u8,
+    buzzer_door_open_alert_in_s: u32,
+    buzzer_passage_alert_in_s: u32,
+    exit_button: bool,
+    exit_button_long_press: String,
+    exit_button_long_press_in_ms: u32,
+    key_switch: bool,
+    door_contact: bool,
+    wiegand: bool,
+    nfc: String,
+    nfc_desfire_aid: u32,
+    two_factor: bool,
+    dual_auth_window_in_s: u32,
+    duress_alarm: bool,
+    duress_alarm_time_in_s: u32,
     mac: String,
     priv_key: String,
 }
@@ -24,7 +55,64 @@ pub const SERVICE_UID: &str = "5f9b34fb-0000-1000-8000-00805f9b34fb";
 pub const LOCK_CHAR_UID: &str = "00000000-DEAD-BEEF-0001-000000000000";
 pub const META_CHAR_UID: &str = "00000000-DEAD-BEEF-0002-000000000000";
 pub const LOGS_CHAR_UID: &str = "00000000-DEAD-BEEF-0003-000000000000";
+pub const ADMIN_CHAR_UID: &str = "00000000-DEAD-BEEF-0004-000000000000";
+pub const OTA_CTRL_CHAR_UID: &str = "00000000-DEAD-BEEF-0005-000000000000";
+pub const OTA_DATA_CHAR_UID: &str = "00000000-DEAD-BEEF-0006-000000000000";
+pub const PROXIMITY_CHAR_UID: &str = "00000000-DEAD-BEEF-0007-000000000000";
+pub const COMMAND_CHAR_UID: &str = "00000000-DEAD-BEEF-0008-000000000000";
+pub const RESPONSE_CHAR_UID: &str = "00000000-DEAD-BEEF-0009-000000000000";
 pub const OPEN_TIME: u32 = 2000;
+/// advertise from a resolvable private address & rotate it together with the status nonce this
+/// often (0 advertises from the public address)
+pub const ADV_ROTATION: u32 = 0;
+/// admin & firmware update operations require a bonded LESC link (the passkey is blinked by the
+/// status LED)
+pub const ADMIN_REQUIRE_BOND: bool = false;
+/// unlocking requires an encrypted link
+pub const LOCK_REQUIRE_ENCRYPTION: bool = false;
+/// the status LED: "gpio" (plain LED), "pwm" (plain LED, dimmed) or "ws2812" (RGB)
+pub const STATUS_LED: &str = "gpio";
+/// length of a single blink & of the gap between blinks
+pub const STATUS_LED_ON_TIME: u32 = 500;
+pub const STATUS_LED_GAP_TIME: u32 = 200;
+/// 0-255; ignored by a "gpio" LED
+pub const STATUS_LED_BRIGHTNESS: u8 = 255;
+/// a passive buzzer on gpio18 (tones for unlocks, denials, lockouts & a door left open)
+pub const BUZZER: bool = false;
+/// the buzzer is muted during these UTC hours (equal values disable the quiet hours)
+pub const BUZZER_QUIET_FROM: u8 = 22;
+pub const BUZZER_QUIET_UNTIL: u8 = 6;
+/// alert if the door contact reports the door open for this many seconds (0 disables the alert)
+pub const BUZZER_DOOR_OPEN_ALERT: u32 = 60;
+/// alert if the passage mode holds the gate open for this many seconds (0 disables the alert)
+pub const BUZZER_PASSAGE_ALERT: u32 = 0;
+/// a request-to-exit button on gpio19 (to GND) opening the gate from inside
+pub const EXIT_BUTTON: bool = false;
+/// holding the exit button: "none" (opens the gate), "passage" (toggles holding the gate open) or
+/// "reboot"
+pub const EXIT_BUTTON_LONG_PRESS: &str = "none";
+pub const EXIT_BUTTON_LONG_PRESS_TIME: u32 = 3000;
+/// a key switch on gpio21 (to GND); while closed unlocking over BLE is disabled
+pub const KEY_SWITCH: bool = false;
+/// a door contact on gpio4 (to GND while the door is closed, e.g. a reed switch)
+pub const DOOR_CONTACT: bool = false;
+/// a Wiegand card reader or keypad with D0 on gpio22 & D1 on gpio23
+pub const WIEGAND: bool = false;
+/// a PN532 NFC reader: "none", "i2c" (SDA gpio25, SCL gpio26) or "spi" (SCK gpio14, MOSI gpio13,
+/// MISO gpio27, SS gpio32)
+pub const NFC: &str = "none";
+/// the DESFire application whose key 0 authenticates enrolled cards
+pub const NFC_DESFIRE_AID: u32 = 0x584147;
+/// signed requests only open the gate together with the user's PIN (entered in the app or on the
+/// Wiegand keypad)
+pub const TWO_FACTOR: bool = false;
+/// two distinct users have to sign a request within this many seconds to open the gate (0 disables
+/// dual authorization)
+pub const DUAL_AUTH_WINDOW: u32 = 0;
+/// an alarm output on gpio33, driven high after an opening with a duress key or PIN
+pub const DURESS_ALARM: bool = false;
+/// the alarm output is reset after this many seconds (0 keeps it high until the next reboot)
+pub const DURESS_ALARM_TIME: u32 = 300;
 pub const MAC_ADDRESS: &str = "3c:61:05:30:b3:ce"; // TODO: change this mac address
 
 fn main() -> color_eyre::Result<()> {
@@ -32,6 +120,32 @@ fn main() -> color_eyre::Result<()> {
     // fn main() {
     embuild::espidf::sysenv::output();
 
+    // expose the git hash to the firmware (shown in the metadata characteristic)
+    // HEAD only changes on a checkout, a commit moves the branch it points to (a loose ref,
+    // the packed refs or, for a packed branch, only the reflog)
+    println!("cargo::rerun-if-changed=.git/HEAD");
+    let head_ref = std::fs::read_to_string(".git/HEAD")
+        .ok()
+        .and_then(|x| x.strip_prefix("ref: ").map(|x| x.trim().to_owned()));
+    for path in head_ref
+        .map(|x| format!(".git/{}", x))
+        .into_iter()
+        .chain([".git/packed-refs".to_owned(), ".git/logs/HEAD".to_owned()])
+    {
+        // a missing file would rerun the script on every build
+        if Path::new(&path).exists() {
+            println!("cargo::rerun-if-changed={}", path);
+        }
+    }
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|x| x.status.success())
+        .map(|x| String::from_utf8_lossy(&x.stdout).trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo::rustc-env=GAX_GIT_HASH={}", git_hash);
+
     let key_pair_dir = Path::new("./config_dir/");
     if !key_pair_dir.exists() {
         std::fs::create_dir_all(key_pair_dir)?;
@@ -51,13 +165,56 @@ fn main() -> color_eyre::Result<()> {
         std::fs::write(priv_key, signing_key.to_bytes())?;
         std::fs::write(pub_key, signing_key.verifying_key().to_sec1_bytes())?;
     }
+
+    // the release key signs firmware images for the OTA update; keep it out of the QR-Code
+    let release_priv_key = key_pair_dir.join("release_private.pem");
+    let release_pub_key = key_pair_dir.join("release_public.bin");
+    if !release_priv_key.exists() || !release_pub_key.exists() {
+        println!("[⚙️] Generating new release keypair");
+        let signing_key = SigningKey::random(&mut rand::thread_rng());
+        let pem = signing_key
+            .to_pkcs8_pem(LineEnding::LF)
+            .map_err(|x| color_eyre::eyre::eyre!("Failed to encode the release key: {x}"))?;
+        std::fs::write(release_priv_key, pem.as_bytes())?;
+        std::fs::write(release_pub_key, signing_key.verifying_key().to_sec1_bytes())?;
+    }
     let config_struct = DeviceConfig {
         ble_name: BLE_NAME.to_owned(),
         service_uuid: SERVICE_UID.to_owned(),
         lock_char_uuid: LOCK_CHAR_UID.to_owned(),
         meta_char_uuid: META_CHAR_UID.to_owned(),
         logs_char_uuid: LOGS_CHAR_UID.to_owned(),
+        admin_char_uuid: ADMIN_CHAR_UID.to_owned(),
+        ota_ctrl_char_uuid: OTA_CTRL_CHAR_UID.to_owned(),
+        ota_data_char_uuid: OTA_DATA_CHAR_UID.to_owned(),
+        proximity_char_uuid: PROXIMITY_CHAR_UID.to_owned(),
+        command_char_uuid: COMMAND_CHAR_UID.to_owned(),
+        response_char_uuid: RESPONSE_CHAR_UID.to_owned(),
         open_time_in_ms: OPEN_TIME.to_owned(),
+        adv_rotation_in_s: ADV_ROTATION,
+        admin_requires_bond: ADMIN_REQUIRE_BOND,
+        lock_requires_encryption: LOCK_REQUIRE_ENCRYPTION,
+        status_led: STATUS_LED.to_owned(),
+        status_led_on_time_in_ms: STATUS_LED_ON_TIME,
+        status_led_gap_in_ms: STATUS_LED_GAP_TIME,
+        status_led_brightness: STATUS_LED_BRIGHTNESS,
+        buzzer: BUZZER,
+        buzzer_quiet_from_hour: BUZZER_QUIET_FROM,
+        buzzer_quiet_until_hour: BUZZER_QUIET_UNTIL,
+        buzzer_door_open_alert_in_s: BUZZER_DOOR_OPEN_ALERT,
+        buzzer_passage_alert_in_s: BUZZER_PASSAGE_ALERT,
+        exit_button: EXIT_BUTTON,
+        exit_button_long_press: EXIT_BUTTON_LONG_PRESS.to_owned(),
+        exit_button_long_press_in_ms: EXIT_BUTTON_LONG_PRESS_TIME,
+        key_switch: KEY_SWITCH,
+        door_contact: DOOR_CONTACT,
+        wiegand: WIEGAND,
+        nfc: NFC.to_owned(),
+        nfc_desfire_aid: NFC_DESFIRE_AID,
+        two_factor: TWO_FACTOR,
+        dual_auth_window_in_s: DUAL_AUTH_WINDOW,
+        duress_alarm: DURESS_ALARM,
+        duress_alarm_time_in_s: DURESS_ALARM_TIME,
         mac: MAC_ADDRESS.to_owned(),
         priv_key: base64::prelude::BASE64_STANDARD
             .encode(std::fs::read("./config_dir/private.bin")?),
@@ -0,0 +1,583 @@
+//! The firmware's MQTT bridge (topic routing, payloads & the unlock results) without any ESP-IDF
+//! dependency, so it can be tested on the host against a [`sim::Broker`]
+
+use std::fmt::Debug;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+pub mod sim;
+
+/// The metadata is republished this often
+pub const META_INTERVAL: Duration = Duration::from_secs(60);
+/// Topic (below the gax prefix) receiving Home Assistant's `lock`/`button` commands
+pub const HA_COMMAND_TOPIC: &str = "ha/command";
+/// Upper bound for the client id of a peer, see [`Bridge`]
+pub const MAX_PEER_LEN: usize = 32;
+
+/// The connection to the broker; `EspMqttClient` on the device, an in-memory one on the host
+pub trait MqttClient {
+    type Error: Debug;
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), Self::Error>;
+    /// publishes with QoS 1
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// What the bridge acts upon; the firmware's challenges, credentials & logs
+///
+/// `peer` is the client id of the app asking (see [`Bridge`]); challenges & lockouts are kept per
+/// peer, so one client can't evict the challenges of another or get it locked out.
+pub trait Device {
+    /// a new hex encoded challenge for `peer`
+    fn issue_challenge(&self, peer: &str) -> Option<String>;
+    /// verifies `challenge | [PIN] | DER signature` (answering a challenge of `peer`) exactly like
+    /// the lock characteristic and requests the opening; failures are logged by the device
+    fn unlock(&self, peer: &str, response: &[u8]) -> Result<(), i32>;
+    /// requests an opening without a signature (Home Assistant's command from a trusted broker)
+    fn unlock_unsigned(&self) -> Result<(), i32>;
+    /// logs a denied request
+    fn deny(&self, code: i32);
+    /// the metadata json
+    fn metadata(&self) -> String;
+    /// the retained Home Assistant discovery configs `(topic, payload)`
+    fn discovery_configs(&self, discovery_prefix: &str, prefix: &str) -> Vec<(String, String)>;
+}
+
+/// What the bridge publishes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// a log entry as json; `duress` entries are published to `alarm` as well
+    Log {
+        entry: String,
+        duress: bool,
+    },
+    GateOpened,
+    GateClosed,
+}
+
+/// The work of the bridge's task
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Task {
+    Connected,
+    Received(String, Vec<u8>),
+    Event(Event),
+}
+
+#[derive(Debug, Clone)]
+pub struct HomeAssistant {
+    pub discovery_prefix: String,
+    /// unsigned commands are honored (Home Assistant can't sign a challenge)
+    pub trusted_commands: bool,
+}
+
+/// Topics (below `prefix`):
+/// - `status` `online`/`offline` (retained, last will)
+/// - `state` `{"state": "open"|"closed"}` (retained)
+/// - `log` every log entry as json
+/// - `alarm` the log entries of openings with a duress credential
+/// - `meta` the metadata json (retained)
+/// - `challenge/request/<client id>` (subscribed) -> a hex challenge is published to
+///   `challenge/<client id>`
+/// - `unlock/<client id>` (subscribed) hex `challenge | DER signature` answering a challenge of
+///   the same client id, same semantics as the lock characteristic; the result is published to
+///   `unlock/result/<client id>`
+///
+/// The client id is the app's MQTT client id (at most [`MAX_PEER_LEN`] bytes); a broker ACL like
+/// mosquitto's `pattern write gax/+/unlock/%c` ties it to the authenticated client.
+/// - `ha/command` (subscribed with `home_assistant`) Home Assistant's `UNLOCK` command; only
+///   honored if `trusted_commands` is set as Home Assistant can't sign a challenge
+pub struct Bridge<C: MqttClient, D: Device> {
+    client: C,
+    device: D,
+    prefix: String,
+    home_assistant: Option<HomeAssistant>,
+    gate_open: bool,
+}
+
+impl<C: MqttClient, D: Device> Bridge<C, D> {
+    pub fn new(
+        client: C,
+        device: D,
+        prefix: String,
+        home_assistant: Option<HomeAssistant>,
+    ) -> Self {
+        Bridge {
+            client,
+            device,
+            prefix,
+            home_assistant,
+            gate_open: false,
+        }
+    }
+
+    /// handles the tasks until the sender is gone, publishing the metadata every [`META_INTERVAL`]
+    pub fn run(self, rx: Receiver<Task>) {
+        self.run_every(rx, META_INTERVAL)
+    }
+
+    /// like [`Bridge::run`] with another metadata interval; a steady stream of tasks doesn't
+    /// postpone the metadata
+    pub fn run_every(mut self, rx: Receiver<Task>, meta_interval: Duration) {
+        let mut next_meta = Instant::now() + meta_interval;
+        loop {
+            let timeout = next_meta.saturating_duration_since(Instant::now());
+            let res = match rx.recv_timeout(timeout) {
+                Ok(task) => self.handle(task),
+                Err(RecvTimeoutError::Timeout) => {
+                    next_meta = Instant::now() + meta_interval;
+                    self.publish_meta()
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(why) = res {
+                log::error!("[❌] MQTT error: {:?}", why);
+            }
+        }
+    }
+
+    pub fn handle(&mut self, task: Task) -> Result<(), C::Error> {
+        match task {
+            Task::Connected => self.on_connected(),
+            Task::Received(topic, data) => self.on_received(&topic, &data),
+            Task::Event(event) => self.on_event(event),
+        }
+    }
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<(), C::Error> {
+        let topic = format!("{}/{}", self.prefix, topic);
+        self.client.publish(&topic, retain, payload)
+    }
+
+    pub fn publish_meta(&mut self) -> Result<(), C::Error> {
+        let meta = self.device.metadata();
+        self.publish("meta", true, meta.as_bytes())
+    }
+
+    fn on_connected(&mut self) -> Result<(), C::Error> {
+        log::info!("[📡] Connected to the MQTT broker");
+        let mut topics = vec!["challenge/request/+", "unlock/+"];
+        if self.home_assistant.is_some() {
+            topics.push(HA_COMMAND_TOPIC);
+        }
+        for topic in topics {
+            let topic = format!("{}/{}", self.prefix, topic);
+            self.client.subscribe(&topic)?;
+        }
+        if let Some(home_assistant) = self.home_assistant.as_ref() {
+            let configs = self
+                .device
+                .discovery_configs(&home_assistant.discovery_prefix, &self.prefix);
+            for (topic, payload) in configs {
+                self.client.publish(&topic, true, payload.as_bytes())?;
+            }
+        }
+        self.publish("status", true, b"online")?;
+        self.publish_state()?;
+        self.publish_meta()
+    }
+
+    fn publish_state(&mut self) -> Result<(), C::Error> {
+        let state = if self.gate_open { "open" } else { "closed" };
+        self.publish(
+            "state",
+            true,
+            json!({ "state": state }).to_string().as_bytes(),
+        )
+    }
+
+    fn on_event(&mut self, event: Event) -> Result<(), C::Error> {
+        match event {
+            Event::Log { entry, duress } => {
+                if duress {
+                    self.publish("alarm", false, entry.as_bytes())?;
+                }
+                self.publish("log", false, entry.as_bytes())
+            }
+            Event::GateOpened => {
+                self.gate_open = true;
+                self.publish_state()
+            }
+            Event::GateClosed => {
+                self.gate_open = false;
+                self.publish_state()
+            }
+        }
+    }
+
+    fn on_received(&mut self, topic: &str, data: &[u8]) -> Result<(), C::Error> {
+        let Some(topic) = topic.strip_prefix(&self.prefix) else {
+            return Ok(());
+        };
+        if self.home_assistant.is_some() && topic == format!("/{}", HA_COMMAND_TOPIC) {
+            self.on_ha_command(data);
+            return Ok(());
+        }
+        if let Some(peer) = topic
+            .strip_prefix("/challenge/request/")
+            .filter(|x| is_peer(x))
+        {
+            return match self.device.issue_challenge(peer) {
+                Some(x) => self.publish(&format!("challenge/{}", peer), false, x.as_bytes()),
+                None => Ok(()),
+            };
+        }
+        if let Some(peer) = topic.strip_prefix("/unlock/").filter(|x| is_peer(x)) {
+            let result = std::str::from_utf8(data)
+                .ok()
+                .and_then(|x| hex_string_to_bytes(x.trim()))
+                .ok_or(0x01)
+                .and_then(|x| self.device.unlock(peer, &x));
+            let topic = format!("unlock/result/{}", peer);
+            return self.publish(&topic, false, unlock_result(result).as_bytes());
+        }
+        Ok(())
+    }
+
+    /// Home Assistant's commands can't be signed; they are only accepted from a trusted broker
+    fn on_ha_command(&self, data: &[u8]) {
+        if data != b"UNLOCK" {
+            // the gate closes on its own
+            return;
+        }
+        if !self
+            .home_assistant
+            .as_ref()
+            .is_some_and(|x| x.trusted_commands)
+        {
+            log::error!("[⛔] (mqtt) Unsigned Home Assistant unlock denied");
+            self.device.deny(0x0C);
+            return;
+        }
+        if let Err(code) = self.device.unlock_unsigned() {
+            log::error!("[❌] (mqtt) Home Assistant unlock failed: {:#04x}", code);
+        }
+    }
+}
+
+/// a client id fit for a topic level
+fn is_peer(peer: &str) -> bool {
+    !peer.is_empty() && peer.len() <= MAX_PEER_LEN && !peer.contains(['/', '+', '#'])
+}
+
+/// the payload published to `unlock/result/<client id>`
+pub fn unlock_result(result: Result<(), i32>) -> String {
+    match result {
+        Ok(_) => json!({"ok": true}),
+        Err(code) => json!({"ok": false, "error": code}),
+    }
+    .to_string()
+}
+
+fn hex_string_to_bytes(str: &str) -> Option<Vec<u8>> {
+    if str.len() % 2 != 0 {
+        return None;
+    }
+    (0..str.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(str.get(x..x + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::{channel, Receiver};
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::sim::{Broker, BrokerClient};
+
+    const PREFIX: &str = "gax/device";
+
+    /// records what the bridge asked for
+    #[derive(Default)]
+    struct FakeDevice {
+        unlocks: Mutex<Vec<(String, Vec<u8>)>>,
+        unsigned_unlocks: Mutex<usize>,
+        denied: Mutex<Vec<i32>>,
+        unlock_error: Option<i32>,
+    }
+
+    impl Device for Arc<FakeDevice> {
+        fn issue_challenge(&self, peer: &str) -> Option<String> {
+            Some(format!("c0ffee{}", peer.len()))
+        }
+
+        fn unlock(&self, peer: &str, response: &[u8]) -> Result<(), i32> {
+            let unlock = (peer.to_owned(), response.to_vec());
+            self.unlocks.lock().unwrap().push(unlock);
+            self.unlock_error.map_or(Ok(()), Err)
+        }
+
+        fn unlock_unsigned(&self) -> Result<(), i32> {
+            *self.unsigned_unlocks.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn deny(&self, code: i32) {
+            self.denied.lock().unwrap().push(code);
+        }
+
+        fn metadata(&self) -> String {
+            r#"{"device_id":"device"}"#.to_owned()
+        }
+
+        fn discovery_configs(&self, discovery_prefix: &str, prefix: &str) -> Vec<(String, String)> {
+            vec![(
+                format!("{}/lock/gax_device/gate/config", discovery_prefix),
+                format!(r#"{{"command_topic":"{}/{}"}}"#, prefix, HA_COMMAND_TOPIC),
+            )]
+        }
+    }
+
+    struct Setup {
+        broker: Broker,
+        device: Arc<FakeDevice>,
+        bridge: Bridge<BrokerClient, Arc<FakeDevice>>,
+        rx: Receiver<Task>,
+    }
+
+    impl Setup {
+        fn new(device: FakeDevice, home_assistant: Option<HomeAssistant>) -> Self {
+            let broker = Broker::default();
+            let device = Arc::new(device);
+            let (tx, rx) = channel();
+            let client = broker.connect(tx);
+            let bridge = Bridge::new(client, device.clone(), PREFIX.to_owned(), home_assistant);
+            let mut setup = Setup {
+                broker,
+                device,
+                bridge,
+                rx,
+            };
+            setup.pump();
+            setup
+        }
+
+        /// lets the bridge handle everything the broker delivered
+        fn pump(&mut self) {
+            while let Ok(task) = self.rx.try_recv() {
+                self.bridge.handle(task).unwrap();
+            }
+        }
+
+        /// publishes as another client (e.g. the app) & lets the bridge handle it
+        fn publish(&mut self, topic: &str, payload: &[u8]) {
+            self.broker
+                .publish(&format!("{}/{}", PREFIX, topic), payload);
+            self.pump();
+        }
+
+        fn messages(&self, topic: &str) -> Vec<String> {
+            self.broker
+                .messages(&format!("{}/{}", PREFIX, topic))
+                .into_iter()
+                .map(|x| String::from_utf8(x).unwrap())
+                .collect()
+        }
+
+        fn retained(&self, topic: &str) -> Option<String> {
+            self.broker
+                .retained(&format!("{}/{}", PREFIX, topic))
+                .map(|x| String::from_utf8(x).unwrap())
+        }
+    }
+
+    fn trusted(trusted_commands: bool) -> Option<HomeAssistant> {
+        Some(HomeAssistant {
+            discovery_prefix: "homeassistant".to_owned(),
+            trusted_commands,
+        })
+    }
+
+    #[test]
+    fn connecting_announces_the_device() {
+        let setup = Setup::new(FakeDevice::default(), None);
+        assert_eq!(setup.retained("status").as_deref(), Some("online"));
+        assert_eq!(
+            setup.retained("state").as_deref(),
+            Some(r#"{"state":"closed"}"#)
+        );
+        assert_eq!(
+            setup.retained("meta").as_deref(),
+            Some(r#"{"device_id":"device"}"#)
+        );
+        assert!(setup.broker.is_subscribed("gax/device/challenge/request/+"));
+        assert!(setup.broker.is_subscribed("gax/device/unlock/+"));
+        assert!(!setup.broker.is_subscribed("gax/device/ha/command"));
+    }
+
+    #[test]
+    fn challenge_requests_are_answered_per_peer() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        setup.publish("challenge/request/app", b"");
+        setup.publish("challenge/request/phone-2", b"");
+        assert_eq!(setup.messages("challenge/app"), vec!["c0ffee3"]);
+        assert_eq!(setup.messages("challenge/phone-2"), vec!["c0ffee7"]);
+    }
+
+    #[test]
+    fn signed_unlocks_are_forwarded_to_the_device() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        setup.publish("unlock/app", b" 0102ff\n");
+        assert_eq!(
+            *setup.device.unlocks.lock().unwrap(),
+            vec![("app".to_owned(), vec![0x01, 0x02, 0xFF])]
+        );
+        assert_eq!(setup.messages("unlock/result/app"), vec![r#"{"ok":true}"#]);
+    }
+
+    #[test]
+    fn requests_without_a_valid_client_id_are_ignored() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        let long = "x".repeat(MAX_PEER_LEN + 1);
+        for peer in ["", "a/b", &long] {
+            setup
+                .bridge
+                .handle(Task::Received(
+                    format!("{}/unlock/{}", PREFIX, peer),
+                    b"0102".to_vec(),
+                ))
+                .unwrap();
+            setup
+                .bridge
+                .handle(Task::Received(
+                    format!("{}/challenge/request/{}", PREFIX, peer),
+                    Vec::new(),
+                ))
+                .unwrap();
+        }
+        setup.publish("unlock", b"0102");
+        setup.publish("challenge/request", b"");
+        assert!(setup.device.unlocks.lock().unwrap().is_empty());
+        assert!(setup.messages("challenge").is_empty());
+    }
+
+    #[test]
+    fn unlock_errors_are_published() {
+        let mut setup = Setup::new(
+            FakeDevice {
+                unlock_error: Some(0x04),
+                ..Default::default()
+            },
+            None,
+        );
+        setup.publish("unlock/app", b"0102");
+        setup.publish("unlock/app", b"not hex");
+        assert_eq!(setup.device.unlocks.lock().unwrap().len(), 1);
+        assert_eq!(
+            setup.messages("unlock/result/app"),
+            vec![r#"{"error":4,"ok":false}"#, r#"{"error":1,"ok":false}"#]
+        );
+    }
+
+    #[test]
+    fn other_prefixes_are_ignored() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        setup.broker.publish("gax/other/unlock/app", b"0102");
+        setup.broker.publish("gax/device-2/unlock/app", b"0102");
+        setup.pump();
+        assert!(setup.device.unlocks.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn gate_events_update_the_retained_state() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        setup.bridge.handle(Task::Event(Event::GateOpened)).unwrap();
+        assert_eq!(
+            setup.retained("state").as_deref(),
+            Some(r#"{"state":"open"}"#)
+        );
+        setup.bridge.handle(Task::Event(Event::GateClosed)).unwrap();
+        assert_eq!(
+            setup.retained("state").as_deref(),
+            Some(r#"{"state":"closed"}"#)
+        );
+    }
+
+    #[test]
+    fn duress_entries_raise_the_alarm() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        let log = |entry: &str, duress| {
+            Task::Event(Event::Log {
+                entry: entry.to_owned(),
+                duress,
+            })
+        };
+        setup.bridge.handle(log("first", false)).unwrap();
+        setup.bridge.handle(log("second", true)).unwrap();
+        assert_eq!(setup.messages("log"), vec!["first", "second"]);
+        assert_eq!(setup.messages("alarm"), vec!["second"]);
+        assert_eq!(setup.retained("log"), None);
+    }
+
+    #[test]
+    fn home_assistant_is_announced() {
+        let setup = Setup::new(FakeDevice::default(), trusted(false));
+        assert!(setup.broker.is_subscribed("gax/device/ha/command"));
+        assert_eq!(
+            setup
+                .broker
+                .retained("homeassistant/lock/gax_device/gate/config")
+                .map(|x| String::from_utf8(x).unwrap())
+                .as_deref(),
+            Some(r#"{"command_topic":"gax/device/ha/command"}"#)
+        );
+    }
+
+    #[test]
+    fn untrusted_home_assistant_commands_are_denied() {
+        let mut setup = Setup::new(FakeDevice::default(), trusted(false));
+        setup.publish("ha/command", b"UNLOCK");
+        assert_eq!(*setup.device.unsigned_unlocks.lock().unwrap(), 0);
+        assert_eq!(*setup.device.denied.lock().unwrap(), vec![0x0C]);
+    }
+
+    #[test]
+    fn trusted_home_assistant_commands_unlock() {
+        let mut setup = Setup::new(FakeDevice::default(), trusted(true));
+        setup.publish("ha/command", b"LOCK");
+        assert_eq!(*setup.device.unsigned_unlocks.lock().unwrap(), 0);
+        setup.publish("ha/command", b"UNLOCK");
+        assert_eq!(*setup.device.unsigned_unlocks.lock().unwrap(), 1);
+        assert!(setup.device.denied.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn steady_events_dont_postpone_the_metadata() {
+        let broker = Broker::default();
+        // the subscriptions' inbox, unused: nothing is subscribed without a connect
+        let (inbox, _) = channel();
+        let client = broker.connect(inbox);
+        let device = Arc::new(FakeDevice::default());
+        let bridge = Bridge::new(client, device, PREFIX.to_owned(), None);
+        let (tx, rx) = channel();
+        let runner = std::thread::spawn(move || bridge.run_every(rx, Duration::from_millis(50)));
+
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(500) {
+            tx.send(Task::Event(Event::GateOpened)).unwrap();
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        drop(tx);
+        runner.join().unwrap();
+        let published = broker.messages("gax/device/meta").len();
+        assert!(published >= 5, "published the metadata {} times", published);
+    }
+
+    #[test]
+    fn home_assistant_commands_need_the_integration() {
+        let mut setup = Setup::new(FakeDevice::default(), None);
+        setup.broker.publish("gax/device/ha/command", b"UNLOCK");
+        setup
+            .bridge
+            .handle(Task::Received(
+                "gax/device/ha/command".to_owned(),
+                b"UNLOCK".to_vec(),
+            ))
+            .unwrap();
+        assert_eq!(*setup.device.unsigned_unlocks.lock().unwrap(), 0);
+        assert!(setup.device.denied.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+//! An in-memory broker standing in for mosquitto, to test the bridge without a network
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::{MqttClient, Task};
+
+#[derive(Debug, Clone)]
+struct Message {
+    topic: String,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct State {
+    /// `(topic filter, inbox of the subscribed client)`; filters support the single level
+    /// wildcard `+`
+    subscriptions: Vec<(String, Sender<Task>)>,
+    retained: HashMap<String, Vec<u8>>,
+    /// everything published, in order
+    messages: Vec<Message>,
+}
+
+impl State {
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) {
+        if retain {
+            self.retained.insert(topic.to_owned(), payload.to_vec());
+        }
+        self.messages.push(Message {
+            topic: topic.to_owned(),
+            payload: payload.to_vec(),
+        });
+        for (_, inbox) in self.subscriptions.iter().filter(|(x, _)| matches(x, topic)) {
+            let _ = inbox.send(Task::Received(topic.to_owned(), payload.to_vec()));
+        }
+    }
+}
+
+/// whether `topic` matches the subscription `filter`
+fn matches(filter: &str, topic: &str) -> bool {
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (None, None) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(x), Some(y)) if x == y => {}
+            _ => return false,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+pub struct Broker {
+    state: Arc<Mutex<State>>,
+}
+
+impl Broker {
+    /// connects a bridge; the connect & the messages of its subscriptions are delivered to
+    /// `inbox` like the firmware's connection task does
+    pub fn connect(&self, inbox: Sender<Task>) -> BrokerClient {
+        let _ = inbox.send(Task::Connected);
+        BrokerClient {
+            broker: self.clone(),
+            inbox,
+        }
+    }
+
+    /// publishes as another client (e.g. the app), without retaining the message
+    pub fn publish(&self, topic: &str, payload: &[u8]) {
+        self.state.lock().unwrap().publish(topic, false, payload);
+    }
+
+    /// every payload published to `topic`, in order
+    pub fn messages(&self, topic: &str) -> Vec<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .messages
+            .iter()
+            .filter(|x| x.topic == topic)
+            .map(|x| x.payload.clone())
+            .collect()
+    }
+
+    /// the retained payload of `topic`
+    pub fn retained(&self, topic: &str) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().retained.get(topic).cloned()
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.subscriptions.iter().any(|(x, _)| x == topic)
+    }
+}
+
+/// A bridge's connection to the [`Broker`]
+pub struct BrokerClient {
+    broker: Broker,
+    inbox: Sender<Task>,
+}
+
+impl MqttClient for BrokerClient {
+    type Error = std::convert::Infallible;
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), Self::Error> {
+        let mut state = self.broker.state.lock().unwrap();
+        state
+            .subscriptions
+            .push((topic.to_owned(), self.inbox.clone()));
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<(), Self::Error> {
+        self.broker
+            .state
+            .lock()
+            .unwrap()
+            .publish(topic, retain, payload);
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+//! The card logic of the firmware's NFC reader (APDUs, the DESFire authentication and the GAX
+//! app's phone signing) without any ESP-IDF dependency, so it can be tested on the host against
+//! a [`sim::SimulatedReader`]
+
+use std::fmt::Display;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes128;
+use rand::{thread_rng, Rng};
+
+pub mod sim;
+
+/// The AID of the GAX app's host card emulation (proprietary, "GAX01")
+pub const PHONE_AID: [u8; 6] = [0xF0, 0x47, 0x41, 0x58, 0x30, 0x31];
+/// Asks the phone to sign the challenge in the body
+pub const INS_SIGN: u8 = 0x10;
+const SW_OK: u16 = 0x9000;
+const SW_DESFIRE_OK: u16 = 0x9100;
+const SW_DESFIRE_MORE: u16 = 0x91AF;
+const SW_DESFIRE_AUTHENTICATION_ERROR: u16 = 0x91AE;
+
+/// A tag in the reader's field
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tag {
+    pub uid: Vec<u8>,
+    /// the tag speaks ISO 14443-4 (DESFire cards & phones)
+    pub iso_dep: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NfcError {
+    /// the reader failed
+    Reader(String),
+    /// the reader or the tag didn't answer in time
+    Timeout,
+    /// the answer couldn't be parsed
+    Protocol(&'static str),
+    /// the tag answered with an unexpected status word
+    Status(u16),
+    /// the tag failed the mutual authentication
+    Authentication,
+}
+
+impl NfcError {
+    /// `0x1A` if the tag is rejected (counts towards the lockout), `0x1B` for a failed transfer
+    /// (e.g. the tag has been pulled away too early)
+    pub fn code(&self) -> i32 {
+        match self {
+            NfcError::Status(_) | NfcError::Authentication => 0x1A,
+            NfcError::Reader(_) | NfcError::Timeout | NfcError::Protocol(_) => 0x1B,
+        }
+    }
+}
+
+impl Display for NfcError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NfcError::Reader(x) => write!(f, "reader error: {}", x),
+            NfcError::Timeout => write!(f, "timeout"),
+            NfcError::Protocol(x) => write!(f, "protocol error: {}", x),
+            NfcError::Status(x) => write!(f, "status {:#06x}", x),
+            NfcError::Authentication => write!(f, "authentication failed"),
+        }
+    }
+}
+
+/// A contactless reader; the PN532 on the device, a simulated one on the host
+pub trait NfcReader: Send {
+    /// the tag in the field, if there is one
+    fn poll(&mut self) -> Result<Option<Tag>, NfcError>;
+    /// exchanges an APDU with the tag returned by the last poll
+    fn transceive(&mut self, apdu: &[u8]) -> Result<Vec<u8>, NfcError>;
+}
+
+/// sends an ISO 7816-4 APDU; returns the status word & the response data
+fn apdu(
+    reader: &mut dyn NfcReader,
+    cla: u8,
+    ins: u8,
+    p1: u8,
+    data: &[u8],
+) -> Result<(u16, Vec<u8>), NfcError> {
+    let mut apdu = vec![cla, ins, p1, 0x00];
+    if !data.is_empty() {
+        apdu.push(data.len() as u8);
+        apdu.extend_from_slice(data);
+    }
+    apdu.push(0x00);
+    let mut res = reader.transceive(&apdu)?;
+    if res.len() < 2 {
+        return Err(NfcError::Protocol("response too short"));
+    }
+    let sw = res.split_off(res.len() - 2);
+    Ok((u16::from_be_bytes([sw[0], sw[1]]), res))
+}
+
+/// a DESFire command in ISO 7816-4 wrapping
+fn desfire(reader: &mut dyn NfcReader, cmd: u8, data: &[u8]) -> Result<(u16, Vec<u8>), NfcError> {
+    apdu(reader, 0x90, cmd, 0x00, data)
+}
+
+fn expect(sw: u16, expected: u16) -> Result<(), NfcError> {
+    match sw {
+        x if x == expected => Ok(()),
+        x => Err(NfcError::Status(x)),
+    }
+}
+
+/// AES-CBC with a zero IV, as used by the DESFire authentication
+fn cbc_encrypt(cipher: &Aes128, data: &mut [u8]) {
+    let mut iv: [u8; 16] = [0x0; 16];
+    for block in data.chunks_exact_mut(16) {
+        block.iter_mut().zip(iv.iter()).for_each(|(x, y)| *x ^= y);
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        iv.copy_from_slice(block);
+    }
+}
+
+fn cbc_decrypt(cipher: &Aes128, data: &mut [u8]) {
+    let mut iv: [u8; 16] = [0x0; 16];
+    for block in data.chunks_exact_mut(16) {
+        let mut encrypted: [u8; 16] = [0x0; 16];
+        encrypted.copy_from_slice(block);
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        block.iter_mut().zip(iv.iter()).for_each(|(x, y)| *x ^= y);
+        iv = encrypted;
+    }
+}
+
+/// selects the DESFire application `aid` and authenticates with its key 0 (`AuthenticateEV2First`,
+/// AES-128); both sides prove that they know the key
+pub fn authenticate_desfire(
+    reader: &mut dyn NfcReader,
+    aid: u32,
+    key: &[u8; 16],
+) -> Result<(), NfcError> {
+    let (sw, _) = desfire(reader, 0x5A, &aid.to_le_bytes()[..3])?;
+    expect(sw, SW_DESFIRE_OK)?;
+    let cipher = Aes128::new(key.into());
+
+    // key number & no capabilities
+    let (sw, mut rnd_b) = desfire(reader, 0x71, &[0x00, 0x00])?;
+    expect(sw, SW_DESFIRE_MORE)?;
+    if rnd_b.len() != 16 {
+        return Err(NfcError::Protocol("invalid RndB"));
+    }
+    cbc_decrypt(&cipher, &mut rnd_b);
+    rnd_b.rotate_left(1);
+    let mut rnd_a: [u8; 16] = [0x0; 16];
+    thread_rng().fill(&mut rnd_a);
+    let mut token: Vec<u8> = rnd_a.iter().chain(rnd_b.iter()).copied().collect();
+    cbc_encrypt(&cipher, &mut token);
+
+    // TI (4) | RndA' (16) | PDcap2 (6) | PCDcap2 (6)
+    let (sw, mut res) = desfire(reader, 0xAF, &token)?;
+    if sw == SW_DESFIRE_AUTHENTICATION_ERROR {
+        return Err(NfcError::Authentication);
+    }
+    expect(sw, SW_DESFIRE_OK)?;
+    if res.len() != 32 {
+        return Err(NfcError::Protocol("invalid authentication response"));
+    }
+    cbc_decrypt(&cipher, &mut res);
+    rnd_a.rotate_left(1);
+    if res[4..20] != rnd_a {
+        return Err(NfcError::Authentication);
+    }
+    Ok(())
+}
+
+/// lets a phone running the GAX app (host card emulation) sign the challenge; `None` if the tag
+/// isn't such a phone, otherwise the DER signature
+pub fn phone_signature(
+    reader: &mut dyn NfcReader,
+    challenge: &[u8; 64],
+) -> Result<Option<Vec<u8>>, NfcError> {
+    // SELECT by name
+    let (sw, _) = apdu(reader, 0x00, 0xA4, 0x04, &PHONE_AID)?;
+    if sw != SW_OK {
+        return Ok(None);
+    }
+    let (sw, signature) = apdu(reader, 0x80, INS_SIGN, 0x00, challenge)?;
+    expect(sw, SW_OK)?;
+    Ok(Some(signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{SimulatedReader, SimulatedTag};
+
+    const AID: u32 = 0x474158;
+    const KEY: [u8; 16] = [0x42; 16];
+    const CHALLENGE: [u8; 64] = [0x07; 64];
+
+    fn card(aid: u32, key: [u8; 16]) -> SimulatedReader {
+        SimulatedReader::new(SimulatedTag::Desfire {
+            uid: vec![0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+            aid,
+            key,
+        })
+    }
+
+    fn scripted(responses: &[&[u8]]) -> SimulatedReader {
+        SimulatedReader::new(SimulatedTag::Scripted {
+            uid: vec![0x04, 0x01, 0x02, 0x03],
+            responses: responses.iter().map(|x| x.to_vec()).collect(),
+        })
+    }
+
+    #[test]
+    fn desfire_authentication_succeeds_with_the_enrolled_key() {
+        let mut reader = card(AID, KEY);
+        assert!(reader.poll().unwrap().unwrap().iso_dep);
+        assert_eq!(authenticate_desfire(&mut reader, AID, &KEY), Ok(()));
+    }
+
+    #[test]
+    fn desfire_authentication_fails_with_another_key() {
+        let mut reader = card(AID, [0x24; 16]);
+        let result = authenticate_desfire(&mut reader, AID, &KEY);
+        assert_eq!(result, Err(NfcError::Authentication));
+        assert_eq!(result.unwrap_err().code(), 0x1A);
+    }
+
+    #[test]
+    fn desfire_authentication_fails_without_the_application() {
+        let mut reader = card(0x010203, KEY);
+        assert_eq!(
+            authenticate_desfire(&mut reader, AID, &KEY),
+            Err(NfcError::Status(0x91A0))
+        );
+    }
+
+    #[test]
+    fn desfire_authentication_rejects_a_card_not_proving_the_key() {
+        // the card accepts the reader's token but can't return RndA'
+        let mut reader = scripted(&[
+            &[0x91, 0x00],
+            &[[0x0; 16].as_slice(), &[0x91, 0xAF]].concat(),
+            &[[0x0; 32].as_slice(), &[0x91, 0x00]].concat(),
+        ]);
+        assert_eq!(
+            authenticate_desfire(&mut reader, AID, &KEY),
+            Err(NfcError::Authentication)
+        );
+    }
+
+    #[test]
+    fn phone_signs_the_challenge() {
+        let mut reader = SimulatedReader::new(SimulatedTag::Phone {
+            uid: vec![0x08, 0x01, 0x02, 0x03],
+            sign: Box::new(|challenge| challenge.iter().rev().copied().collect()),
+        });
+        let expected: Vec<u8> = CHALLENGE.iter().rev().copied().collect();
+        assert_eq!(phone_signature(&mut reader, &CHALLENGE), Ok(Some(expected)));
+    }
+
+    #[test]
+    fn card_is_not_a_phone() {
+        let mut reader = card(AID, KEY);
+        assert_eq!(phone_signature(&mut reader, &CHALLENGE), Ok(None));
+    }
+
+    #[test]
+    fn short_responses_are_transfer_errors() {
+        let mut reader = scripted(&[&[0x90]]);
+        let result = authenticate_desfire(&mut reader, AID, &KEY);
+        assert_eq!(result, Err(NfcError::Protocol("response too short")));
+        assert_eq!(result.unwrap_err().code(), 0x1B);
+
+        let mut reader = scripted(&[&[]]);
+        assert_eq!(
+            phone_signature(&mut reader, &CHALLENGE),
+            Err(NfcError::Protocol("response too short"))
+        );
+    }
+
+    #[test]
+    fn truncated_rnd_b_is_rejected() {
+        let mut reader = scripted(&[&[0x91, 0x00], &[0x01, 0x02, 0x03, 0x91, 0xAF]]);
+        assert_eq!(
+            authenticate_desfire(&mut reader, AID, &KEY),
+            Err(NfcError::Protocol("invalid RndB"))
+        );
+    }
+
+    #[test]
+    fn pulled_away_tag_times_out() {
+        let mut reader = scripted(&[&[0x91, 0x00]]);
+        assert_eq!(
+            authenticate_desfire(&mut reader, AID, &KEY),
+            Err(NfcError::Timeout)
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! A reader with a simulated tag in its field, to test the card logic without the PN532
+
+use std::collections::VecDeque;
+
+use aes::cipher::KeyInit;
+use aes::Aes128;
+use rand::{thread_rng, Rng};
+
+use crate::{cbc_decrypt, cbc_encrypt, NfcError, NfcReader, Tag, INS_SIGN, PHONE_AID};
+
+/// Answers the GAX app's sign command with the signature of the challenge
+pub type Sign = Box<dyn FnMut(&[u8]) -> Vec<u8> + Send>;
+
+/// What is held to the simulated reader
+pub enum SimulatedTag {
+    /// a DESFire EV2 card with the AES key 0 of the application `aid`
+    Desfire {
+        uid: Vec<u8>,
+        aid: u32,
+        key: [u8; 16],
+    },
+    /// a phone running the GAX app
+    Phone { uid: Vec<u8>, sign: Sign },
+    /// answers the APDUs with `responses` in order and times out once they are used up, as if the
+    /// tag has been pulled away
+    Scripted {
+        uid: Vec<u8>,
+        responses: VecDeque<Vec<u8>>,
+    },
+}
+
+impl SimulatedTag {
+    fn uid(&self) -> &[u8] {
+        match self {
+            SimulatedTag::Desfire { uid, .. }
+            | SimulatedTag::Phone { uid, .. }
+            | SimulatedTag::Scripted { uid, .. } => uid,
+        }
+    }
+}
+
+pub struct SimulatedReader {
+    tag: Option<SimulatedTag>,
+    /// the DESFire application selected by the last `SelectApplication`
+    selected: Option<u32>,
+    /// the card's challenge of a running authentication
+    rnd_b: Option<[u8; 16]>,
+}
+
+impl SimulatedReader {
+    pub fn new(tag: SimulatedTag) -> Self {
+        SimulatedReader {
+            tag: Some(tag),
+            selected: None,
+            rnd_b: None,
+        }
+    }
+
+    /// takes the tag out of the field
+    pub fn remove(&mut self) {
+        self.tag = None;
+    }
+
+    fn desfire(&mut self, aid: u32, key: [u8; 16], ins: u8, data: &[u8]) -> Vec<u8> {
+        let cipher = Aes128::new(&key.into());
+        match ins {
+            // SelectApplication
+            0x5A if data.len() == 3 => {
+                let requested = u32::from_le_bytes([data[0], data[1], data[2], 0x00]);
+                if requested != aid {
+                    return vec![0x91, 0xA0];
+                }
+                self.selected = Some(requested);
+                vec![0x91, 0x00]
+            }
+            // AuthenticateEV2First
+            0x71 if self.selected == Some(aid) => {
+                let mut rnd_b: [u8; 16] = [0x0; 16];
+                thread_rng().fill(&mut rnd_b);
+                self.rnd_b = Some(rnd_b);
+                let mut res = rnd_b.to_vec();
+                cbc_encrypt(&cipher, &mut res);
+                res.extend_from_slice(&[0x91, 0xAF]);
+                res
+            }
+            // the reader's token
+            0xAF if data.len() == 32 => {
+                let Some(mut rnd_b) = self.rnd_b.take() else {
+                    return vec![0x91, 0xCA];
+                };
+                let mut token = data.to_vec();
+                cbc_decrypt(&cipher, &mut token);
+                rnd_b.rotate_left(1);
+                if token[16..] != rnd_b {
+                    return vec![0x91, 0xAE];
+                }
+                let mut rnd_a = token[..16].to_vec();
+                rnd_a.rotate_left(1);
+                // TI | RndA' | PDcap2 | PCDcap2
+                let mut res = vec![0x0; 32];
+                thread_rng().fill(&mut res[..4]);
+                res[4..20].copy_from_slice(&rnd_a);
+                cbc_encrypt(&cipher, &mut res);
+                res.extend_from_slice(&[0x91, 0x00]);
+                res
+            }
+            _ => vec![0x91, 0x1C],
+        }
+    }
+}
+
+impl NfcReader for SimulatedReader {
+    fn poll(&mut self) -> Result<Option<Tag>, NfcError> {
+        Ok(self.tag.as_ref().map(|x| Tag {
+            uid: x.uid().to_vec(),
+            iso_dep: true,
+        }))
+    }
+
+    fn transceive(&mut self, apdu: &[u8]) -> Result<Vec<u8>, NfcError> {
+        if apdu.len() < 4 {
+            return Err(NfcError::Protocol("APDU too short"));
+        }
+        let (cla, ins) = (apdu[0], apdu[1]);
+        let data = match apdu.get(4) {
+            Some(len) if apdu.len() > 5 => apdu
+                .get(5..5 + *len as usize)
+                .ok_or(NfcError::Protocol("invalid APDU length"))?,
+            _ => &[],
+        };
+        match self.tag.as_mut() {
+            None => Err(NfcError::Timeout),
+            Some(SimulatedTag::Scripted { responses, .. }) => {
+                responses.pop_front().ok_or(NfcError::Timeout)
+            }
+            Some(SimulatedTag::Phone { sign, .. }) => Ok(match (cla, ins) {
+                (0x00, 0xA4) if data == PHONE_AID => vec![0x90, 0x00],
+                (0x00, 0xA4) => vec![0x6A, 0x82],
+                (0x80, INS_SIGN) => {
+                    let mut res = sign(data);
+                    res.extend_from_slice(&[0x90, 0x00]);
+                    res
+                }
+                _ => vec![0x6D, 0x00],
+            }),
+            Some(SimulatedTag::Desfire { aid, key, .. }) => {
+                let (aid, key) = (*aid, *key);
+                Ok(match cla {
+                    0x90 => self.desfire(aid, key, ins, data),
+                    // DESFire cards don't know the phone's application
+                    _ => vec![0x6A, 0x82],
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,513 @@
+//! The firmware's webhook notifier (event filters, bodies, signatures & the retry queue) without
+//! any ESP-IDF dependency, so it can be tested on the host against a [`sim::HttpServer`]
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+
+pub mod sim;
+
+/// Undelivered notifications kept for a retry; the oldest ones are dropped first
+pub const MAX_QUEUED: usize = 16;
+/// The persisted queue is kept below this size (serialized); the oldest notifications are dropped
+/// first
+pub const MAX_QUEUE_SIZE: usize = 8 * 1024;
+/// A notification is dropped after this many failed deliveries
+pub const MAX_ATTEMPTS: u8 = 10;
+/// The queued notifications are retried this often
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+/// Header carrying the hex encoded HMAC-SHA256 of the body (if a secret is configured)
+pub const SIGNATURE_HEADER: &str = "X-Gax-Signature";
+
+/// The outcome of a log entry, as far as the event filters are concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Successful,
+    Failed(i32),
+    Duress,
+    /// entries no filter but the catch-all matches (e.g. a restart)
+    Other,
+}
+
+/// The log entries a webhook can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// a successful opening
+    Unlock,
+    /// any rejected attempt
+    Failed,
+    /// a rejected attempt with an invalid signature or an unknown/replayed challenge
+    Attack,
+    /// an opening with a duress credential
+    Duress,
+}
+impl WebhookEvent {
+    pub fn matches(&self, status: &Status) -> bool {
+        match (self, status) {
+            (WebhookEvent::Unlock, Status::Successful) => true,
+            (WebhookEvent::Failed, Status::Failed(_)) => true,
+            (WebhookEvent::Attack, Status::Failed(x)) => matches!(x, 0x02 | 0x04 | 0x06),
+            (WebhookEvent::Duress, Status::Duress) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A webhook target, part of the firmware's network config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookTarget {
+    pub url: String,
+    /// defaults to all events
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// signs the body with HMAC-SHA256 (see [`SIGNATURE_HEADER`])
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+impl WebhookTarget {
+    pub fn wants(&self, status: &Status) -> bool {
+        self.events.is_empty() || self.events.iter().any(|x| x.matches(status))
+    }
+}
+
+/// A log entry to notify about
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub status: Status,
+    /// the entry's json fields: `credential`, `user`, `second_user`, `status` & `source`
+    pub fields: Value,
+    /// seconds since the epoch (0 while the clock isn't set)
+    pub timestamp: u64,
+}
+
+/// the posted json: the entry's fields with the `device_id` & the `timestamp`
+pub fn body(device_id: &str, entry: &Entry) -> String {
+    let mut body = match &entry.fields {
+        Value::Object(x) => x.clone(),
+        _ => Default::default(),
+    };
+    body.insert("device_id".to_owned(), Value::from(device_id));
+    body.insert("timestamp".to_owned(), Value::from(entry.timestamp));
+    Value::Object(body).to_string()
+}
+
+/// the hex encoded HMAC-SHA256 of `body`
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("[❌] HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|x| format!("{:0>2x}", x))
+        .collect()
+}
+
+/// A pending notification
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub url: String,
+    pub body: String,
+    pub signature: Option<String>,
+    pub attempts: u8,
+}
+
+/// POSTs the notifications; `EspHttpConnection` on the device, a plain TCP client on the host
+pub trait Transport {
+    type Error: Debug;
+
+    /// the HTTP status the target answered
+    fn post(
+        &mut self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<u16, Self::Error>;
+}
+
+/// Persists the retry queue across reboots; NVS on the device
+pub trait QueueStore {
+    type Error: Debug;
+
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Self::Error>;
+    fn store(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// POSTs every matching log entry as json (see [`body`]) to the targets
+///
+/// Failed deliveries are kept in a bounded queue (persisted by the [`QueueStore`]) and retried by
+/// [`Notifier::retry`].
+pub struct Notifier<T: Transport, S: QueueStore> {
+    targets: Vec<WebhookTarget>,
+    device_id: String,
+    queue: Vec<Delivery>,
+    transport: T,
+    store: S,
+}
+
+impl<T: Transport, S: QueueStore> Notifier<T, S> {
+    /// a notifier continuing with the queue persisted in `store`
+    pub fn new(
+        targets: Vec<WebhookTarget>,
+        device_id: String,
+        transport: T,
+        store: S,
+    ) -> Result<Self, S::Error> {
+        let mut notifier = Notifier {
+            targets,
+            device_id,
+            queue: Vec::new(),
+            transport,
+            store,
+        };
+        notifier.load_queue()?;
+        Ok(notifier)
+    }
+
+    /// the pending notifications, oldest first
+    pub fn queue(&self) -> &[Delivery] {
+        &self.queue
+    }
+
+    pub fn notify(&mut self, entry: &Entry) {
+        let body = body(&self.device_id, entry);
+        let deliveries: Vec<Delivery> = self
+            .targets
+            .iter()
+            .filter(|x| x.wants(&entry.status))
+            .map(|x| Delivery {
+                url: x.url.clone(),
+                signature: x.secret.as_ref().map(|secret| sign(secret, &body)),
+                body: body.clone(),
+                attempts: 0,
+            })
+            .collect();
+        let mut queue_changed = false;
+        for delivery in deliveries {
+            if !self.deliver(delivery) {
+                queue_changed = true;
+            }
+        }
+        if queue_changed {
+            self.store_queue();
+        }
+    }
+
+    pub fn retry(&mut self) {
+        if self.queue.is_empty() {
+            return;
+        }
+        log::info!("[🪝] Retrying {} webhook notifications", self.queue.len());
+        for delivery in std::mem::take(&mut self.queue) {
+            self.deliver(delivery);
+        }
+        self.store_queue();
+    }
+
+    /// sends `delivery` or queues it for a retry; returns whether it has been delivered
+    fn deliver(&mut self, mut delivery: Delivery) -> bool {
+        match self.post(&delivery) {
+            Ok(_) => return true,
+            Err(why) => log::error!(
+                "[❌] Failed to deliver the webhook to '{}': {}",
+                delivery.url,
+                why
+            ),
+        }
+        delivery.attempts += 1;
+        if delivery.attempts >= MAX_ATTEMPTS {
+            log::error!("[❌] Giving up on the webhook to '{}'", delivery.url);
+            return false;
+        }
+        if serde_json::to_vec(&[&delivery]).map_or(true, |x| x.len() > MAX_QUEUE_SIZE) {
+            log::error!(
+                "[❌] The webhook to '{}' is too large to queue",
+                delivery.url
+            );
+            return false;
+        }
+        if self.queue.len() >= MAX_QUEUED {
+            self.queue.remove(0);
+        }
+        self.queue.push(delivery);
+        false
+    }
+
+    fn post(&mut self, delivery: &Delivery) -> Result<(), String> {
+        let len = delivery.body.len().to_string();
+        let mut headers = vec![
+            ("Content-Type", "application/json"),
+            ("Content-Length", len.as_str()),
+        ];
+        if let Some(signature) = delivery.signature.as_ref() {
+            headers.push((SIGNATURE_HEADER, signature.as_str()));
+        }
+        match self
+            .transport
+            .post(&delivery.url, &headers, delivery.body.as_bytes())
+        {
+            Ok(200..=299) => Ok(()),
+            Ok(status) => Err(format!("answered {}", status)),
+            Err(why) => Err(format!("{:?}", why)),
+        }
+    }
+
+    fn load_queue(&mut self) -> Result<(), S::Error> {
+        if let Some(x) = self.store.load()? {
+            match serde_json::from_slice(&x) {
+                Ok(x) => self.queue = x,
+                Err(why) => log::error!("[❌] Failed to parse the webhook queue: {}", why),
+            }
+        }
+        if !self.queue.is_empty() {
+            log::info!(
+                "[🪝] Loaded {} pending webhook notifications",
+                self.queue.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn store_queue(&mut self) {
+        let data = loop {
+            let data = match serde_json::to_vec(&self.queue) {
+                Ok(x) => x,
+                Err(why) => {
+                    log::error!("[❌] Failed to serialize the webhook queue: {}", why);
+                    return;
+                }
+            };
+            if data.len() <= MAX_QUEUE_SIZE || self.queue.is_empty() {
+                break data;
+            }
+            let dropped = self.queue.remove(0);
+            log::error!(
+                "[❌] Webhook queue too large, dropping the webhook to '{}'",
+                dropped.url
+            );
+        };
+        if let Err(why) = self.store.store(&data) {
+            log::error!("[❌] Failed to store the webhook queue: {:?}", why);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::sim::{HttpServer, HttpTransport, MemoryStore};
+
+    const DEVICE_ID: &str = "3c6105";
+    const SECRET: &str = "s3cr3t";
+
+    fn entry(status: Status, timestamp: u64) -> Entry {
+        Entry {
+            status,
+            fields: json!({
+                "credential": {"type": "ble", "id": "00:11:22:33:44:55"},
+                "user": 3,
+                "second_user": null,
+                "status": "Successful",
+                "source": "Ble",
+            }),
+            timestamp,
+        }
+    }
+
+    fn target(url: String, events: Vec<WebhookEvent>, secret: Option<&str>) -> WebhookTarget {
+        WebhookTarget {
+            url,
+            events,
+            secret: secret.map(|x| x.to_owned()),
+        }
+    }
+
+    fn notifier(
+        targets: Vec<WebhookTarget>,
+        store: &MemoryStore,
+    ) -> Notifier<HttpTransport, MemoryStore> {
+        Notifier::new(targets, DEVICE_ID.to_owned(), HttpTransport, store.clone()).unwrap()
+    }
+
+    #[test]
+    fn events_filter_the_entries() {
+        assert!(WebhookEvent::Unlock.matches(&Status::Successful));
+        assert!(!WebhookEvent::Unlock.matches(&Status::Duress));
+        assert!(WebhookEvent::Failed.matches(&Status::Failed(0x18)));
+        assert!(WebhookEvent::Attack.matches(&Status::Failed(0x04)));
+        assert!(!WebhookEvent::Attack.matches(&Status::Failed(0x18)));
+        assert!(WebhookEvent::Duress.matches(&Status::Duress));
+
+        let all = target("http://x".to_owned(), vec![], None);
+        assert!(all.wants(&Status::Other));
+        let failed = target("http://x".to_owned(), vec![WebhookEvent::Failed], None);
+        assert!(failed.wants(&Status::Failed(0x0E)));
+        assert!(!failed.wants(&Status::Successful));
+    }
+
+    #[test]
+    fn body_carries_the_entry_device_and_time() {
+        let body: Value =
+            serde_json::from_str(&body(DEVICE_ID, &entry(Status::Successful, 42))).unwrap();
+        assert_eq!(body["device_id"], DEVICE_ID);
+        assert_eq!(body["timestamp"], 42);
+        assert_eq!(body["user"], 3);
+        assert_eq!(body["credential"]["type"], "ble");
+        assert_eq!(body["source"], "Ble");
+    }
+
+    #[test]
+    fn signed_deliveries_carry_the_hmac() {
+        let server = HttpServer::start(204);
+        let store = MemoryStore::default();
+        let mut notifier = notifier(vec![target(server.url(), vec![], Some(SECRET))], &store);
+        notifier.notify(&entry(Status::Successful, 1));
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.header("content-type"), Some("application/json"));
+        let body = String::from_utf8(request.body.clone()).unwrap();
+        assert_eq!(body, self::body(DEVICE_ID, &entry(Status::Successful, 1)));
+        // computed independently of `sign`
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(&request.body);
+        let expected: String = mac
+            .finalize()
+            .into_bytes()
+            .iter()
+            .map(|x| format!("{:02x}", x))
+            .collect();
+        assert_eq!(request.header(SIGNATURE_HEADER), Some(expected.as_str()));
+        assert!(notifier.queue().is_empty());
+    }
+
+    #[test]
+    fn unsigned_deliveries_have_no_signature() {
+        let server = HttpServer::start(200);
+        let store = MemoryStore::default();
+        let mut notifier = notifier(vec![target(server.url(), vec![], None)], &store);
+        notifier.notify(&entry(Status::Successful, 1));
+        assert_eq!(server.requests()[0].header(SIGNATURE_HEADER), None);
+    }
+
+    #[test]
+    fn only_wanted_entries_are_posted() {
+        let server = HttpServer::start(200);
+        let store = MemoryStore::default();
+        let targets = vec![target(server.url(), vec![WebhookEvent::Duress], None)];
+        let mut notifier = notifier(targets, &store);
+        notifier.notify(&entry(Status::Successful, 1));
+        notifier.notify(&entry(Status::Failed(0x04), 2));
+        assert!(server.requests().is_empty());
+        notifier.notify(&entry(Status::Duress, 3));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn failed_deliveries_are_retried_and_persisted() {
+        let server = HttpServer::start(503);
+        let store = MemoryStore::default();
+        let targets = vec![target(server.url(), vec![], Some(SECRET))];
+        let mut notifier = notifier(targets.clone(), &store);
+        notifier.notify(&entry(Status::Successful, 1));
+        assert_eq!(notifier.queue().len(), 1);
+        assert_eq!(notifier.queue()[0].attempts, 1);
+
+        // the queue survives a reboot
+        let mut notifier = self::notifier(targets, &store);
+        assert_eq!(notifier.queue().len(), 1);
+
+        server.set_status(200);
+        notifier.retry();
+        assert!(notifier.queue().is_empty());
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body, requests[1].body);
+        assert_eq!(
+            requests[0].header(SIGNATURE_HEADER),
+            requests[1].header(SIGNATURE_HEADER)
+        );
+        let stored: Vec<Delivery> = serde_json::from_slice(&store.data().unwrap()).unwrap();
+        assert!(stored.is_empty());
+    }
+
+    #[test]
+    fn unreachable_targets_count_as_failures() {
+        let store = MemoryStore::default();
+        let url = HttpServer::unused_url();
+        let mut notifier = notifier(vec![target(url, vec![], None)], &store);
+        notifier.notify(&entry(Status::Successful, 1));
+        assert_eq!(notifier.queue().len(), 1);
+    }
+
+    #[test]
+    fn the_oldest_deliveries_are_dropped() {
+        let server = HttpServer::start(500);
+        let store = MemoryStore::default();
+        let mut notifier = notifier(vec![target(server.url(), vec![], None)], &store);
+        for timestamp in 0..MAX_QUEUED as u64 + 4 {
+            notifier.notify(&entry(Status::Successful, timestamp));
+        }
+        let timestamps: Vec<u64> = notifier
+            .queue()
+            .iter()
+            .map(|x| serde_json::from_str::<Value>(&x.body).unwrap()["timestamp"].as_u64())
+            .map(Option::unwrap)
+            .collect();
+        assert_eq!(timestamps, (4..MAX_QUEUED as u64 + 4).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_stored_queue_is_bounded_by_size() {
+        let server = HttpServer::start(500);
+        let store = MemoryStore::default();
+        let mut notifier = notifier(vec![target(server.url(), vec![], None)], &store);
+        let mut large = entry(Status::Successful, 0);
+        large.fields["user"] = Value::from("x".repeat(MAX_QUEUE_SIZE / 4));
+        for timestamp in 0..4 {
+            large.timestamp = timestamp;
+            notifier.notify(&large);
+        }
+        let stored = store.data().unwrap();
+        assert!(stored.len() <= MAX_QUEUE_SIZE);
+        let stored: Vec<Delivery> = serde_json::from_slice(&stored).unwrap();
+        assert_eq!(stored, notifier.queue());
+        assert!(stored.len() < 4);
+        // the newest ones are kept
+        let last: Value = serde_json::from_str(&stored.last().unwrap().body).unwrap();
+        assert_eq!(last["timestamp"], 3);
+
+        // a single oversized notification isn't queued, without pushing out the others
+        let queued = notifier.queue().to_vec();
+        large.fields["user"] = Value::from("x".repeat(MAX_QUEUE_SIZE));
+        notifier.notify(&large);
+        assert_eq!(notifier.queue(), queued);
+    }
+
+    #[test]
+    fn deliveries_are_given_up_after_the_last_attempt() {
+        let server = HttpServer::start(500);
+        let store = MemoryStore::default();
+        let mut notifier = notifier(vec![target(server.url(), vec![], None)], &store);
+        notifier.notify(&entry(Status::Successful, 1));
+        for _ in 1..MAX_ATTEMPTS - 1 {
+            notifier.retry();
+        }
+        assert_eq!(notifier.queue()[0].attempts, MAX_ATTEMPTS - 1);
+        notifier.retry();
+        assert!(notifier.queue().is_empty());
+        assert_eq!(server.requests().len(), MAX_ATTEMPTS as usize);
+        // nothing is sent once it has been given up
+        notifier.retry();
+        assert_eq!(server.requests().len(), MAX_ATTEMPTS as usize);
+    }
+}
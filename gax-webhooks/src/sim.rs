@@ -0,0 +1,189 @@
+//! A local HTTP receiver standing in for the webhook targets, a plain TCP client & an in-memory
+//! queue store, to test the notifier without the device
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::{QueueStore, Transport};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request the [`HttpServer`] received
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// `(name, value)`, names are lowercase
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        self.headers
+            .iter()
+            .find(|(x, _)| *x == name)
+            .map(|(_, x)| x.as_str())
+    }
+}
+
+/// Records every request & answers it with the configured status
+pub struct HttpServer {
+    port: u16,
+    status: Arc<AtomicU16>,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl HttpServer {
+    /// listens on an ephemeral port of the loopback interface
+    pub fn start(status: u16) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = HttpServer {
+            port,
+            status: Arc::new(AtomicU16::new(status)),
+            requests: Default::default(),
+        };
+        let status = server.status.clone();
+        let requests = server.requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Some(request) = read_request(&stream) {
+                    // recorded before answering, so the client only returns once it's visible
+                    requests.lock().unwrap().push(request);
+                    let status = status.load(Ordering::SeqCst);
+                    let _ = write!(
+                        &stream,
+                        "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                        status
+                    );
+                }
+            }
+        });
+        server
+    }
+
+    /// a url nothing listens on
+    pub fn unused_url() -> String {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        format!("http://127.0.0.1:{}/hook", port)
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://127.0.0.1:{}/hook", self.port)
+    }
+
+    /// the status following requests are answered with
+    pub fn set_status(&self, status: u16) {
+        self.status.store(status, Ordering::SeqCst);
+    }
+
+    /// every request received, in order
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+fn read_request(stream: &TcpStream) -> Option<Request> {
+    stream.set_read_timeout(Some(TIMEOUT)).ok()?;
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.trim().to_lowercase(), value.trim().to_owned()));
+    }
+    let len = headers
+        .iter()
+        .find(|(x, _)| x == "content-length")
+        .and_then(|(_, x)| x.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// A minimal HTTP/1.1 client for `http://host:port/path` urls
+pub struct HttpTransport;
+
+impl Transport for HttpTransport {
+    type Error = std::io::Error;
+
+    fn post(
+        &mut self,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<u16, Self::Error> {
+        let invalid = || std::io::Error::new(std::io::ErrorKind::InvalidInput, url.to_owned());
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (host, path) = match rest.find('/') {
+            Some(x) => (&rest[..x], &rest[x..]),
+            None => (rest, "/"),
+        };
+        let mut stream = TcpStream::connect(host)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        write!(stream, "POST {} HTTP/1.1\r\nHost: {}\r\n", path, host)?;
+        for (name, value) in headers {
+            write!(stream, "{}: {}\r\n", name, value)?;
+        }
+        write!(stream, "Connection: close\r\n\r\n")?;
+        stream.write_all(body)?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        line.split_whitespace()
+            .nth(1)
+            .and_then(|x| x.parse().ok())
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, line.clone()))
+    }
+}
+
+/// The persisted queue, shared between its clones (like a NVS namespace across reboots)
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    data: Arc<Mutex<Option<Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn data(&self) -> Option<Vec<u8>> {
+        self.data.lock().unwrap().clone()
+    }
+}
+
+impl QueueStore for MemoryStore {
+    type Error = std::convert::Infallible;
+
+    fn load(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.data())
+    }
+
+    fn store(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        *self.data.lock().unwrap() = Some(data.to_vec());
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::challenge::{Client, Factor, UnlockRequest};
+use crate::roles::{Operation, Roles};
+use crate::two_factor::TwoFactor;
+
+/// How requests to drive an output are authorized
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OutputPolicy {
+    /// signed requests need the user's PIN as well (see [`TwoFactor`])
+    pub two_factor: bool,
+    /// two distinct users have to sign a request within this window (zero disables the rule)
+    pub dual_authorization: Duration,
+}
+
+/// A signed request waiting for a second user; part of the metadata
+#[derive(Debug, Clone, Serialize)]
+pub struct PendingAuthorization {
+    pub output: &'static str,
+    pub user: Option<u16>,
+    pub expires_in_s: u64,
+}
+
+/// The access control layer between the verified requests and the outputs; the relay of the gate
+/// is the only output so far
+pub struct AccessControl {
+    gate: OutputPolicy,
+    roles: Arc<Roles>,
+    two_factor: Mutex<TwoFactor>,
+    /// the first participant of a dual authorization
+    pending: Mutex<Option<(UnlockRequest, Instant)>>,
+}
+
+impl AccessControl {
+    pub fn new(gate: OutputPolicy, roles: Arc<Roles>) -> Self {
+        AccessControl {
+            gate,
+            roles,
+            two_factor: Mutex::new(TwoFactor::new(gate.two_factor)),
+            pending: Mutex::new(None),
+        }
+    }
+
+    pub fn gate_policy(&self) -> OutputPolicy {
+        self.gate
+    }
+
+    /// nobody can be authorized until a new owner claims the device, see [`Roles::enrollment_mode`]
+    pub fn enrollment_mode(&self) -> bool {
+        self.roles.enrollment_mode()
+    }
+
+    /// the dual authorization waiting for a second user, if there is one
+    pub fn pending(&self) -> Option<PendingAuthorization> {
+        let pending = self.pending.lock().unwrap_or_else(|x| x.into_inner());
+        pending.as_ref().and_then(|(request, since)| {
+            let remaining = self.gate.dual_authorization.checked_sub(since.elapsed())?;
+            Some(PendingAuthorization {
+                output: "gate",
+                user: request.user,
+                expires_in_s: remaining.as_secs(),
+            })
+        })
+    }
+
+    /// the request to carry out, `None` while it waits for another factor or user
+    ///
+    /// Errors with the code the request is logged with:
+    /// - `0x19` the user's role doesn't allow unlocking
+    /// - `0x1E` the gate requires signed requests of two users, cards, PINs & unsigned Home Assistant
+    ///   commands aren't enough
+    /// - see [`TwoFactor::check`]
+    pub fn authorize(&self, request: UnlockRequest) -> Result<Option<UnlockRequest>, i32> {
+        let (client, user) = (request.client.clone(), request.user);
+        self.roles
+            .dispatch(&client, user, Operation::Unlock, move |_| {
+                self.two_factor
+                    .lock()
+                    .unwrap_or_else(|x| x.into_inner())
+                    .check(request)
+            })
+            .and_then(|request| match request {
+                Some(x) => self.dual_authorization(x),
+                None => Ok(None),
+            })
+    }
+
+    fn dual_authorization(&self, request: UnlockRequest) -> Result<Option<UnlockRequest>, i32> {
+        let window = self.gate.dual_authorization;
+        // only the exit button (inside the gate) is exempt, trusted Home Assistant commands
+        // aren't signed by anyone and are rejected like cards
+        if window.is_zero() || request.client == Client::Button {
+            return Ok(Some(request));
+        }
+        if !matches!(request.factor, Factor::Signature | Factor::SignatureAndPin) {
+            log::error!(
+                "[⛔] ({}) Request denied: two users have to sign",
+                request.client
+            );
+            return Err(0x1E);
+        }
+        let mut pending = self.pending.lock().unwrap_or_else(|x| x.into_inner());
+        let first = pending
+            .take()
+            .filter(|(_, since)| since.elapsed() <= window);
+        match first {
+            Some((first, since)) if first.user == request.user => {
+                log::info!(
+                    "[👥] ({}) User {:?} already signed, waiting for a second user",
+                    request.client,
+                    request.user
+                );
+                *pending = Some((first, since));
+                Ok(None)
+            }
+            Some((first, _)) => {
+                log::info!(
+                    "[👥] ({}) User {:?} completed the authorization of user {:?}",
+                    request.client,
+                    request.user,
+                    first.user
+                );
+                Ok(Some(UnlockRequest {
+                    second_user: request.user,
+                    duress: first.duress || request.duress,
+                    ..first
+                }))
+            }
+            None => {
+                log::info!(
+                    "[👥] ({}) User {:?} signed, waiting {}s for a second user",
+                    request.client,
+                    request.user,
+                    window.as_secs()
+                );
+                *pending = Some((request, Instant::now()));
+                Ok(None)
+            }
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::sync::{Arc, Mutex};
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use serde_json::{json, Value};
+
+use crate::challenge::{verify_response, Challenge, Client};
+use crate::credentials::Credentials;
+use crate::factory_reset;
+use crate::proximity::Proximity;
+use crate::roles::{Operation, Role, Roles};
+use crate::security;
+use crate::stats::Stats;
+
+/// Commands accepted by the admin characteristic
+///
+/// Write layout: `challenge (64 byte) | opcode (1 byte) | body length (u16 BE) | body | DER signature`
+/// where the signature covers everything before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdminCommand {
+    ResetStats,
+    /// body: json encoded [`crate::wifi::NetworkConfig`], applied after a reboot
+    #[cfg(feature = "wifi")]
+    SetNetworkConfig,
+    /// body: json encoded [`crate::proximity::ProximitySettings`], applied immediately
+    SetProximity,
+    /// responds with the bonded addresses (only through the command protocol)
+    ListBonds,
+    /// body: the address of the bond to delete; empty to delete all bonds
+    ClearBonds,
+    /// body: json encoded user with its credentials, see [`Credentials::enroll`]
+    EnrollUser,
+    /// body: the id of the user (u16 BE)
+    RemoveUser,
+    /// responds with the enrolled users (only through the command protocol)
+    ListUsers,
+    /// erases the device and reboots into the enrollment mode, see [`factory_reset::wipe`]
+    FactoryReset,
+}
+impl AdminCommand {
+    pub fn from_opcode(opcode: u8) -> Option<Self> {
+        match opcode {
+            0x01 => Some(AdminCommand::ResetStats),
+            #[cfg(feature = "wifi")]
+            0x02 => Some(AdminCommand::SetNetworkConfig),
+            0x03 => Some(AdminCommand::SetProximity),
+            0x04 => Some(AdminCommand::ListBonds),
+            0x05 => Some(AdminCommand::ClearBonds),
+            0x06 => Some(AdminCommand::EnrollUser),
+            0x07 => Some(AdminCommand::RemoveUser),
+            0x08 => Some(AdminCommand::ListUsers),
+            0x09 => Some(AdminCommand::FactoryReset),
+            _ => None,
+        }
+    }
+}
+
+/// Everything an admin command may act upon
+pub struct AdminContext {
+    pub challenges: Arc<Mutex<Vec<Challenge>>>,
+    pub credentials: Arc<Credentials>,
+    pub roles: Arc<Roles>,
+    pub stats: Arc<Stats>,
+    pub proximity: Arc<Proximity>,
+    pub nvs_partition: EspDefaultNvsPartition,
+}
+
+/// verifies and executes a write to the admin characteristic; returns the command's response
+/// (if it has one)
+///
+/// Errors with the code reported to the client:
+/// - `0x09` unknown opcode
+/// - `0x0A` the command failed to execute
+/// - `0x19` the signer's role doesn't allow the command
+/// - see [`verify_response`]
+pub fn handle_admin_write(
+    ctx: &AdminContext,
+    data: &[u8],
+    client: &Client,
+) -> Result<Option<Value>, i32> {
+    if data.len() < 64 + 3 {
+        log::error!("[❌] ({}) Got only {} bytes", client, data.len());
+        return Err(0x01);
+    }
+    let body_len = u16::from_be_bytes([data[65], data[66]]) as usize;
+    let signer = verify_response(
+        &ctx.challenges,
+        &ctx.credentials.keys(),
+        data,
+        3 + body_len,
+        client,
+    )?;
+    let body = &data[67..67 + body_len];
+    let command = match AdminCommand::from_opcode(data[64]) {
+        Some(x) => x,
+        None => {
+            log::error!("[❌] ({}) Unknown admin opcode {:#04x}", client, data[64]);
+            return Err(0x09);
+        }
+    };
+    ctx.roles.dispatch(
+        client,
+        Some(signer.user),
+        Operation::Admin(command),
+        |role| execute(ctx, command, body, client, role.ok_or(0x19)?),
+    )
+}
+
+fn execute(
+    ctx: &AdminContext,
+    command: AdminCommand,
+    body: &[u8],
+    client: &Client,
+    role: Role,
+) -> Result<Option<Value>, i32> {
+    log::info!("[🔑] ({}) Executing admin command {:?}", client, command);
+    match command {
+        AdminCommand::ResetStats => ctx.stats.reset().map_err(|why| {
+            log::error!("[❌] Failed to reset the lifetime counters: {:?}", why);
+            0x0A
+        })?,
+        #[cfg(feature = "wifi")]
+        AdminCommand::SetNetworkConfig => {
+            crate::wifi::NetworkConfig::store(ctx.nvs_partition.clone(), body)?
+        }
+        AdminCommand::SetProximity => ctx.proximity.configure(body)?,
+        AdminCommand::ListBonds => {
+            let bonds = security::bonds().map_err(|why| {
+                log::error!("[❌] Failed to list the bonds: {:?}", why);
+                0x0A
+            })?;
+            return Ok(Some(json!({ "bonds": bonds })));
+        }
+        AdminCommand::ClearBonds => {
+            let address = std::str::from_utf8(body)
+                .map(|x| x.trim())
+                .ok()
+                .filter(|x| !x.is_empty());
+            security::clear_bonds(address).map_err(|why| {
+                log::error!("[❌] Failed to delete the bonds: {:?}", why);
+                0x0A
+            })?
+        }
+        AdminCommand::EnrollUser => ctx.credentials.enroll(body, role)?,
+        AdminCommand::RemoveUser => ctx.credentials.remove(body, role)?,
+        AdminCommand::ListUsers => return Ok(Some(json!({ "users": ctx.credentials.list() }))),
+        AdminCommand::FactoryReset => {
+            log::warn!("[🧹] ({}) Factory reset requested", client);
+            factory_reset::schedule(ctx.nvs_partition.clone()).map_err(|why| {
+                log::error!("[❌] Failed to schedule the factory reset: {:?}", why);
+                0x0A
+            })?
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,167 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes128;
+use esp32_nimble::enums::OwnAddrType;
+use esp32_nimble::{BLEAdvertisementData, BLEDevice, BLEError};
+use esp_idf_svc::sys::ble_hs_id_set_rnd;
+use hmac::{Hmac, Mac};
+use rand::{thread_rng, Rng};
+use sha2::Sha256;
+
+use crate::events::GateEvent;
+use crate::identity::Identity;
+use crate::lockout::Lockout;
+use crate::logs::LogStore;
+use crate::PROTOCOL_VERSION;
+
+/// Company id of the manufacturer data (reserved for testing by the Bluetooth SIG)
+const COMPANY_ID: u16 = 0xFFFF;
+/// The status is refreshed at least this often (e.g. when a lockout expires)
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+const FLAG_GATE_OPEN: u8 = 0x01;
+const FLAG_LOCKED_OUT: u8 = 0x02;
+
+extern "C" {
+    /// sets the IRK NimBLE distributes when bonding; it isn't part of NimBLE's public headers
+    fn ble_hs_pvcy_set_our_irk(irk: *const u8) -> i32;
+}
+
+/// The manufacturer data advertised in the scan response (14 bytes):
+///
+/// `company id (u16 LE) | protocol version (u8) | nonce (2 byte) | id hash (4 byte) | flags (u8) |
+/// log sequence (u32 BE)`
+///
+/// - `id hash` the first 4 bytes of `HMAC-SHA256(status key, nonce)`; only the users who opened a
+///   session know the status key and can match the device
+/// - `flags` `0x01` gate open, `0x02` locked out
+/// - `log sequence` the sequence number of the latest log entry XOR the next 4 bytes of the HMAC;
+///   the app only has to connect to sync the logs if it changed
+fn manufacturer_data(status_key: &[u8; 16], nonce: [u8; 2], flags: u8, log_seq: u32) -> [u8; 14] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(status_key).expect("HMAC takes keys of any size");
+    mac.update(&nonce);
+    let hash = mac.finalize().into_bytes();
+
+    let mut res: [u8; 14] = [0x0; 14];
+    res[..2].clone_from_slice(&COMPANY_ID.to_le_bytes());
+    res[2] = PROTOCOL_VERSION as u8;
+    res[3..5].clone_from_slice(&nonce);
+    res[5..9].clone_from_slice(&hash[..4]);
+    res[9] = flags;
+    res[10..].clone_from_slice(&log_seq.to_be_bytes());
+    res[10..]
+        .iter_mut()
+        .zip(&hash[4..8])
+        .for_each(|(x, y)| *x ^= y);
+    res
+}
+
+/// a new resolvable private address (little endian): `hash (3 byte) | prand (3 byte)` where
+/// `hash = ah(irk, prand)`, so bonded peers can resolve it with the IRK
+fn resolvable_private_address(irk: &[u8; 16]) -> [u8; 6] {
+    let mut prand: [u8; 3] = [0x0; 3];
+    thread_rng().fill(&mut prand);
+    prand[2] = (prand[2] & 0x3F) | 0x40;
+    // ah() encrypts the most significant octet first
+    let mut key = *irk;
+    key.reverse();
+    let mut block: [u8; 16] = [0x0; 16];
+    block[13..].copy_from_slice(&[prand[2], prand[1], prand[0]]);
+    Aes128::new(&key.into()).encrypt_block(GenericArray::from_mut_slice(&mut block));
+    let hash = [block[15], block[14], block[13]];
+    [hash[0], hash[1], hash[2], prand[0], prand[1], prand[2]]
+}
+
+/// advertises from resolvable private addresses generated with the device's IRK instead of the
+/// public address (the base MAC); [`spawn_status_task`] rotates them
+pub fn enable_privacy(device: &mut BLEDevice, irk: &[u8; 16]) -> Result<(), BLEError> {
+    BLEError::convert(unsafe { ble_hs_pvcy_set_our_irk(irk.as_ptr()) } as u32)?;
+    device.set_own_addr_type(OwnAddrType::Random);
+    let address = resolvable_private_address(irk);
+    BLEError::convert(unsafe { ble_hs_id_set_rnd(address.as_ptr()) } as u32)
+}
+
+/// switches to a new private address advertising `data`; advertising has to be stopped meanwhile
+fn rotate_address(irk: &[u8; 16], data: &[u8]) -> Result<(), BLEError> {
+    let address = resolvable_private_address(irk);
+    let mut advertising = BLEDevice::take().get_advertising().lock();
+    let advertised = advertising.is_advertising();
+    if advertised {
+        advertising.stop()?;
+    }
+    let res =
+        BLEError::convert(unsafe { ble_hs_id_set_rnd(address.as_ptr()) } as u32).and_then(|_| {
+            advertising.scan_response_data(BLEAdvertisementData::new().manufacturer_data(data))
+        });
+    if advertised {
+        advertising.start()?;
+    }
+    res
+}
+
+/// keeps the advertised status up to date; with a `rotation` interval the private address and
+/// the nonce (and therefore the id hash & the log sequence) change together periodically,
+/// otherwise the device advertises from its public address with a fixed nonce
+pub fn spawn_status_task(
+    identity: Arc<Identity>,
+    rotation: Option<Duration>,
+    logs: Arc<LogStore>,
+    lockout: Arc<Lockout>,
+    events: Receiver<GateEvent>,
+) {
+    std::thread::spawn(move || {
+        let mut nonce: [u8; 2] = [0x0; 2];
+        let mut rotated = Instant::now();
+        if rotation.is_some() {
+            thread_rng().fill(&mut nonce);
+        }
+        let mut gate_open = false;
+        loop {
+            let rotate = rotation.is_some_and(|x| rotated.elapsed() >= x);
+            let next_nonce = if rotate {
+                rotated = Instant::now();
+                thread_rng().gen()
+            } else {
+                nonce
+            };
+            let mut flags = 0;
+            if gate_open {
+                flags |= FLAG_GATE_OPEN;
+            }
+            if lockout.any_locked_out() {
+                flags |= FLAG_LOCKED_OUT;
+            }
+            let data =
+                manufacturer_data(&identity.status_key, next_nonce, flags, logs.latest_seq());
+            if rotate {
+                // the new nonce must never be advertised from the old address & vice versa
+                match rotate_address(&identity.irk, &data) {
+                    Ok(_) => nonce = next_nonce,
+                    Err(why) => log::error!("[❌] Failed to rotate the private address: {:?}", why),
+                }
+            } else if let Err(why) = set_scan_response(&data) {
+                log::error!("[❌] Failed to update the advertised status: {:?}", why);
+            }
+
+            let timeout = rotation.map_or(REFRESH_INTERVAL, |x| x.min(REFRESH_INTERVAL));
+            match events.recv_timeout(timeout) {
+                Ok(GateEvent::GateOpened) => gate_open = true,
+                Ok(GateEvent::GateClosed) => gate_open = false,
+                Ok(GateEvent::Log(_) | GateEvent::PassageMode(_) | GateEvent::DoorOpen(_))
+                | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+fn set_scan_response(data: &[u8]) -> Result<(), BLEError> {
+    BLEDevice::take()
+        .get_advertising()
+        .lock()
+        .scan_response_data(BLEAdvertisementData::new().manufacturer_data(data))
+}
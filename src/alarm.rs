@@ -0,0 +1,48 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+
+use crate::events::GateEvent;
+use crate::logs::LogEntryStatus;
+use crate::supervisor::{self, Watchdog};
+
+/// Drives the alarm output high for `duration` (zero keeps it high until the next reboot) after
+/// an opening with a duress credential
+pub fn spawn<T: OutputPin>(
+    mut pin: PinDriver<'static, T, Output>,
+    duration: Duration,
+    events: Receiver<GateEvent>,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            let watchdog = Watchdog::subscribe("alarm");
+            let mut raised: Option<Instant> = None;
+            loop {
+                watchdog.feed();
+                match events.recv_timeout(supervisor::FEED_INTERVAL) {
+                    Ok(GateEvent::Log(entry)) if matches!(entry.status, LogEntryStatus::Duress) => {
+                        log::warn!("[🚨] Duress opening by user {:?}", entry.user);
+                        if let Err(why) = pin.set_high() {
+                            log::error!("[❌] Failed to raise the alarm: {:?}", why);
+                        }
+                        raised = Some(Instant::now());
+                    }
+                    Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => {
+                        log::error!("[❌] The alarm lost its event source");
+                        return;
+                    }
+                }
+                let over = !duration.is_zero() && raised.is_some_and(|x| x.elapsed() >= duration);
+                if over {
+                    raised = None;
+                    if let Err(why) = pin.set_low() {
+                        log::error!("[❌] Failed to clear the alarm: {:?}", why);
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::fmt::Display;
+
+use esp32_nimble::utilities::BleUuid;
+use esp32_nimble::{BLEAdvertisementData, BLEDevice, BLEError, NimbleProperties};
+use esp_idf_svc::hal::gpio::{Output, Pin, PinDriver};
+use esp_idf_svc::sys::EspError;
+use serde_json::json;
+
+use crate::led::{Pattern, StatusLed};
+use crate::{device_info, ota};
+
+const RECOVERY_NAME: &str = "GAX recovery";
+const RECOVERY_SERVICE_UUID: &str = "00000000-DEAD-BEEF-00FF-000000000000";
+/// Read only; the failure as json
+const RECOVERY_CHAR_UUID: &str = "00000000-DEAD-BEEF-00FE-000000000000";
+
+/// Failure classes of the boot sequence; each one has its own blink code
+#[derive(Debug)]
+pub enum BootError {
+    /// the device config, a UUID or a key couldn't be parsed
+    Config(String),
+    /// NVS couldn't be opened or read
+    Storage(EspError),
+    /// a GPIO or eFuse access failed
+    Hardware(EspError),
+    /// the BLE stack couldn't be set up
+    Ble(BLEError),
+    /// a thread couldn't be spawned
+    System(std::io::Error),
+}
+
+impl BootError {
+    /// the number of blinks repeated by the status LED
+    pub fn blink_code(&self) -> u8 {
+        match self {
+            BootError::Config(_) => 2,
+            BootError::Storage(_) => 3,
+            BootError::Hardware(_) => 4,
+            BootError::Ble(_) => 5,
+            BootError::System(_) => 6,
+        }
+    }
+
+    pub fn class(&self) -> &'static str {
+        match self {
+            BootError::Config(_) => "config",
+            BootError::Storage(_) => "storage",
+            BootError::Hardware(_) => "hardware",
+            BootError::Ble(_) => "ble",
+            BootError::System(_) => "system",
+        }
+    }
+}
+
+impl Display for BootError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BootError::Config(x) => write!(f, "{}", x),
+            BootError::Storage(x) | BootError::Hardware(x) => write!(f, "{:?}", x),
+            BootError::Ble(x) => write!(f, "{:?}", x),
+            BootError::System(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+/// parses a 128 bit UUID of the device config
+pub fn uuid(name: &str, uuid: &str) -> Result<BleUuid, BootError> {
+    BleUuid::from_uuid128_string(uuid)
+        .map_err(|why| BootError::Config(format!("invalid {} '{}': {:?}", name, uuid, why)))
+}
+
+/// Keeps the relay off, blinks the error code and advertises a minimal diagnostic service
+/// (read the recovery characteristic to see what failed). A freshly updated image is rolled back
+/// instead.
+pub fn recovery_mode<T: Pin>(
+    error: BootError,
+    trigger: Option<&mut PinDriver<T, Output>>,
+    status_led: Option<&StatusLed>,
+) -> ! {
+    log::error!("[🚑] Boot failed ({}): {}", error.class(), error);
+    if let Some(trigger) = trigger {
+        if let Err(why) = trigger.set_low() {
+            log::error!("[❌] Failed to hold the trigger low: {:?}", why);
+        }
+    }
+    if ota::running_image_unconfirmed() {
+        log::error!("[🚑] Rolling back the new firmware image");
+        log::error!("{:?}", ota::reject_running_image());
+    }
+    if let Some(status_led) = status_led {
+        status_led.halt(Pattern::ErrorCode(error.blink_code()));
+    }
+
+    let diagnostics = json!({
+        "error_class": error.class(),
+        "error": error.to_string(),
+        "firmware_version": device_info::FIRMWARE_VERSION,
+        "git_hash": device_info::GIT_HASH,
+        "reset_reason": device_info::reset_reason(),
+    })
+    .to_string();
+    if let Err(why) = advertise_diagnostics(diagnostics) {
+        log::error!("[❌] Failed to start the recovery service: {:?}", why);
+    }
+    // the trigger has to stay owned (and low) forever
+    loop {
+        std::thread::park();
+    }
+}
+
+fn advertise_diagnostics(diagnostics: String) -> Result<(), BLEError> {
+    let service_uuid = BleUuid::from_uuid128_string(RECOVERY_SERVICE_UUID)?;
+    let char_uuid = BleUuid::from_uuid128_string(RECOVERY_CHAR_UUID)?;
+    let device = BLEDevice::take();
+    let service = device.get_server().create_service(service_uuid);
+    service
+        .lock()
+        .create_characteristic(char_uuid, NimbleProperties::READ)
+        .lock()
+        .set_value(diagnostics.as_bytes());
+    BLEDevice::set_device_name(RECOVERY_NAME)?;
+    let mut advertising = device.get_advertising().lock();
+    advertising.set_data(
+        BLEAdvertisementData::new()
+            .name(RECOVERY_NAME)
+            .add_service_uuid(service_uuid),
+    )?;
+    advertising.start()?;
+    log::info!("[🚑] Advertising the recovery service");
+    Ok(())
+}
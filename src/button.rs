@@ -0,0 +1,191 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Input, InputPin, OutputPin, PinDriver, Pull};
+use esp_idf_svc::sys::{esp_restart, EspError};
+use serde::Deserialize;
+
+use crate::challenge::{Client, UnlockRequest};
+use crate::events::{EventBus, GateEvent};
+use crate::stats::Stats;
+use crate::supervisor::Watchdog;
+
+/// The inputs are sampled this often
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// A level has to be stable this long to count
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// What holding the exit button does
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LongPressAction {
+    /// a long press opens the gate like a short one
+    None,
+    /// toggles the passage mode (the gate is held open)
+    Passage,
+    /// reboots the device
+    Reboot,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ButtonSettings {
+    pub long_press: Duration,
+    pub long_press_action: LongPressAction,
+}
+
+/// an input with the internal pull-up; buttons & switches connect it to GND
+pub fn input<P: InputPin + OutputPin>(pin: P) -> Result<PinDriver<'static, P, Input>, EspError> {
+    let mut driver = PinDriver::input(pin)?;
+    driver.set_pull(Pull::Up)?;
+    Ok(driver)
+}
+
+/// A debounced active low input
+struct Debounced<P: InputPin> {
+    pin: PinDriver<'static, P, Input>,
+    active: bool,
+    raw: bool,
+    since: Instant,
+}
+
+impl<P: InputPin> Debounced<P> {
+    fn new(pin: PinDriver<'static, P, Input>) -> Self {
+        let active = pin.is_low();
+        Debounced {
+            pin,
+            active,
+            raw: active,
+            since: Instant::now(),
+        }
+    }
+
+    /// the new state if it changed
+    fn poll(&mut self) -> Option<bool> {
+        let raw = self.pin.is_low();
+        if raw != self.raw {
+            self.raw = raw;
+            self.since = Instant::now();
+            return None;
+        }
+        if raw != self.active && self.since.elapsed() >= DEBOUNCE {
+            self.active = raw;
+            return Some(raw);
+        }
+        None
+    }
+}
+
+/// The local inputs enabled in the device config
+pub struct Inputs<B: InputPin, K: InputPin, D: InputPin> {
+    pub button: Option<PinDriver<'static, B, Input>>,
+    pub key_switch: Option<PinDriver<'static, K, Input>>,
+    pub door_contact: Option<PinDriver<'static, D, Input>>,
+}
+
+/// Watches the request-to-exit button, the key switch & the door contact: a press of the button
+/// opens the gate like a BLE unlock (while it is held `button_held` is set), the closed key switch
+/// disables unlocking over BLE and the door contact publishes [`GateEvent::DoorOpen`] (a closed
+/// contact is a closed door)
+pub fn spawn<B: InputPin, K: InputPin, D: InputPin>(
+    inputs: Inputs<B, K, D>,
+    settings: ButtonSettings,
+    unlock: Sender<UnlockRequest>,
+    ble_unlock_disabled: Arc<AtomicBool>,
+    button_held: Arc<AtomicBool>,
+    stats: Arc<Stats>,
+    events: Arc<EventBus>,
+) -> std::io::Result<()> {
+    let mut button = inputs.button.map(Debounced::new);
+    let mut key_switch = inputs.key_switch.map(Debounced::new);
+    let mut door_contact = inputs.door_contact.map(Debounced::new);
+    if let Some(x) = key_switch.as_ref() {
+        ble_unlock_disabled.store(x.active, Ordering::Relaxed);
+    }
+    if let Some(x) = door_contact.as_ref() {
+        events.publish(GateEvent::DoorOpen(!x.active));
+    }
+    if let Some(x) = button.as_ref() {
+        button_held.store(x.active, Ordering::Relaxed);
+    }
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            let watchdog = Watchdog::subscribe("inputs");
+            let mut pressed: Option<Instant> = None;
+            let mut long_pressed = false;
+            let mut passage = false;
+            let send = |request: UnlockRequest| {
+                if let Err(why) = unlock.send(request) {
+                    log::error!("[❌] Failed to tx: {:?}", why);
+                }
+            };
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                watchdog.feed();
+
+                if let Some(x) = key_switch.as_mut().and_then(|x| x.poll()) {
+                    log::info!(
+                        "[🔑] Key switch {}: BLE unlock {}",
+                        if x { "closed" } else { "opened" },
+                        if x { "disabled" } else { "enabled" }
+                    );
+                    ble_unlock_disabled.store(x, Ordering::Relaxed);
+                }
+
+                if let Some(x) = door_contact.as_mut().and_then(|x| x.poll()) {
+                    log::info!("[🚪] Door {}", if x { "closed" } else { "opened" });
+                    events.publish(GateEvent::DoorOpen(!x));
+                }
+
+                let Some(button) = button.as_mut() else {
+                    continue;
+                };
+                let state = button.poll();
+                if let Some(x) = state {
+                    button_held.store(x, Ordering::Relaxed);
+                }
+                match state {
+                    Some(true) => {
+                        pressed = Some(Instant::now());
+                        long_pressed = false;
+                    }
+                    Some(false) => {
+                        if pressed.take().is_some() && !long_pressed {
+                            log::info!("[🔘] Exit button pressed");
+                            send(Client::Button.into());
+                        }
+                    }
+                    None => {}
+                }
+                let held = pressed.is_some_and(|x| x.elapsed() >= settings.long_press);
+                if !held || long_pressed {
+                    continue;
+                }
+                match settings.long_press_action {
+                    LongPressAction::None => continue,
+                    LongPressAction::Passage => {
+                        passage = !passage;
+                        log::info!(
+                            "[🔘] Exit button held: passage mode {}",
+                            if passage { "on" } else { "off" }
+                        );
+                        send(UnlockRequest {
+                            hold: Some(passage),
+                            ..Client::Button.into()
+                        });
+                    }
+                    LongPressAction::Reboot => {
+                        log::info!("[🔘] Exit button held: rebooting");
+                        if let Err(why) = stats.flush() {
+                            log::error!("[❌] Failed to flush lifetime counters: {:?}", why);
+                        }
+                        unsafe { esp_restart() };
+                    }
+                }
+                long_pressed = true;
+            }
+        })?;
+    Ok(())
+}
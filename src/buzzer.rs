@@ -0,0 +1,186 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::hal::gpio::OutputPin;
+use esp_idf_svc::hal::ledc::{
+    config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver,
+};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::units::FromValueType;
+use esp_idf_svc::sys::{self, esp, EspError};
+
+use crate::events::GateEvent;
+use crate::logs::LogEntryStatus;
+use crate::supervisor::{self, Watchdog};
+
+/// Quiet hours are only applied once the clock has been set (e.g. through SNTP); before that it
+/// is somewhere around 1970
+const CLOCK_SET: u64 = 1_577_836_800; // 2020-01-01
+/// The "door left open" tone is repeated this often
+const DOOR_OPEN_REPEAT: Duration = Duration::from_secs(5);
+
+/// A tone sequence; every step is `(frequency in Hz (0 is silence), duration in ms)`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tone {
+    Success,
+    Denied,
+    Lockout,
+    DoorOpen,
+}
+
+impl Tone {
+    fn steps(&self) -> &'static [(u32, u64)] {
+        match self {
+            Tone::Success => &[(1047, 100), (1319, 100), (1568, 150)],
+            Tone::Denied => &[(400, 300), (0, 100), (400, 300)],
+            Tone::Lockout => &[
+                (2000, 80),
+                (0, 80),
+                (2000, 80),
+                (0, 80),
+                (2000, 80),
+                (0, 80),
+                (2000, 80),
+                (0, 80),
+                (2000, 80),
+            ],
+            Tone::DoorOpen => &[(2500, 200), (0, 200), (2500, 200)],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BuzzerSettings {
+    /// the buzzer is muted from `quiet_from` until `quiet_until` (UTC hours, equal values disable
+    /// the quiet hours)
+    pub quiet_from: u8,
+    pub quiet_until: u8,
+    /// alert if the door contact reports the door open this long (zero disables the alert)
+    pub door_open_alert: Duration,
+    /// alert if the passage mode holds the gate open this long (zero disables the alert)
+    pub passage_alert: Duration,
+}
+
+impl BuzzerSettings {
+    /// whether `since` (if any) is longer ago than the enabled `limit`
+    fn exceeded(since: Option<Instant>, limit: Duration) -> bool {
+        !limit.is_zero() && since.is_some_and(|x| x.elapsed() >= limit)
+    }
+
+    fn is_quiet(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or_default();
+        if now < CLOCK_SET || self.quiet_from == self.quiet_until {
+            return false;
+        }
+        let hour = ((now / 3600) % 24) as u8;
+        if self.quiet_from < self.quiet_until {
+            (self.quiet_from..self.quiet_until).contains(&hour)
+        } else {
+            hour >= self.quiet_from || hour < self.quiet_until
+        }
+    }
+}
+
+/// A passive buzzer driven by LEDC PWM
+pub struct Buzzer {
+    timer: sys::ledc_timer_t,
+    channel: LedcDriver<'static>,
+}
+
+impl Buzzer {
+    pub fn new(
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+        timer: impl Peripheral<P = impl LedcTimer> + 'static,
+        channel: impl Peripheral<P = impl LedcChannel> + 'static,
+    ) -> Result<Self, EspError> {
+        let timer = LedcTimerDriver::new(timer, &TimerConfig::new().frequency(2.kHz().into()))?;
+        let timer_id = timer.timer();
+        let mut channel = LedcDriver::new(channel, timer, pin)?;
+        channel.set_duty(0)?;
+        Ok(Buzzer {
+            timer: timer_id,
+            channel,
+        })
+    }
+
+    pub fn play(&mut self, tone: Tone) -> Result<(), EspError> {
+        for (frequency, duration) in tone.steps() {
+            if *frequency == 0 {
+                self.channel.set_duty(0)?;
+            } else {
+                // the timer is configured with the default (low) speed mode
+                esp!(unsafe {
+                    sys::ledc_set_freq(sys::ledc_mode_t_LEDC_LOW_SPEED_MODE, self.timer, *frequency)
+                })?;
+                self.channel.set_duty(self.channel.get_max_duty() / 2)?;
+            }
+            std::thread::sleep(Duration::from_millis(*duration));
+        }
+        self.channel.set_duty(0)
+    }
+}
+
+/// plays the tones for the gate's events
+pub fn spawn(
+    mut buzzer: Buzzer,
+    settings: BuzzerSettings,
+    events: Receiver<GateEvent>,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            let watchdog = Watchdog::subscribe("buzzer");
+            // since when the door contact reports the door open
+            let mut door_open: Option<Instant> = None;
+            // since when the passage mode holds the gate open
+            let mut passage: Option<Instant> = None;
+            let mut alerted = Instant::now();
+            loop {
+                watchdog.feed();
+                let timeout = supervisor::FEED_INTERVAL.min(DOOR_OPEN_REPEAT);
+                let tone = match events.recv_timeout(timeout) {
+                    Ok(GateEvent::GateOpened) => Some(Tone::Success),
+                    Ok(GateEvent::Log(entry)) => match entry.status {
+                        LogEntryStatus::Failed(0x0E) => Some(Tone::Lockout),
+                        LogEntryStatus::Failed(_) => Some(Tone::Denied),
+                        _ => None,
+                    },
+                    Ok(GateEvent::DoorOpen(x)) => {
+                        door_open = x.then(|| door_open.unwrap_or_else(Instant::now));
+                        None
+                    }
+                    Ok(GateEvent::PassageMode(x)) => {
+                        passage = x.then(|| passage.unwrap_or_else(Instant::now));
+                        None
+                    }
+                    Ok(GateEvent::GateClosed) | Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => {
+                        log::error!("[❌] The buzzer lost its event source");
+                        return;
+                    }
+                };
+                let door_left_open =
+                    (BuzzerSettings::exceeded(door_open, settings.door_open_alert)
+                        || BuzzerSettings::exceeded(passage, settings.passage_alert))
+                        && alerted.elapsed() >= DOOR_OPEN_REPEAT;
+                let tone = match tone {
+                    Some(x) => x,
+                    None if door_left_open => {
+                        alerted = Instant::now();
+                        Tone::DoorOpen
+                    }
+                    None => continue,
+                };
+                if settings.is_quiet() {
+                    continue;
+                }
+                if let Err(why) = buzzer.play(tone) {
+                    log::error!("[❌] Failed to play {:?}: {:?}", tone, why);
+                }
+            }
+        })?;
+    Ok(())
+}
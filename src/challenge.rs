@@ -0,0 +1,330 @@
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use esp32_nimble::{BLEAddress, BLEConnDesc};
+use k256::ecdsa::{signature::Verifier, Signature};
+use rand::{thread_rng, Rng};
+
+use crate::bytes_to_hex_string;
+use crate::credentials::{CredentialId, Credentials, Signer};
+use crate::logs::LogSource;
+
+/// Time a client has to answer a challenge
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(90);
+/// Challenges a single client may hold at once; the oldest one is dropped for a new one (MQTT &
+/// HTTP clients never disconnect, so their challenges are only ever freed by use or expiry)
+const MAX_CHALLENGES_PER_CLIENT: usize = 4;
+/// Challenges held by all clients together (e.g. HTTP clients rotating their address); the
+/// oldest one is dropped for a new one
+const MAX_CHALLENGES: usize = 64;
+/// A PIN sent along with an unlock response has at most this many digits
+const MAX_PIN_LEN: usize = 8;
+
+/// The party a challenge has been issued to
+#[derive(Debug, Clone, PartialEq)]
+pub enum Client {
+    /// a BLE connection: the peer's address & the connection handle; challenges & sessions belong
+    /// to the connection, another connection from the same address doesn't share them
+    Ble(BLEAddress, u16),
+    /// an MQTT app by the client id in its topics
+    #[cfg_attr(not(feature = "wifi-mqtt"), allow(dead_code))]
+    Mqtt(String),
+    /// an HTTP client by its source address
+    #[cfg_attr(not(feature = "wifi-http"), allow(dead_code))]
+    Http(IpAddr),
+    /// the request-to-exit button
+    Button,
+    /// a keypad or card reader
+    Wiegand(CredentialId),
+    /// an NFC tag or a phone emulating one
+    Nfc(CredentialId),
+}
+impl Client {
+    pub fn ble(desc: &BLEConnDesc) -> Self {
+        Client::Ble(desc.address(), desc.conn_handle())
+    }
+
+    /// the credential recorded in the log
+    pub fn credential(&self) -> CredentialId {
+        match self {
+            Client::Ble(x, _) => CredentialId::Ble(x.to_string()),
+            Client::Mqtt(_) | Client::Http(_) | Client::Button => CredentialId::None,
+            Client::Wiegand(x) | Client::Nfc(x) => x.clone(),
+        }
+    }
+    pub fn source(&self) -> LogSource {
+        match self {
+            Client::Ble(..) => LogSource::Ble,
+            Client::Mqtt(_) => LogSource::Mqtt,
+            Client::Http(_) => LogSource::Http,
+            Client::Button => LogSource::Button,
+            Client::Wiegand(_) => LogSource::Wiegand,
+            Client::Nfc(_) => LogSource::Nfc,
+        }
+    }
+}
+impl Display for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Client::Ble(x, _) => write!(f, "{}", x),
+            Client::Mqtt(x) => write!(f, "mqtt {}", x),
+            Client::Http(x) => write!(f, "http {}", x),
+            Client::Button => write!(f, "button"),
+            Client::Wiegand(x) => write!(f, "wiegand {}", x),
+            Client::Nfc(x) => write!(f, "nfc {}", x),
+        }
+    }
+}
+
+/// What the user proved to make a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Factor {
+    /// nothing (the exit button)
+    None,
+    /// a signed challenge
+    Signature,
+    /// a signed challenge and the user's PIN
+    SignatureAndPin,
+    /// a PIN entered on a keypad
+    Pin,
+    /// a card or tag
+    Card,
+    /// a card or tag and the user's PIN
+    CardAndPin,
+}
+
+/// A verified request to open the gate
+#[derive(Debug, Clone)]
+pub struct UnlockRequest {
+    pub client: Client,
+    /// the connection RSSI of a hands-free opening
+    pub rssi: Option<i8>,
+    /// `Some(true)` holds the gate open (passage mode) until `Some(false)` releases it
+    pub hold: Option<bool>,
+    /// the enrolled user who made the request
+    pub user: Option<u16>,
+    pub factor: Factor,
+    /// made with a duress key or PIN: the gate opens as usual but a silent alarm is raised
+    pub duress: bool,
+    /// the user who completed a dual authorization (see [`crate::access::AccessControl`])
+    pub second_user: Option<u16>,
+}
+impl UnlockRequest {
+    pub fn for_user(client: Client, user: u16, factor: Factor) -> Self {
+        UnlockRequest {
+            user: Some(user),
+            factor,
+            ..client.into()
+        }
+    }
+}
+impl From<Client> for UnlockRequest {
+    fn from(client: Client) -> Self {
+        UnlockRequest {
+            client,
+            rssi: None,
+            hold: None,
+            user: None,
+            factor: Factor::None,
+            duress: false,
+            second_user: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Challenge {
+    pub time: Instant,
+    pub challenge_bytes: [u8; 64],
+    pub client: Client,
+}
+
+/// creates a new challenge for `client`; `None` if the store couldn't be locked
+///
+/// Expired challenges are dropped and a client holds at most [`MAX_CHALLENGES_PER_CLIENT`] (all
+/// clients at most [`MAX_CHALLENGES`]), so requesting challenges over and over can't exhaust the
+/// memory.
+pub fn issue_challenge(store: &Mutex<Vec<Challenge>>, client: Client) -> Option<[u8; 64]> {
+    let mut challenge_bytes: [u8; 64] = [Default::default(); 64];
+    thread_rng().fill(&mut challenge_bytes);
+    log::info!(
+        "[🎲] ({}) Sending challenge bytes '{}'",
+        client,
+        bytes_to_hex_string(&challenge_bytes)
+    );
+    let mut challenges = match store.lock() {
+        Ok(x) => x,
+        Err(why) => {
+            log::error!("[❌] ({}) Mutex lock error: {:?}", client, why);
+            return None;
+        }
+    };
+    challenges.retain(|x| x.time.elapsed() <= CHALLENGE_TIMEOUT);
+    let issued = challenges.iter().filter(|x| x.client == client).count();
+    if issued >= MAX_CHALLENGES_PER_CLIENT {
+        if let Some(oldest) = challenges.iter().position(|x| x.client == client) {
+            challenges.remove(oldest);
+        }
+    }
+    if challenges.len() >= MAX_CHALLENGES {
+        challenges.remove(0);
+    }
+    challenges.push(Challenge {
+        time: Instant::now(),
+        challenge_bytes,
+        client,
+    });
+    Some(challenge_bytes)
+}
+
+/// checks if the challenge has been issued to `client` & is in time; the challenge is consumed
+///
+/// Errors with the code reported to the client:
+/// - `0x05` the store couldn't be locked
+/// - `0x06` the challenge is unknown
+/// - `0x07` the challenge has expired
+pub fn consume_challenge(
+    store: &Mutex<Vec<Challenge>>,
+    challenge_data: &[u8],
+    client: &Client,
+) -> Result<(), i32> {
+    let mut challenges = match store.lock() {
+        Ok(x) => x,
+        Err(why) => {
+            log::error!("[❌] ({}) Mutex lock error: {:?}", client, why);
+            return Err(0x05);
+        }
+    };
+    let challenge_result = match challenges
+        .iter()
+        .find(|x| x.client == *client && x.challenge_bytes == challenge_data)
+    {
+        Some(x) => x,
+        None => {
+            log::error!(
+                "[⛔] ({}) Request denied: couldn't find challenge: '{}'",
+                client,
+                bytes_to_hex_string(challenge_data)
+            );
+            return Err(0x06);
+        }
+    };
+    let expired = challenge_result.time.elapsed() > CHALLENGE_TIMEOUT;
+    clean_up_challenges(&mut challenges, challenge_data, client);
+    if expired {
+        log::error!("[⛔] ({}) Request denied: challenge expired", client);
+        return Err(0x07);
+    }
+    Ok(())
+}
+
+/// removes all challenges issued to `client` (e.g. when its connection closes)
+pub fn clean_up_client(store: &Mutex<Vec<Challenge>>, client: &Client) {
+    let mut challenges = match store.lock() {
+        Ok(x) => x,
+        Err(why) => {
+            log::error!(
+                "[❌] Mutex Lock Error while trying to clean challenges: {:?}",
+                why
+            );
+            return;
+        }
+    };
+    challenges.retain(|x| x.client != *client);
+    log::info!("[♻️] Cleaned up challenges: {} remaining", challenges.len());
+}
+
+fn clean_up_challenges(challenges: &mut Vec<Challenge>, challenge_data: &[u8], client: &Client) {
+    challenges.retain(|x| !(x.client == *client && x.challenge_bytes == challenge_data));
+}
+
+/// verifies a challenge response of the form `challenge (64 byte) | payload | DER signature`
+/// where the signature is created from a SHA256 digest of `challenge | payload` with one of the
+/// `keys`; returns the matching key
+///
+/// Errors with the code reported to the client:
+/// - `0x01` the response is too short
+/// - `0x02` the signature isn't valid DER
+/// - `0x04` the signature verification failed
+/// - see [`consume_challenge`]
+pub fn verify_response(
+    store: &Mutex<Vec<Challenge>>,
+    keys: &[Signer],
+    data: &[u8],
+    payload_len: usize,
+    client: &Client,
+) -> Result<Signer, i32> {
+    if data.len() < 64 + payload_len {
+        log::error!(
+            "[❌] ({}) Got only {} bytes, expected at least {}",
+            client,
+            data.len(),
+            64 + payload_len + 1
+        );
+        return Err(0x01);
+    }
+    let challenge_data = &data[..64];
+    consume_challenge(store, challenge_data, client)?;
+
+    let signed = &data[..64 + payload_len];
+    let signature = match Signature::from_der(&data[64 + payload_len..]) {
+        Ok(x) => x,
+        Err(why) => {
+            log::error!("[❌] ({}) Invalid DER signature: {:?}", client, why);
+            return Err(0x02);
+        }
+    };
+    match keys
+        .iter()
+        .find(|x| x.key.verify(signed, &signature).is_ok())
+    {
+        Some(x) => Ok(x.clone()),
+        None => {
+            log::error!("[❌] ({}) Signature verification failed", client);
+            Err(0x04)
+        }
+    }
+}
+
+/// verifies an unlock response `challenge (64 byte) | [PIN length (1 byte) | PIN (ASCII)] | DER
+/// signature` where the PIN is covered by the signature; DER signatures start with `0x30`, so
+/// responses without a PIN stay valid
+///
+/// The request is marked as made under duress if either the key or the PIN is a duress one.
+///
+/// Errors with `0x1C` if the PIN isn't the one of the signing user, see [`verify_response`] for
+/// the other codes
+pub fn verify_unlock(
+    store: &Mutex<Vec<Challenge>>,
+    credentials: &Credentials,
+    data: &[u8],
+    client: &Client,
+) -> Result<UnlockRequest, i32> {
+    let pin_len = data
+        .get(64)
+        .map(|x| *x as usize)
+        .filter(|x| *x <= MAX_PIN_LEN);
+    let payload_len = pin_len.map_or(0, |x| x + 1);
+    let signer = verify_response(store, &credentials.keys(), data, payload_len, client)?;
+    let Some(pin_len) = pin_len else {
+        return Ok(UnlockRequest {
+            duress: signer.duress,
+            ..UnlockRequest::for_user(client.clone(), signer.user, Factor::Signature)
+        });
+    };
+    let pin = String::from_utf8_lossy(&data[65..65 + pin_len]);
+    let Some(duress) = credentials.check_pin(signer.user, &pin) else {
+        log::error!(
+            "[⛔] ({}) Request denied: wrong PIN of user {}",
+            client,
+            signer.user
+        );
+        return Err(0x1C);
+    };
+    Ok(UnlockRequest {
+        duress: signer.duress || duress,
+        ..UnlockRequest::for_user(client.clone(), signer.user, Factor::SignatureAndPin)
+    })
+}
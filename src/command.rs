@@ -0,0 +1,378 @@
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
+use esp32_nimble::{BLEAddress, BLECharacteristic};
+use k256::ecdsa::VerifyingKey;
+use serde::{Deserialize, Serialize};
+
+use crate::admin::{self, AdminContext};
+use crate::challenge::{self, Challenge, Client, UnlockRequest};
+use crate::credentials::{self, Credentials, Signer};
+use crate::factory_reset::ClaimWindow;
+use crate::framing;
+use crate::identity::Identity;
+use crate::logs::{LogEntryStatus, LogStore};
+use crate::metadata::MetadataSource;
+use crate::roles::{Operation, Role, Roles};
+use crate::security::{LinkSecurity, SecurityPolicy};
+
+/// Version of the command protocol (the separate lock/meta/logs characteristics were v0, which
+/// isn't spoken anymore since protocol version 6, see [`crate::PROTOCOL_VERSION`])
+pub const COMMAND_PROTOCOL_VERSION: u8 = 1;
+
+/// Opcodes of the command protocol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    /// response: `{"version": u8, "opcodes": [u8]}`
+    Capabilities = 0x00,
+    /// response: `{"challenge": bytes}`
+    Challenge = 0x01,
+    /// request: `{"response": bytes}` (`challenge | DER signature`, like the lock characteristic)
+    Unlock = 0x02,
+    /// response: the metadata (requires a session allowed to read it)
+    Meta = 0x03,
+    /// request: `{"since": u32}` (optional); response: the log entries newer than `since`
+    /// (requires a session allowed to read them)
+    Logs = 0x04,
+    /// request: `{"request": bytes}` (a write to the admin characteristic); response: the admin
+    /// command's response (if any)
+    Admin = 0x05,
+    /// request: `{"response": bytes}` (`challenge | DER signature`); opens a session for the
+    /// signing user which lasts until the disconnect; response: `{"user": u16, "role": string,
+    /// "status_key": bytes}`
+    Authenticate = 0x06,
+    /// request: `{"public_key": bytes, "response": bytes, "nonce": bytes}`; makes the SEC1
+    /// `public_key` the owner's key while the device has none (after a factory reset), the
+    /// `response` (`challenge | DER signature`) proves its possession; response: `{"device_key":
+    /// string, "signature": bytes, "status_key": bytes}` where the device signs the `nonce` with its
+    /// identity key; only while the [`ClaimWindow`] is open
+    Claim = 0x07,
+}
+impl Opcode {
+    pub const ALL: [Opcode; 8] = [
+        Opcode::Capabilities,
+        Opcode::Challenge,
+        Opcode::Unlock,
+        Opcode::Meta,
+        Opcode::Logs,
+        Opcode::Admin,
+        Opcode::Authenticate,
+        Opcode::Claim,
+    ];
+
+    pub fn from_u8(opcode: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|x| *x as u8 == opcode)
+    }
+}
+
+#[derive(Serialize)]
+struct Capabilities {
+    version: u8,
+    opcodes: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct ChallengeResponse {
+    #[serde(with = "serde_bytes")]
+    challenge: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct UnlockBody {
+    #[serde(with = "serde_bytes")]
+    response: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct AuthenticateBody {
+    #[serde(with = "serde_bytes")]
+    response: Vec<u8>,
+}
+
+#[derive(Deserialize)]
+struct ClaimBody {
+    #[serde(with = "serde_bytes")]
+    public_key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    response: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    nonce: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Claimed {
+    device_key: String,
+    #[serde(with = "serde_bytes")]
+    signature: Vec<u8>,
+    /// matches the advertised status, see [`crate::advertising`]
+    #[serde(with = "serde_bytes")]
+    status_key: Vec<u8>,
+}
+
+#[derive(Serialize)]
+struct Session {
+    user: u16,
+    role: Role,
+    #[serde(with = "serde_bytes")]
+    status_key: Vec<u8>,
+}
+
+#[derive(Deserialize, Default)]
+struct LogsBody {
+    #[serde(default)]
+    since: u32,
+}
+
+#[derive(Deserialize)]
+struct AdminBody {
+    #[serde(with = "serde_bytes")]
+    request: Vec<u8>,
+}
+
+/// Everything a command may act upon
+pub struct CommandContext {
+    pub challenges: Arc<Mutex<Vec<Challenge>>>,
+    pub credentials: Arc<Credentials>,
+    pub unlock: Sender<UnlockRequest>,
+    pub logs: Arc<LogStore>,
+    pub metadata: Arc<MetadataSource>,
+    pub admin: Arc<AdminContext>,
+    pub roles: Arc<Roles>,
+    pub identity: Arc<Identity>,
+    pub security: SecurityPolicy,
+    pub claim_window: ClaimWindow,
+}
+
+/// A complete (reassembled) write to the command characteristic
+pub struct CommandMessage {
+    pub address: BLEAddress,
+    /// the connection the response is notified to
+    pub conn_handle: u16,
+    pub mtu: u16,
+    pub link: LinkSecurity,
+    pub data: Vec<u8>,
+}
+
+/// handles the command messages on a separate thread and notifies the responses
+///
+/// Request: `version (u8) | opcode (u8) | request id (u16 BE) | CBOR body`
+/// Response: `version (u8) | opcode (u8) | request id (u16 BE) | status (u8) | CBOR body` where
+/// `status` is `0` on success or the error code. Responses are framed like reads (see
+/// [`framing::Framing`]) and only notified to the connection which sent the request (other
+/// subscribers mustn't see session-gated responses), the request id identifies them.
+pub fn spawn(
+    ctx: CommandContext,
+    response_char: Arc<NimbleMutex<BLECharacteristic>>,
+) -> std::io::Result<Sender<CommandMessage>> {
+    let (tx, rx) = channel::<CommandMessage>();
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            for message in rx.iter() {
+                let response = handle(&ctx, &message);
+                // a notification carries at most MTU - 3 bytes
+                let frame_len = (message.mtu as usize).saturating_sub(3);
+                for frame in framing::fragment(&response, frame_len) {
+                    if let Err(why) = response_char
+                        .lock()
+                        .notify_with(&frame, message.conn_handle)
+                    {
+                        log::error!(
+                            "[❌] ({}) Failed to notify the response: {:?}",
+                            message.address,
+                            why
+                        );
+                        break;
+                    }
+                }
+            }
+        })?;
+    Ok(tx)
+}
+
+fn handle(ctx: &CommandContext, message: &CommandMessage) -> Vec<u8> {
+    if message.data.len() < 4 {
+        log::error!("[❌] ({}) Got a truncated command", message.address);
+        return encode_response(COMMAND_PROTOCOL_VERSION, 0xFF, 0, Err(0x32));
+    }
+    let (version, opcode) = (message.data[0], message.data[1]);
+    let request_id = u16::from_be_bytes([message.data[2], message.data[3]]);
+    let body = &message.data[4..];
+    let client = Client::Ble(message.address, message.conn_handle);
+
+    let result = if version != COMMAND_PROTOCOL_VERSION {
+        log::error!("[❌] ({}) Unsupported command version {}", client, version);
+        Err(0x30)
+    } else {
+        match Opcode::from_u8(opcode) {
+            Some(x) => {
+                log::info!("[📨] ({}) Command {:?} #{}", client, x, request_id);
+                execute(ctx, x, body, &client, &message.link)
+            }
+            None => {
+                log::error!("[❌] ({}) Unknown command opcode {:#04x}", client, opcode);
+                Err(0x31)
+            }
+        }
+    };
+    encode_response(version, opcode, request_id, result)
+}
+
+/// Errors with the status reported to the client:
+/// - `0x30` unsupported protocol version
+/// - `0x31` unknown opcode
+/// - `0x32` malformed request
+/// - `0x0F` the link doesn't meet the [`SecurityPolicy`]
+/// - `0x19` the role of the session doesn't allow the command (see [`Roles::dispatch`])
+/// - `0x1F` the device already has an owner (claim)
+/// - `0x26` the claim window is closed: neither is the exit button held nor has the device just
+///   been reset (claim)
+/// - the codes of the corresponding characteristic
+fn execute(
+    ctx: &CommandContext,
+    opcode: Opcode,
+    body: &[u8],
+    client: &Client,
+    link: &LinkSecurity,
+) -> Result<Vec<u8>, i32> {
+    match opcode {
+        Opcode::Capabilities => encode(&Capabilities {
+            version: COMMAND_PROTOCOL_VERSION,
+            opcodes: Opcode::ALL.iter().map(|x| *x as u8).collect(),
+        }),
+        Opcode::Challenge => match challenge::issue_challenge(&ctx.challenges, client.clone()) {
+            Some(x) => encode(&ChallengeResponse {
+                challenge: x.to_vec(),
+            }),
+            None => Err(0x05),
+        },
+        Opcode::Unlock => {
+            ctx.security.check(link, false)?;
+            let body: UnlockBody = decode(body)?;
+            let result =
+                challenge::verify_unlock(&ctx.challenges, &ctx.credentials, &body.response, client)
+                    .and_then(|request| {
+                        ctx.unlock.send(request).map_err(|why| {
+                            log::error!("[❌] Failed to tx: {:?}", why);
+                            0x08
+                        })
+                    });
+            if let Err(code) = result {
+                ctx.logs.append(client, LogEntryStatus::Failed(code));
+            }
+            result.map(|_| Vec::new())
+        }
+        Opcode::Meta => ctx
+            .roles
+            .dispatch(client, None, Operation::ReadMetadata, |_| {
+                encode(&ctx.metadata.current())
+            }),
+        Opcode::Logs => ctx.roles.dispatch(client, None, Operation::ReadLogs, |_| {
+            let body: LogsBody = if body.is_empty() {
+                LogsBody::default()
+            } else {
+                decode(body)?
+            };
+            encode(&ctx.logs.since(body.since))
+        }),
+        Opcode::Admin => {
+            ctx.security.check(link, true)?;
+            let body: AdminBody = decode(body)?;
+            match admin::handle_admin_write(&ctx.admin, &body.request, client)? {
+                Some(x) => encode(&x),
+                None => Ok(Vec::new()),
+            }
+        }
+        Opcode::Authenticate => {
+            let Client::Ble(address, conn_handle) = client else {
+                return Err(0x32);
+            };
+            let body: AuthenticateBody = decode(body)?;
+            let signer = challenge::verify_response(
+                &ctx.challenges,
+                &ctx.credentials.keys(),
+                &body.response,
+                0,
+                client,
+            )?;
+            let role = ctx.credentials.role(signer.user).ok_or(0x19)?;
+            ctx.roles.open_session(*address, *conn_handle, signer.user);
+            encode(&Session {
+                user: signer.user,
+                role,
+                status_key: ctx.identity.status_key.to_vec(),
+            })
+        }
+        Opcode::Claim => {
+            ctx.security.check(link, true)?;
+            let Client::Ble(address, conn_handle) = client else {
+                return Err(0x32);
+            };
+            if ctx.credentials.has_owner() {
+                log::error!("[⛔] ({}) The device has already been claimed", client);
+                return Err(0x1F);
+            }
+            if !ctx.claim_window.is_open() {
+                log::error!(
+                    "[⛔] ({}) Claims need the exit button held or a fresh reset",
+                    client
+                );
+                return Err(0x26);
+            }
+            let body: ClaimBody = decode(body)?;
+            let key = VerifyingKey::from_sec1_bytes(&body.public_key).map_err(|why| {
+                log::error!("[❌] ({}) Invalid owner key: {:?}", client, why);
+                0x32
+            })?;
+            let owner = Signer {
+                user: credentials::OWNER,
+                key,
+                duress: false,
+            };
+            challenge::verify_response(&ctx.challenges, &[owner], &body.response, 0, client)?;
+            ctx.credentials.claim(key)?;
+            ctx.roles
+                .open_session(*address, *conn_handle, credentials::OWNER);
+            encode(&Claimed {
+                device_key: ctx.identity.public_key(),
+                signature: ctx.identity.sign(&body.nonce),
+                status_key: ctx.identity.status_key.to_vec(),
+            })
+        }
+    }
+}
+
+fn decode<T: for<'a> Deserialize<'a>>(body: &[u8]) -> Result<T, i32> {
+    ciborium::from_reader(body).map_err(|why| {
+        log::error!("[❌] Malformed command body: {:?}", why);
+        0x32
+    })
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, i32> {
+    let mut res = Vec::new();
+    ciborium::into_writer(value, &mut res).map_err(|why| {
+        log::error!("[❌] Failed to encode the command response: {:?}", why);
+        0x0A
+    })?;
+    Ok(res)
+}
+
+fn encode_response(
+    version: u8,
+    opcode: u8,
+    request_id: u16,
+    result: Result<Vec<u8>, i32>,
+) -> Vec<u8> {
+    let mut res = vec![version, opcode];
+    res.extend_from_slice(&request_id.to_be_bytes());
+    match result {
+        Ok(body) => {
+            res.push(0);
+            res.extend_from_slice(&body);
+        }
+        Err(code) => res.push(code as u8),
+    }
+    res
+}
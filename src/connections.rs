@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use esp32_nimble::{BLEAddress, BLEConnDesc, BLEDevice, BLEError};
+use esp_idf_svc::sys::CONFIG_BT_NIMBLE_MAX_CONNECTIONS;
+
+use crate::framing::Framing;
+use crate::roles::Roles;
+use crate::supervisor::Watchdog;
+
+/// A connection without any transfer for this long is closed
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Connections a single address may hold at once
+const MAX_PER_ADDRESS: usize = 2;
+/// The last slot is reserved for admins: a bonded peer taking it has to open an owner or admin
+/// session within [`RESERVED_SLOT_GRACE`]
+const RESERVED_SLOTS: usize = 1;
+const RESERVED_SLOT_GRACE: Duration = Duration::from_secs(10);
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+struct Connection {
+    conn_handle: u16,
+    address: BLEAddress,
+    deadline: Instant,
+    /// holds the reserved admin slot since then
+    reserved: Option<Instant>,
+}
+
+/// Keeps track of the connections so parked phones can't occupy every slot
+#[derive(Default)]
+pub struct Connections {
+    connections: Mutex<Vec<Connection>>,
+    /// disconnect reasons (NimBLE error codes) since boot
+    disconnect_reasons: Mutex<BTreeMap<String, u32>>,
+    /// the framings of the characteristics, their transfers are dropped with the connection
+    framings: Mutex<Vec<Weak<Framing>>>,
+}
+
+impl Connections {
+    /// registers a new connection; `false` if it has to be closed again
+    pub fn connected(&self, desc: &BLEConnDesc) -> bool {
+        let mut connections = self.connections.lock().unwrap_or_else(|x| x.into_inner());
+        let address = desc.address();
+        let per_address = connections.iter().filter(|x| x.address == address).count();
+        if per_address >= MAX_PER_ADDRESS {
+            log::error!("[⛔] ({}) Too many connections from this address", address);
+            return false;
+        }
+        let max = CONFIG_BT_NIMBLE_MAX_CONNECTIONS as usize;
+        let reserved = connections.len() + 1 > max - RESERVED_SLOTS;
+        if reserved && !is_bonded(desc) {
+            log::error!("[⛔] ({}) Only the reserved admin slot is left", address);
+            return false;
+        }
+        connections.push(Connection {
+            conn_handle: desc.conn_handle(),
+            address,
+            deadline: Instant::now() + IDLE_TIMEOUT,
+            reserved: reserved.then(Instant::now),
+        });
+        true
+    }
+
+    pub fn disconnected(&self, desc: &BLEConnDesc, reason: &Result<(), BLEError>) {
+        self.connections
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .retain(|x| x.conn_handle != desc.conn_handle());
+        self.framings
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .filter_map(|x| x.upgrade())
+            .for_each(|x| x.disconnected(desc.conn_handle()));
+        let code = reason.as_ref().err().map(|x| x.code()).unwrap_or(0);
+        *self
+            .disconnect_reasons
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .entry(format!("{:#x}", code))
+            .or_default() += 1;
+    }
+
+    /// see [`Framing::new`]
+    pub fn register_framing(&self, framing: &Arc<Framing>) {
+        self.framings
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .push(Arc::downgrade(framing));
+    }
+
+    /// records activity of `address`, postponing the idle timeout
+    pub fn touch(&self, address: BLEAddress) {
+        self.keep_alive(address, IDLE_TIMEOUT);
+    }
+
+    /// keeps the connections of `address` open for at least `duration`
+    pub fn keep_alive(&self, address: BLEAddress, duration: Duration) {
+        let deadline = Instant::now() + duration;
+        for connection in self
+            .connections
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter_mut()
+            .filter(|x| x.address == address)
+        {
+            connection.deadline = connection.deadline.max(deadline);
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.connections
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .len()
+    }
+
+    pub fn disconnect_reasons(&self) -> BTreeMap<String, u32> {
+        self.disconnect_reasons
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .clone()
+    }
+
+    fn idle(&self) -> Vec<(u16, BLEAddress)> {
+        let now = Instant::now();
+        self.connections
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .filter(|x| x.deadline < now)
+            .map(|x| (x.conn_handle, x.address))
+            .collect()
+    }
+
+    /// the connections holding the reserved slot for longer than the grace period
+    fn reserved(&self) -> Vec<(u16, BLEAddress)> {
+        self.connections
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .filter(|x| {
+                x.reserved
+                    .is_some_and(|x| x.elapsed() > RESERVED_SLOT_GRACE)
+            })
+            .map(|x| (x.conn_handle, x.address))
+            .collect()
+    }
+}
+
+/// the link is only encrypted after the connect, so known addresses count as well
+fn is_bonded(desc: &BLEConnDesc) -> bool {
+    desc.bonded()
+        || BLEDevice::take()
+            .bonded_addresses()
+            .is_ok_and(|x| x.contains(&desc.address()))
+}
+
+/// (re)starts advertising if there is a free slot left
+pub fn ensure_advertising() {
+    let mut advertising = BLEDevice::take().get_advertising().lock();
+    if advertising.is_advertising() {
+        return;
+    }
+    if let Err(why) = advertising.start() {
+        log::error!("[❌] Failed to restart advertising: {:?}", why);
+    }
+}
+
+/// closes idle connections and frees the reserved slot from connections without an owner or
+/// admin session
+pub fn spawn_idle_task(connections: Arc<Connections>, roles: Arc<Roles>) {
+    std::thread::spawn(move || {
+        let watchdog = Watchdog::subscribe("connections");
+        loop {
+            std::thread::sleep(CHECK_INTERVAL);
+            for (conn_handle, address) in connections.idle() {
+                log::info!("[🔌] ({}) Closing idle connection", address);
+                disconnect(conn_handle, address);
+            }
+            for (conn_handle, address) in connections.reserved() {
+                if !roles.admin_session(conn_handle) {
+                    log::info!("[🔌] ({}) Freeing the reserved admin slot", address);
+                    disconnect(conn_handle, address);
+                }
+            }
+            watchdog.feed();
+        }
+    });
+}
+
+fn disconnect(conn_handle: u16, address: BLEAddress) {
+    if let Err(why) = BLEDevice::take().get_server().disconnect(conn_handle) {
+        log::error!("[❌] ({}) Failed to disconnect: {:?}", address, why);
+    }
+}
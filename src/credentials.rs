@@ -0,0 +1,522 @@
+use std::fmt::Display;
+use std::sync::Mutex;
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use k256::ecdsa::VerifyingKey;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::roles::Role;
+use crate::{bytes_to_hex_string, hex_string_to_bytes};
+
+const NVS_NAMESPACE: &str = "gax_creds";
+const NVS_USERS_KEY: &str = "users";
+const NVS_SALT_KEY: &str = "salt";
+/// the key of the owner who claimed the device after a factory reset (SEC1)
+const NVS_OWNER_KEY: &str = "owner";
+const MAX_USERS: usize = 32;
+/// The user holding the built-in key (`config_dir/public.bin`, or the key which claimed the device
+/// after a factory reset); it can be enrolled with further credentials (e.g. a PIN) like any other
+/// user and always has the [`Role::Owner`]
+pub const OWNER: u16 = 0;
+
+/// A credential enrolled for a [`User`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Credential {
+    /// a public key (SEC1, hex) signing challenges over BLE or the network; a `duress` key opens
+    /// the gate like any other but raises a silent alarm
+    Key {
+        public_key: String,
+        #[serde(default)]
+        duress: bool,
+    },
+    /// SHA-256 (hex) of the device salt followed by the PIN; a `duress` PIN raises a silent alarm
+    Pin {
+        hash: String,
+        #[serde(default)]
+        duress: bool,
+    },
+    /// the facility code & card number read by a Wiegand reader (`facility << 16 | card`)
+    Card { number: u32 },
+    /// the UID (hex) of an NFC tag; with an AES-128 `key` (hex) it has to be a DESFire EV2 card
+    /// passing the mutual authentication
+    Tag {
+        uid: String,
+        #[serde(default)]
+        key: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: u16,
+    pub name: String,
+    #[serde(default)]
+    pub role: Role,
+    #[serde(default)]
+    pub credentials: Vec<Credential>,
+}
+
+/// A credential as written to the admin characteristic; PINs are hashed before they're stored
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum EnrollCredential {
+    Key {
+        public_key: String,
+        #[serde(default)]
+        duress: bool,
+    },
+    Pin {
+        pin: String,
+        #[serde(default)]
+        duress: bool,
+    },
+    Card {
+        number: u32,
+    },
+    Tag {
+        uid: String,
+        key: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct EnrollBody {
+    id: u16,
+    name: String,
+    #[serde(default)]
+    role: Role,
+    credentials: Vec<EnrollCredential>,
+}
+
+/// What a request has been made with; recorded in every log entry
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "id", rename_all = "lowercase")]
+pub enum CredentialId {
+    /// the address of a BLE client (which signed a challenge)
+    Ble(String),
+    /// remote clients & the exit button don't present a credential of their own
+    None,
+    /// a PIN entered on a keypad and the user it belongs to (`None` for a wrong PIN)
+    Pin(Option<u16>),
+    /// a card number
+    Card(u32),
+    /// the UID (hex) of an NFC tag or phone
+    Tag(String),
+}
+
+impl CredentialId {
+    /// the 6-byte identifier of a log entry: the BLE address, zeros (no credential), the user id
+    /// of a PIN (`0xFFFF` for a wrong PIN), the card number or the last 6 bytes of an NFC UID; the
+    /// type is only part of the json (see [`CredentialId`]'s serialization)
+    pub fn encode(&self) -> [u8; 6] {
+        let mut res: [u8; 6] = [0x0; 6];
+        match self {
+            CredentialId::Ble(mac) => {
+                mac.split(":")
+                    .filter_map(|x| u8::from_str_radix(x, 16).ok())
+                    .zip(res.iter_mut())
+                    .for_each(|(x, y)| *y = x);
+                res
+            }
+            CredentialId::None => res,
+            CredentialId::Pin(user) => {
+                res[4..].clone_from_slice(&user.unwrap_or(0xFFFF).to_be_bytes());
+                res
+            }
+            CredentialId::Card(number) => {
+                res[2..].clone_from_slice(&number.to_be_bytes());
+                res
+            }
+            CredentialId::Tag(uid) => {
+                let uid = hex_string_to_bytes(uid).unwrap_or_default();
+                let len = uid.len().min(6);
+                res[6 - len..].clone_from_slice(&uid[uid.len() - len..]);
+                res
+            }
+        }
+    }
+}
+
+impl Display for CredentialId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialId::Ble(x) => write!(f, "{}", x),
+            CredentialId::None => write!(f, "-"),
+            CredentialId::Pin(_) => write!(f, "pin"),
+            CredentialId::Card(x) => write!(f, "card {}", x),
+            CredentialId::Tag(x) => write!(f, "tag {}", x),
+        }
+    }
+}
+
+/// A key challenges may be signed with
+#[derive(Debug, Clone)]
+pub struct Signer {
+    pub user: u16,
+    pub key: VerifyingKey,
+    /// signing with the key raises the duress alarm
+    pub duress: bool,
+}
+
+/// The registry of users & their credentials, kept in NVS
+pub struct Credentials {
+    /// `None` until a new owner claims the device after a factory reset
+    owner: Mutex<Option<VerifyingKey>>,
+    salt: [u8; 16],
+    users: Mutex<Vec<User>>,
+    /// the parsed keys of all users (including the owner)
+    keys: Mutex<Vec<Signer>>,
+    nvs: Mutex<EspNvs<NvsDefault>>,
+}
+
+impl Credentials {
+    /// loads the registry; a claimed owner key takes precedence over the built-in `owner`
+    pub fn load(
+        partition: EspDefaultNvsPartition,
+        owner: Option<VerifyingKey>,
+    ) -> Result<Self, EspError> {
+        let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let mut key: [u8; 65] = [0x0; 65];
+        let claimed = nvs
+            .get_raw(NVS_OWNER_KEY, &mut key)?
+            .and_then(|x| VerifyingKey::from_sec1_bytes(x).ok());
+        let owner = claimed.or(owner);
+        if owner.is_none() {
+            log::warn!("[🪪] No owner: waiting for one to claim the device");
+        }
+        let mut salt: [u8; 16] = [0x0; 16];
+        let stored = nvs.get_raw(NVS_SALT_KEY, &mut salt)?.map(|x| x.len());
+        if stored != Some(salt.len()) {
+            log::info!("[🪪] Generating the device salt");
+            thread_rng().fill(&mut salt);
+            nvs.set_raw(NVS_SALT_KEY, &salt)?;
+        }
+        // all users with their keys, PINs & tags don't fit a fixed buffer
+        let mut buf = vec![0x0; nvs.blob_len(NVS_USERS_KEY)?.unwrap_or(0)];
+        let users: Vec<User> = match nvs.get_raw(NVS_USERS_KEY, &mut buf)? {
+            Some(x) => serde_json::from_slice(x).unwrap_or_else(|why| {
+                log::error!("[❌] Failed to parse the stored users: {:?}", why);
+                Vec::new()
+            }),
+            None => Vec::new(),
+        };
+        log::info!("[🪪] {} users enrolled", users.len());
+        let credentials = Credentials {
+            owner: Mutex::new(owner),
+            salt,
+            users: Mutex::new(Vec::new()),
+            keys: Mutex::new(Vec::new()),
+            nvs: Mutex::new(nvs),
+        };
+        credentials.replace(users);
+        Ok(credentials)
+    }
+
+    /// whether the device has an owner (it hasn't right after a factory reset)
+    pub fn has_owner(&self) -> bool {
+        self.owner
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .is_some()
+    }
+
+    /// makes `key` the owner's key
+    ///
+    /// Errors with `0x1F` if the device already has an owner and `0x0A` if the key couldn't be
+    /// stored
+    pub fn claim(&self, key: VerifyingKey) -> Result<(), i32> {
+        {
+            let mut owner = self.owner.lock().unwrap_or_else(|x| x.into_inner());
+            if owner.is_some() {
+                log::error!("[⛔] The device has already been claimed");
+                return Err(0x1F);
+            }
+            self.nvs
+                .lock()
+                .unwrap_or_else(|x| x.into_inner())
+                .set_raw(NVS_OWNER_KEY, key.to_encoded_point(false).as_bytes())
+                .map_err(|why| {
+                    log::error!("[❌] Failed to store the owner key: {:?}", why);
+                    0x0A
+                })?;
+            *owner = Some(key);
+        }
+        log::info!("[🪪] The device has been claimed by a new owner");
+        self.replace(self.users());
+        Ok(())
+    }
+
+    /// the keys challenges may be signed with and the users they belong to
+    pub fn keys(&self) -> Vec<Signer> {
+        self.keys.lock().unwrap_or_else(|x| x.into_inner()).clone()
+    }
+
+    pub fn hash_pin(&self, pin: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt);
+        hasher.update(pin.as_bytes());
+        bytes_to_hex_string(&hasher.finalize())
+    }
+
+    /// the user `pin` belongs to and whether it is their duress PIN
+    pub fn find_pin(&self, pin: &str) -> Option<(u16, bool)> {
+        let hash = self.hash_pin(pin);
+        let users = self.users.lock().unwrap_or_else(|x| x.into_inner());
+        users.iter().find_map(|user| {
+            user.credentials.iter().find_map(|x| match x {
+                Credential::Pin { hash: x, duress } if *x == hash => Some((user.id, *duress)),
+                _ => None,
+            })
+        })
+    }
+
+    /// whether `pin` is one of the user's PINs: `Some(duress)` if it is
+    pub fn check_pin(&self, user: u16, pin: &str) -> Option<bool> {
+        self.find_pin(pin)
+            .filter(|(x, _)| *x == user)
+            .map(|(_, duress)| duress)
+    }
+
+    /// the role of the user, `None` if there is no such user
+    pub fn role(&self, user: u16) -> Option<Role> {
+        if user == OWNER {
+            return self.has_owner().then_some(Role::Owner);
+        }
+        self.users
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .find(|x| x.id == user)
+            .map(|x| x.role)
+    }
+
+    /// the user the card belongs to
+    pub fn find_card(&self, number: u32) -> Option<u16> {
+        self.find(|x| *x == Credential::Card { number })
+    }
+
+    /// the user the NFC tag (UID in hex) belongs to and the tag's DESFire key, if it has one
+    pub fn find_tag(&self, uid: &str) -> Option<(u16, Option<[u8; 16]>)> {
+        let users = self.users.lock().unwrap_or_else(|x| x.into_inner());
+        users.iter().find_map(|user| {
+            user.credentials.iter().find_map(|x| match x {
+                Credential::Tag { uid: x, key } if x.eq_ignore_ascii_case(uid) => Some((
+                    user.id,
+                    key.as_deref()
+                        .and_then(hex_string_to_bytes)
+                        .and_then(|x| x.try_into().ok()),
+                )),
+                _ => None,
+            })
+        })
+    }
+
+    fn find(&self, predicate: impl Fn(&Credential) -> bool) -> Option<u16> {
+        self.users
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .find(|x| x.credentials.iter().any(&predicate))
+            .map(|x| x.id)
+    }
+
+    /// the enrolled users with the types of their credentials (the secrets aren't exposed)
+    pub fn list(&self) -> Value {
+        let users = self.users.lock().unwrap_or_else(|x| x.into_inner());
+        Value::Array(
+            users
+                .iter()
+                .map(|x| {
+                    let credentials: Vec<Value> = x
+                        .credentials
+                        .iter()
+                        .map(|x| match x {
+                            Credential::Key { public_key, duress } => {
+                                json!({ "type": "key", "public_key": public_key, "duress": duress })
+                            }
+                            Credential::Pin { duress, .. } => {
+                                json!({ "type": "pin", "duress": duress })
+                            }
+                            Credential::Card { number } => {
+                                json!({ "type": "card", "number": number })
+                            }
+                            Credential::Tag { uid, key } => {
+                                json!({ "type": "tag", "uid": uid, "desfire": key.is_some() })
+                            }
+                        })
+                        .collect();
+                    json!({ "id": x.id, "name": x.name, "role": x.role, "credentials": credentials })
+                })
+                .collect(),
+        )
+    }
+
+    /// adds the user (or replaces the one with the same id) on behalf of a user with the role `by`
+    /// from the json `body`: `{"id", "name", "role": "admin|user|guest|auditor", "credentials": [{"type": "key", "public_key": "<hex>"}, {"type": "pin",
+    /// "pin": "1234"}, {"type": "card", "number": 123}, {"type": "tag", "uid": "<hex>", "key":
+    /// "<hex>"}]}`
+    ///
+    /// Errors with `0x0B` if the body is invalid, `0x0A` if it couldn't be stored and `0x19` if `by`
+    /// may not manage the user (see [`Credentials::may_manage`])
+    pub fn enroll(&self, body: &[u8], by: Role) -> Result<(), i32> {
+        let body: EnrollBody = serde_json::from_slice(body).map_err(|why| {
+            log::error!("[❌] Invalid user: {:?}", why);
+            0x0B
+        })?;
+        let role = match (body.id, body.role) {
+            (OWNER, _) => Role::Owner,
+            (_, Role::Owner) => {
+                log::error!("[❌] Only user {} can be the owner", OWNER);
+                return Err(0x0B);
+            }
+            (_, x) => x,
+        };
+        if !self.may_manage(by, body.id) || (role == Role::Admin && by != Role::Owner) {
+            log::error!(
+                "[⛔] A {:?} can't enroll user {} as {:?}",
+                by,
+                body.id,
+                role
+            );
+            return Err(0x19);
+        }
+        let credentials = body
+            .credentials
+            .into_iter()
+            .map(|x| match x {
+                EnrollCredential::Key { public_key, duress } => hex_string_to_bytes(&public_key)
+                    .and_then(|x| VerifyingKey::from_sec1_bytes(&x).ok())
+                    .map(|_| Credential::Key { public_key, duress }),
+                EnrollCredential::Pin { pin, duress } => Some(pin)
+                    .filter(|x| (4..=8).contains(&x.len()) && x.chars().all(|x| x.is_ascii_digit()))
+                    .map(|x| Credential::Pin {
+                        hash: self.hash_pin(&x),
+                        duress,
+                    }),
+                EnrollCredential::Card { number } => Some(Credential::Card { number }),
+                EnrollCredential::Tag { uid, key } => {
+                    let uid_valid =
+                        hex_string_to_bytes(&uid).is_some_and(|x| (4..=10).contains(&x.len()));
+                    let key_valid = key.as_deref().map_or(true, |x| {
+                        hex_string_to_bytes(x).is_some_and(|x| x.len() == 16)
+                    });
+                    (uid_valid && key_valid).then(|| Credential::Tag {
+                        uid: uid.to_lowercase(),
+                        key,
+                    })
+                }
+            })
+            .collect::<Option<Vec<Credential>>>()
+            .ok_or_else(|| {
+                log::error!("[❌] Invalid credential for user {}", body.id);
+                0x0B
+            })?;
+
+        let mut users = self.users();
+        users.retain(|x| x.id != body.id);
+        // a PIN entered on a keypad identifies the user
+        let pin_hashes: Vec<&String> = users
+            .iter()
+            .flat_map(|x| x.credentials.iter())
+            .chain(credentials.iter())
+            .filter_map(|x| match x {
+                Credential::Pin { hash, .. } => Some(hash),
+                _ => None,
+            })
+            .collect();
+        let taken = pin_hashes
+            .iter()
+            .enumerate()
+            .any(|(i, x)| pin_hashes[i + 1..].contains(x));
+        if taken {
+            log::error!("[❌] The PIN of user {} is already taken", body.id);
+            return Err(0x0B);
+        }
+        if users.len() >= MAX_USERS {
+            log::error!("[❌] Can't enroll more than {} users", MAX_USERS);
+            return Err(0x0A);
+        }
+        log::info!("[🪪] Enrolling user {} ('{}')", body.id, body.name);
+        users.push(User {
+            id: body.id,
+            name: body.name,
+            role,
+            credentials,
+        });
+        self.store(users)
+    }
+
+    /// removes the user with the id in the body (u16 BE) on behalf of a user with the role `by`
+    pub fn remove(&self, body: &[u8], by: Role) -> Result<(), i32> {
+        let id = match body {
+            [a, b] => u16::from_be_bytes([*a, *b]),
+            _ => return Err(0x0B),
+        };
+        if !self.may_manage(by, id) {
+            log::error!("[⛔] A {:?} can't remove user {}", by, id);
+            return Err(0x19);
+        }
+        let mut users = self.users();
+        users.retain(|x| x.id != id);
+        log::info!("[🪪] Removing user {}", id);
+        self.store(users)
+    }
+
+    /// only the owner manages itself & the admins
+    fn may_manage(&self, by: Role, user: u16) -> bool {
+        by == Role::Owner || !matches!(self.role(user), Some(Role::Owner | Role::Admin))
+    }
+
+    fn users(&self) -> Vec<User> {
+        self.users.lock().unwrap_or_else(|x| x.into_inner()).clone()
+    }
+
+    fn store(&self, users: Vec<User>) -> Result<(), i32> {
+        let data = serde_json::to_vec(&users).map_err(|_| 0x0A)?;
+        self.nvs
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .set_raw(NVS_USERS_KEY, &data)
+            .map_err(|why| {
+                log::error!("[❌] Failed to store the users: {:?}", why);
+                0x0A
+            })?;
+        self.replace(users);
+        Ok(())
+    }
+
+    fn replace(&self, users: Vec<User>) {
+        let owner = *self.owner.lock().unwrap_or_else(|x| x.into_inner());
+        let mut keys: Vec<Signer> = owner
+            .map(|key| Signer {
+                user: OWNER,
+                key,
+                duress: false,
+            })
+            .into_iter()
+            .collect();
+        for user in users.iter() {
+            for credential in user.credentials.iter() {
+                if let Credential::Key { public_key, duress } = credential {
+                    match hex_string_to_bytes(public_key)
+                        .and_then(|x| VerifyingKey::from_sec1_bytes(&x).ok())
+                    {
+                        Some(key) => keys.push(Signer {
+                            user: user.id,
+                            key,
+                            duress: *duress,
+                        }),
+                        None => log::error!("[❌] Invalid key of user {}", user.id),
+                    }
+                }
+            }
+        }
+        *self.keys.lock().unwrap_or_else(|x| x.into_inner()) = keys;
+        *self.users.lock().unwrap_or_else(|x| x.into_inner()) = users;
+    }
+}
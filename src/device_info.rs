@@ -0,0 +1,64 @@
+use std::ffi::CStr;
+
+use esp_idf_svc::sys::{self, esp, EspError};
+
+/// Firmware version as declared in `Cargo.toml`
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Short git hash of the commit the firmware was built from (set by `build.rs`)
+pub const GIT_HASH: &str = env!("GAX_GIT_HASH");
+
+pub fn idf_version() -> String {
+    unsafe { CStr::from_ptr(sys::esp_get_idf_version()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// returns the chip model and the chip revision (`major * 100 + minor`)
+#[allow(non_upper_case_globals)]
+pub fn chip_info() -> (String, u16) {
+    let mut info: sys::esp_chip_info_t = Default::default();
+    unsafe { sys::esp_chip_info(&mut info) };
+    let model = match info.model {
+        sys::esp_chip_model_t_CHIP_ESP32 => "ESP32",
+        sys::esp_chip_model_t_CHIP_ESP32S2 => "ESP32-S2",
+        sys::esp_chip_model_t_CHIP_ESP32S3 => "ESP32-S3",
+        sys::esp_chip_model_t_CHIP_ESP32C3 => "ESP32-C3",
+        sys::esp_chip_model_t_CHIP_ESP32C2 => "ESP32-C2",
+        sys::esp_chip_model_t_CHIP_ESP32C6 => "ESP32-C6",
+        sys::esp_chip_model_t_CHIP_ESP32H2 => "ESP32-H2",
+        x => return (format!("unknown ({})", x), info.revision),
+    };
+    (model.to_owned(), info.revision)
+}
+
+/// the device id is the factory programmed base MAC (eFuse) as a hex string
+pub fn device_id() -> Result<String, EspError> {
+    let mut mac: [u8; 6] = [0x0; 6];
+    esp!(unsafe { sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) })?;
+    Ok(crate::bytes_to_hex_string(&mac))
+}
+
+#[allow(non_upper_case_globals)]
+pub fn reset_reason() -> &'static str {
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external_pin",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "other_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+pub fn free_heap() -> u32 {
+    unsafe { sys::esp_get_free_heap_size() }
+}
+
+pub fn min_free_heap() -> u32 {
+    unsafe { sys::esp_get_minimum_free_heap_size() }
+}
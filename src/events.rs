@@ -0,0 +1,40 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+use crate::logs::LogEntry;
+
+/// Everything observers (e.g. the MQTT bridge) might want to know about
+#[derive(Debug, Clone)]
+pub enum GateEvent {
+    Log(LogEntry),
+    GateOpened,
+    GateClosed,
+    /// the gate is held open until the passage mode ends (published before `GateOpened` &
+    /// `GateClosed`)
+    PassageMode(bool),
+    /// the door contact reports the door open (`true`) or closed
+    DoorOpen(bool),
+}
+
+/// Fans out [`GateEvent`]s to every subscriber
+#[derive(Debug, Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Sender<GateEvent>>>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Receiver<GateEvent> {
+        let (tx, rx) = channel();
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .push(tx);
+        rx
+    }
+
+    pub fn publish(&self, event: GateEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|x| x.into_inner());
+        // drop subscribers which went away
+        subscribers.retain(|x| x.send(event.clone()).is_ok());
+    }
+}
@@ -0,0 +1,92 @@
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Input, InputPin, PinDriver};
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sys::{esp, esp_restart, nvs_flash_erase, nvs_flash_init, EspError};
+
+const NVS_NAMESPACE: &str = "gax_reset";
+/// Written right after the wipe: the built-in owner key isn't trusted anymore
+const NVS_UNCLAIMED_KEY: &str = "unclaimed";
+/// The exit button has to be held this long at boot to reset the device
+pub const BOOT_HOLD: Duration = Duration::from_secs(10);
+/// A reset requested by a command is carried out after this delay, so the response still goes out
+const COMMAND_DELAY: Duration = Duration::from_secs(1);
+/// A new owner can claim the device this long after it booted into the enrollment mode
+pub const CLAIM_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// When a new owner may claim the device: within [`CLAIM_WINDOW`] of the boot into the enrollment
+/// mode or while the exit button is held, so only somebody at the gate can take it over
+pub struct ClaimWindow {
+    /// the boot, if it came up in the enrollment mode
+    boot: Option<Instant>,
+    exit_button_held: Arc<AtomicBool>,
+}
+
+impl ClaimWindow {
+    pub fn new(boot: Instant, unclaimed: bool, exit_button_held: Arc<AtomicBool>) -> Self {
+        ClaimWindow {
+            boot: unclaimed.then_some(boot),
+            exit_button_held,
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.exit_button_held.load(Ordering::Relaxed)
+            || self.boot.is_some_and(|x| x.elapsed() < CLAIM_WINDOW)
+    }
+}
+
+/// whether the device has been reset and waits for a new owner to claim it
+pub fn unclaimed(partition: EspDefaultNvsPartition) -> Result<bool, EspError> {
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    Ok(nvs.get_u8(NVS_UNCLAIMED_KEY)?.is_some())
+}
+
+/// whether the (active low) exit button is pressed at boot and held for [`BOOT_HOLD`]
+pub fn held_at_boot<P: InputPin>(button: &PinDriver<'static, P, Input>) -> bool {
+    if button.is_high() {
+        return false;
+    }
+    log::warn!(
+        "[🧹] Exit button held at boot: keep holding it for {}s to reset the device",
+        BOOT_HOLD.as_secs()
+    );
+    let since = Instant::now();
+    while since.elapsed() < BOOT_HOLD {
+        if button.is_high() {
+            log::info!("[🧹] Factory reset aborted");
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    true
+}
+
+/// erases the whole NVS partition and reboots into the enrollment mode: the user registry, the
+/// queued webhook deliveries & lifetime counters, the network & hands-free settings, the bonds
+/// and the device identity key (regenerated on the next boot) are gone; the access log only lives
+/// in memory
+pub fn wipe(partition: EspDefaultNvsPartition) -> Result<Infallible, EspError> {
+    log::warn!("[🧹] Factory reset: erasing the NVS partition");
+    esp!(unsafe { nvs_flash_erase() })?;
+    esp!(unsafe { nvs_flash_init() })?;
+    EspNvs::new(partition, NVS_NAMESPACE, true)?.set_u8(NVS_UNCLAIMED_KEY, 1)?;
+    log::warn!("[🧹] Rebooting into the enrollment mode");
+    unsafe { esp_restart() }
+}
+
+/// wipes the device shortly (see [`wipe`])
+pub fn schedule(partition: EspDefaultNvsPartition) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            std::thread::sleep(COMMAND_DELAY);
+            if let Err(why) = wipe(partition) {
+                log::error!("[❌] Factory reset failed: {:?}", why);
+            }
+        })?;
+    Ok(())
+}
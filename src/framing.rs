@@ -0,0 +1,224 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+use esp32_nimble::{BLEAddress, BLEConnDesc, OnWriteArgs};
+
+use crate::connections::Connections;
+
+/// Upper bound for a reassembled message
+pub const MAX_MESSAGE_LEN: usize = 4096;
+/// A transfer is dropped if the next fragment doesn't arrive in time
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(5);
+const CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+const FLAG_MORE_FRAGMENTS: u8 = 0x01;
+const FIRST_HEADER_LEN: usize = 8;
+const HEADER_LEN: usize = 2;
+
+/// Chunked transfer of messages of up to [`MAX_MESSAGE_LEN`] bytes over a characteristic,
+/// independent of the negotiated MTU & the phone's long read/write support
+///
+/// Every read/write carries one fragment:
+/// - first: `seq (0) | flags | total length (u16 BE) | CRC-32 of the message (u32 BE) | chunk`
+/// - following: `seq | flags | chunk`
+///
+/// `seq` counts up from 0 and `flags` `0x01` marks that more fragments follow. Reads return the
+/// next fragment of the current message (sized to the connection's MTU) and render a new message
+/// once the previous one has been read completely. Every fragment counts as activity of the
+/// connection (see [`Connections::touch`]). Transfers belong to the connection (address & handle)
+/// and are dropped when it closes, see [`Framing::disconnected`].
+pub struct Framing {
+    incoming: Mutex<Vec<Incoming>>,
+    outgoing: Mutex<Vec<Outgoing>>,
+    connections: Arc<Connections>,
+}
+
+struct Incoming {
+    address: BLEAddress,
+    conn_handle: u16,
+    next_seq: u8,
+    len: usize,
+    crc: u32,
+    data: Vec<u8>,
+    time: Instant,
+}
+
+struct Outgoing {
+    address: BLEAddress,
+    conn_handle: u16,
+    frames: VecDeque<Vec<u8>>,
+    time: Instant,
+}
+
+impl Framing {
+    /// a framing whose transfers are dropped with their connection
+    pub fn new(connections: Arc<Connections>) -> Arc<Self> {
+        let framing = Arc::new(Framing {
+            incoming: Mutex::new(Vec::new()),
+            outgoing: Mutex::new(Vec::new()),
+            connections: connections.clone(),
+        });
+        connections.register_framing(&framing);
+        framing
+    }
+
+    /// the next fragment for the reading client; `render` creates the message on the first read
+    pub fn read(&self, desc: &BLEConnDesc, render: impl FnOnce() -> Vec<u8>) -> Vec<u8> {
+        let (address, conn_handle) = (desc.address(), desc.conn_handle());
+        self.connections.touch(address);
+        let mut outgoing = self.outgoing.lock().unwrap_or_else(|x| x.into_inner());
+        outgoing.retain(|x| x.time.elapsed() < FRAGMENT_TIMEOUT && !x.frames.is_empty());
+        let transfer = match outgoing
+            .iter_mut()
+            .position(|x| x.address == address && x.conn_handle == conn_handle)
+        {
+            Some(x) => &mut outgoing[x],
+            None => {
+                // a read response carries at most MTU - 1 bytes
+                let frame_len = (desc.mtu() as usize).saturating_sub(1);
+                outgoing.push(Outgoing {
+                    address,
+                    conn_handle,
+                    frames: fragment(&render(), frame_len).into(),
+                    time: Instant::now(),
+                });
+                outgoing.last_mut().unwrap()
+            }
+        };
+        transfer.time = Instant::now();
+        transfer.frames.pop_front().unwrap_or_default()
+    }
+
+    /// reassembles a written fragment; `Some` once the message is complete
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x20` a fragment is out of order (or the transfer timed out)
+    /// - `0x21` the message is longer than [`MAX_MESSAGE_LEN`]
+    /// - `0x22` the CRC doesn't match
+    /// - `0x23` the message length doesn't match the announced one
+    /// - `0x24` the fragment header is incomplete
+    pub fn write(
+        &self,
+        address: BLEAddress,
+        conn_handle: u16,
+        data: &[u8],
+    ) -> Result<Option<Vec<u8>>, i32> {
+        self.connections.touch(address);
+        let mut incoming = self.incoming.lock().unwrap_or_else(|x| x.into_inner());
+        incoming.retain(|x| x.time.elapsed() < FRAGMENT_TIMEOUT);
+        if data.len() < HEADER_LEN {
+            return Err(0x24);
+        }
+        let (seq, flags) = (data[0], data[1]);
+        let index = if seq == 0 {
+            if data.len() < FIRST_HEADER_LEN {
+                return Err(0x24);
+            }
+            let len = u16::from_be_bytes([data[2], data[3]]) as usize;
+            if len > MAX_MESSAGE_LEN {
+                log::error!("[❌] ({}) Message of {} bytes is too long", address, len);
+                return Err(0x21);
+            }
+            incoming.retain(|x| x.address != address || x.conn_handle != conn_handle);
+            incoming.push(Incoming {
+                address,
+                conn_handle,
+                next_seq: 0,
+                len,
+                crc: u32::from_be_bytes([data[4], data[5], data[6], data[7]]),
+                data: Vec::with_capacity(len),
+                time: Instant::now(),
+            });
+            incoming.len() - 1
+        } else {
+            match incoming.iter().position(|x| {
+                x.address == address && x.conn_handle == conn_handle && x.next_seq == seq
+            }) {
+                Some(x) => x,
+                None => {
+                    log::error!("[❌] ({}) Got fragment {} out of order", address, seq);
+                    incoming.retain(|x| x.address != address || x.conn_handle != conn_handle);
+                    return Err(0x20);
+                }
+            }
+        };
+
+        let transfer = &mut incoming[index];
+        let chunk = &data[if seq == 0 {
+            FIRST_HEADER_LEN
+        } else {
+            HEADER_LEN
+        }..];
+        if transfer.data.len() + chunk.len() > transfer.len {
+            incoming.remove(index);
+            return Err(0x23);
+        }
+        transfer.data.extend_from_slice(chunk);
+        transfer.next_seq = transfer.next_seq.wrapping_add(1);
+        transfer.time = Instant::now();
+        if flags & FLAG_MORE_FRAGMENTS != 0 {
+            return Ok(None);
+        }
+
+        let transfer = incoming.remove(index);
+        if transfer.data.len() != transfer.len {
+            return Err(0x23);
+        }
+        if CRC.checksum(&transfer.data) != transfer.crc {
+            log::error!("[❌] ({}) CRC mismatch", address);
+            return Err(0x22);
+        }
+        Ok(Some(transfer.data))
+    }
+
+    /// like [`Framing::write`] for a characteristic write; errors are reported to the client
+    pub fn receive(&self, args: &mut OnWriteArgs) -> Option<Vec<u8>> {
+        let desc = args.desc();
+        match self.write(desc.address(), desc.conn_handle(), args.recv_data()) {
+            Ok(x) => x,
+            Err(code) => {
+                args.reject_with_error_code(code as u8);
+                None
+            }
+        }
+    }
+
+    /// drops the transfers of a closed connection
+    pub fn disconnected(&self, conn_handle: u16) {
+        self.incoming
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .retain(|x| x.conn_handle != conn_handle);
+        self.outgoing
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .retain(|x| x.conn_handle != conn_handle);
+    }
+}
+
+/// splits `payload` into frames of at most `frame_len` bytes
+pub fn fragment(payload: &[u8], frame_len: usize) -> Vec<Vec<u8>> {
+    let payload = &payload[..payload.len().min(MAX_MESSAGE_LEN)];
+    let first_len = frame_len.saturating_sub(FIRST_HEADER_LEN).max(1);
+    let rest_len = frame_len.saturating_sub(HEADER_LEN).max(1);
+    let mut chunks = vec![&payload[..first_len.min(payload.len())]];
+    chunks.extend(payload[chunks[0].len()..].chunks(rest_len));
+
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(seq, chunk)| {
+            let flags = if seq < last { FLAG_MORE_FRAGMENTS } else { 0 };
+            let mut frame = vec![seq as u8, flags];
+            if seq == 0 {
+                frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+                frame.extend_from_slice(&CRC.checksum(payload).to_be_bytes());
+            }
+            frame.extend_from_slice(chunk);
+            frame
+        })
+        .collect()
+}
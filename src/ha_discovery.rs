@@ -0,0 +1,120 @@
+use serde_json::{json, Value};
+
+use crate::metadata::MetaDataStruct;
+
+/// Home Assistant's default discovery prefix
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// Topic (below the gax prefix) receiving the `lock`/`button` commands
+pub use gax_mqtt::HA_COMMAND_TOPIC as COMMAND_TOPIC;
+
+/// The retained discovery configs `(topic, payload)` announcing the gate to Home Assistant
+///
+/// Entities: a `lock` & an "open" `button` (commands go to [`COMMAND_TOPIC`]), the door
+/// `binary_sensor` and diagnostic sensors for the uptime, the failed attempts and the last user.
+pub fn configs(
+    discovery_prefix: &str,
+    prefix: &str,
+    meta: &MetaDataStruct,
+) -> Vec<(String, String)> {
+    let node_id = format!("gax_{}", meta.device_id);
+    let device = json!({
+        "identifiers": [node_id],
+        "name": format!("GAX {}", meta.device_id),
+        "manufacturer": "GAX",
+        "model": meta.chip_model,
+        "sw_version": format!("{} ({})", meta.firmware_version, meta.git_hash),
+    });
+    let availability_topic = format!("{}/status", prefix);
+    let state_topic = format!("{}/state", prefix);
+    let meta_topic = format!("{}/meta", prefix);
+    let log_topic = format!("{}/log", prefix);
+    let command_topic = format!("{}/{}", prefix, COMMAND_TOPIC);
+
+    let entities: [(&str, &str, Value); 6] = [
+        (
+            "lock",
+            "gate",
+            json!({
+                "name": "Gate",
+                "state_topic": state_topic,
+                "value_template": "{{ 'UNLOCKED' if value_json.state == 'open' else 'LOCKED' }}",
+                "command_topic": command_topic,
+                "payload_unlock": "UNLOCK",
+                "payload_lock": "LOCK",
+                "optimistic": false,
+            }),
+        ),
+        (
+            "button",
+            "open",
+            json!({
+                "name": "Open",
+                "command_topic": command_topic,
+                "payload_press": "UNLOCK",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "door",
+            json!({
+                "name": "Door",
+                "device_class": "door",
+                "state_topic": state_topic,
+                "value_template": "{{ 'ON' if value_json.state == 'open' else 'OFF' }}",
+            }),
+        ),
+        (
+            "sensor",
+            "uptime",
+            json!({
+                "name": "Uptime",
+                "entity_category": "diagnostic",
+                "device_class": "duration",
+                "unit_of_measurement": "h",
+                "state_topic": meta_topic,
+                "value_template": "{{ value_json.power_on_hours | round(2) }}",
+            }),
+        ),
+        (
+            "sensor",
+            "failed_attempts",
+            json!({
+                "name": "Failed attempts",
+                "entity_category": "diagnostic",
+                "state_class": "total_increasing",
+                "state_topic": meta_topic,
+                "value_template": "{{ value_json.failed_openings }}",
+            }),
+        ),
+        (
+            "sensor",
+            "last_user",
+            json!({
+                "name": "Last user",
+                "entity_category": "diagnostic",
+                "icon": "mdi:account-key",
+                "state_topic": log_topic,
+                "value_template": concat!(
+                    "{{ (value_json.credential.id if value_json.user is none else value_json.user)",
+                    " if value_json.status == 'Successful' else this.state }}"
+                ),
+            }),
+        ),
+    ];
+
+    entities
+        .into_iter()
+        .map(|(component, object_id, mut config)| {
+            config["unique_id"] = json!(format!("{}_{}", node_id, object_id));
+            config["availability_topic"] = json!(availability_topic);
+            config["device"] = device.clone();
+            (
+                format!(
+                    "{}/{}/{}/{}/config",
+                    discovery_prefix, component, node_id, object_id
+                ),
+                config.to_string(),
+            )
+        })
+        .collect()
+}
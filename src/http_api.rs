@@ -0,0 +1,156 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::sys::{self, EspError};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::challenge::Client;
+use crate::hex_string_to_bytes;
+use crate::remote::RemoteContext;
+use crate::roles::Operation;
+
+/// Upper bound for request bodies; an unlock request is well below that
+const MAX_BODY_LEN: usize = 512;
+
+#[derive(Deserialize)]
+struct UnlockRequest {
+    /// hex encoded `challenge | DER signature`
+    response: String,
+}
+
+/// Routes (all responses are json):
+/// - `GET /challenge` `{"challenge": hex}`
+/// - `POST /unlock` `{"response": hex}`, same semantics as a write to the lock characteristic;
+///   answers `{"ok": true}` or `403` `{"ok": false, "error": code}`
+/// - `GET /logs?since=<seq>&response=<hex>` the [`crate::logs::LogEntry`]s newer than `seq` (all
+///   by default)
+/// - `GET /meta?response=<hex>` the metadata json
+///
+/// The reads need the hex encoded `challenge | DER signature` of a user whose role allows them;
+/// they answer `403` `{"ok": false, "error": code}` otherwise. Challenges & lockouts belong to
+/// the source address of the request.
+pub fn start(ctx: RemoteContext) -> Result<(), EspError> {
+    let mut server = EspHttpServer::new(&Configuration {
+        stack_size: 10240,
+        ..Default::default()
+    })?;
+
+    let challenge_ctx = ctx.clone();
+    server.fn_handler(
+        "/challenge",
+        Method::Get,
+        move |mut req| match challenge_ctx.issue_challenge(Client::Http(peer_address(&mut req))) {
+            Some(x) => respond(req, 200, &json!({ "challenge": x }).to_string()),
+            None => respond(
+                req,
+                500,
+                &json!({ "error": "failed to issue a challenge" }).to_string(),
+            ),
+        },
+    )?;
+
+    let unlock_ctx = ctx.clone();
+    server.fn_handler("/unlock", Method::Post, move |mut req| {
+        let client = Client::Http(peer_address(&mut req));
+        let result = read_body(&mut req)
+            .ok()
+            .and_then(|x| serde_json::from_slice::<UnlockRequest>(&x).ok())
+            .and_then(|x| hex_string_to_bytes(x.response.trim()))
+            .ok_or(0x01)
+            .and_then(|x| unlock_ctx.unlock(&client, &x));
+        match result {
+            Ok(_) => respond(req, 200, &json!({ "ok": true }).to_string()),
+            Err(code) => respond(req, 403, &json!({ "ok": false, "error": code }).to_string()),
+        }
+    })?;
+
+    let logs_ctx = ctx.clone();
+    server.fn_handler("/logs", Method::Get, move |mut req| {
+        let client = Client::Http(peer_address(&mut req));
+        let response = query_param(req.uri(), "response");
+        let since = query_param(req.uri(), "since")
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(0);
+        match logs_ctx.dispatch(&client, response, Operation::ReadLogs, || {
+            json!(logs_ctx.logs.since(since)).to_string()
+        }) {
+            Ok(x) => respond(req, 200, &x),
+            Err(code) => respond(req, 403, &json!({ "ok": false, "error": code }).to_string()),
+        }
+    })?;
+
+    server.fn_handler("/meta", Method::Get, move |mut req| {
+        let client = Client::Http(peer_address(&mut req));
+        let response = query_param(req.uri(), "response");
+        match ctx.dispatch(&client, response, Operation::ReadMetadata, || {
+            ctx.metadata.to_json()
+        }) {
+            Ok(x) => respond(req, 200, &x),
+            Err(code) => respond(req, 403, &json!({ "ok": false, "error": code }).to_string()),
+        }
+    })?;
+
+    log::info!("[🌐] HTTP API listening on port 80");
+    // the server stops when dropped and has to run for the whole lifetime of the firmware
+    std::mem::forget(server);
+    Ok(())
+}
+
+fn respond(req: Request<&mut EspHttpConnection>, status: u16, body: &str) -> Result<(), EspError> {
+    let mut res = req.into_response(status, None, &[("Content-Type", "application/json")])?;
+    res.write_all(body.as_bytes())
+}
+
+fn read_body(req: &mut Request<&mut EspHttpConnection>) -> Result<Vec<u8>, EspError> {
+    let len = (req.content_len().unwrap_or(0) as usize).min(MAX_BODY_LEN);
+    let mut buf = vec![0x0; len];
+    req.read_exact(&mut buf).map_err(|why| match why {
+        esp_idf_svc::io::ReadExactError::Other(x) => x,
+        esp_idf_svc::io::ReadExactError::UnexpectedEof => {
+            EspError::from_infallible::<{ esp_idf_svc::sys::ESP_FAIL }>()
+        }
+    })?;
+    Ok(buf)
+}
+
+/// the source address of the request; unspecified if the socket can't tell
+fn peer_address(req: &mut Request<&mut EspHttpConnection>) -> IpAddr {
+    let unspecified = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    let Ok(raw) = req.connection().raw_connection() else {
+        return unspecified;
+    };
+    // lwIP's sockaddr_in(6): length, family, port, then the address (at 4, or at 8 after the
+    // flow info)
+    let mut addr = [0u32; 8];
+    let mut len = std::mem::size_of_val(&addr) as sys::socklen_t;
+    let result = unsafe {
+        let fd = sys::httpd_req_to_sockfd(raw.handle());
+        sys::lwip_getpeername(fd, addr.as_mut_ptr() as *mut sys::sockaddr, &mut len)
+    };
+    if result != 0 {
+        return unspecified;
+    }
+    let bytes: Vec<u8> = addr.iter().flat_map(|x| x.to_ne_bytes()).collect();
+    match bytes[1] as u32 {
+        sys::AF_INET => IpAddr::V4(Ipv4Addr::new(bytes[4], bytes[5], bytes[6], bytes[7])),
+        sys::AF_INET6 => {
+            let address = Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[8..24]).unwrap());
+            address
+                .to_ipv4_mapped()
+                .map_or(IpAddr::V6(address), IpAddr::V4)
+        }
+        _ => unspecified,
+    }
+}
+
+fn query_param<'a>(uri: &'a str, key: &str) -> Option<&'a str> {
+    uri.split_once('?')?
+        .1
+        .split('&')
+        .filter_map(|x| x.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
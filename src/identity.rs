@@ -0,0 +1,73 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use k256::ecdsa::signature::Signer;
+use k256::ecdsa::{Signature, SigningKey};
+use rand::{thread_rng, Rng};
+
+use crate::bytes_to_hex_string;
+
+const NVS_NAMESPACE: &str = "gax_ident";
+const NVS_KEY: &str = "key";
+const NVS_IRK_KEY: &str = "irk";
+const NVS_STATUS_KEY: &str = "status_key";
+
+/// Who the device is: its id (the base MAC), its own key pair and the secrets of the advertised
+/// status, generated on the first boot and again after a factory reset
+pub struct Identity {
+    pub device_id: String,
+    key: SigningKey,
+    /// the identity resolving key of the rotated private addresses (little endian, like NimBLE)
+    pub irk: [u8; 16],
+    /// keys the id hash of the advertised status; handed to the users opening a session
+    pub status_key: [u8; 16],
+}
+
+impl Identity {
+    pub fn load(partition: EspDefaultNvsPartition, device_id: String) -> Result<Self, EspError> {
+        let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let mut buf: [u8; 32] = [0x0; 32];
+        let stored = nvs
+            .get_raw(NVS_KEY, &mut buf)?
+            .and_then(|x| SigningKey::from_slice(x).ok());
+        let key = match stored {
+            Some(x) => x,
+            None => {
+                log::info!("[🪪] Generating the device identity key");
+                let key = SigningKey::random(&mut thread_rng());
+                nvs.set_raw(NVS_KEY, &key.to_bytes())?;
+                key
+            }
+        };
+        let irk = load_secret(&mut nvs, NVS_IRK_KEY)?;
+        let status_key = load_secret(&mut nvs, NVS_STATUS_KEY)?;
+        Ok(Identity {
+            device_id,
+            key,
+            irk,
+            status_key,
+        })
+    }
+
+    /// the public key (compressed SEC1, hex)
+    pub fn public_key(&self) -> String {
+        bytes_to_hex_string(self.key.verifying_key().to_encoded_point(true).as_bytes())
+    }
+
+    /// a DER signature over `data`, proving the device's identity
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let signature: Signature = self.key.sign(data);
+        signature.to_der().as_bytes().to_vec()
+    }
+}
+
+/// the random 16 byte secret stored under `key`, generated if there is none
+fn load_secret(nvs: &mut EspNvs<NvsDefault>, key: &str) -> Result<[u8; 16], EspError> {
+    let mut buf: [u8; 16] = [0x0; 16];
+    if nvs.get_raw(key, &mut buf)?.is_some_and(|x| x.len() == 16) {
+        return Ok(buf);
+    }
+    log::info!("[🪪] Generating the {}", key);
+    thread_rng().fill(&mut buf);
+    nvs.set_raw(key, &buf)?;
+    Ok(buf)
+}
@@ -0,0 +1,439 @@
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Output, OutputPin, PinDriver};
+use esp_idf_svc::hal::ledc::{
+    config::TimerConfig, LedcChannel, LedcDriver, LedcTimer, LedcTimerDriver,
+};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::rmt::{
+    config::TransmitConfig, FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver,
+};
+use esp_idf_svc::hal::units::FromValueType;
+use esp_idf_svc::sys::EspError;
+use serde::Deserialize;
+
+use crate::connections::Connections;
+use crate::events::GateEvent;
+use crate::lockout::Lockout;
+use crate::logs::LogEntryStatus;
+use crate::supervisor::Watchdog;
+
+/// The engine re-evaluates its inputs this often
+const TICK: Duration = Duration::from_millis(50);
+
+/// Kind of the status LED wired to the status pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LedKind {
+    /// plain LED switched on & off
+    Gpio,
+    /// plain LED dimmed through LEDC PWM
+    Pwm,
+    /// a single WS2812 (NeoPixel) driven by the RMT peripheral
+    Ws2812,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color::new(0, 0, 0);
+    pub const WHITE: Color = Color::new(255, 255, 255);
+    pub const RED: Color = Color::new(255, 0, 0);
+    pub const GREEN: Color = Color::new(0, 255, 0);
+    pub const BLUE: Color = Color::new(0, 0, 255);
+    pub const AMBER: Color = Color::new(255, 120, 0);
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+
+    /// the brightness of a single color LED showing this color
+    fn level(&self) -> u8 {
+        self.r.max(self.g).max(self.b)
+    }
+
+    fn scale(&self, brightness: u8) -> Color {
+        let scale = |x: u8| (x as u16 * brightness as u16 / 255) as u8;
+        Color::new(scale(self.r), scale(self.g), scale(self.b))
+    }
+}
+
+/// Something which can show a [`Color`]; single color LEDs show its brightness
+pub trait LedDriver: Send {
+    fn set(&mut self, color: Color) -> Result<(), EspError>;
+}
+
+impl<T: OutputPin> LedDriver for PinDriver<'static, T, Output> {
+    fn set(&mut self, color: Color) -> Result<(), EspError> {
+        if color.level() > 0 {
+            self.set_high()
+        } else {
+            self.set_low()
+        }
+    }
+}
+
+impl LedDriver for LedcDriver<'static> {
+    fn set(&mut self, color: Color) -> Result<(), EspError> {
+        let duty = self.get_max_duty() * color.level() as u32 / 255;
+        self.set_duty(duty)
+    }
+}
+
+/// a single WS2812 on an RMT channel
+pub struct Ws2812 {
+    tx: TxRmtDriver<'static>,
+}
+
+impl Ws2812 {
+    pub fn new<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'static,
+        pin: impl Peripheral<P = impl OutputPin> + 'static,
+    ) -> Result<Self, EspError> {
+        let config = TransmitConfig::new().clock_divider(1);
+        Ok(Ws2812 {
+            tx: TxRmtDriver::new(channel, pin, &config)?,
+        })
+    }
+}
+
+impl LedDriver for Ws2812 {
+    fn set(&mut self, color: Color) -> Result<(), EspError> {
+        let ticks_hz = self.tx.counter_clock()?;
+        let pulse =
+            |state, ns| Pulse::new_with_duration(ticks_hz, state, &Duration::from_nanos(ns));
+        let zero = (pulse(PinState::High, 350)?, pulse(PinState::Low, 800)?);
+        let one = (pulse(PinState::High, 700)?, pulse(PinState::Low, 600)?);
+        // the WS2812 expects GRB, most significant bit first
+        let grb = (color.g as u32) << 16 | (color.r as u32) << 8 | color.b as u32;
+        let mut signal = FixedLengthSignal::<24>::new();
+        for bit in 0..24 {
+            let pulses = if grb & (1 << (23 - bit)) != 0 {
+                &one
+            } else {
+                &zero
+            };
+            signal.set(bit, pulses)?;
+        }
+        self.tx.start_blocking(&signal)
+    }
+}
+
+/// creates the driver of the status LED on `pin`; the LEDC timer & channel are only used by
+/// [`LedKind::Pwm`], the RMT channel only by [`LedKind::Ws2812`]
+pub fn driver<P: OutputPin>(
+    kind: LedKind,
+    pin: P,
+    timer: impl Peripheral<P = impl LedcTimer> + 'static,
+    channel: impl Peripheral<P = impl LedcChannel> + 'static,
+    rmt: impl Peripheral<P = impl RmtChannel> + 'static,
+) -> Result<Box<dyn LedDriver>, EspError> {
+    Ok(match kind {
+        LedKind::Gpio => Box::new(PinDriver::output(pin)?),
+        LedKind::Pwm => {
+            let timer = LedcTimerDriver::new(timer, &TimerConfig::new().frequency(5.kHz().into()))?;
+            Box::new(LedcDriver::new(channel, timer, pin)?)
+        }
+        LedKind::Ws2812 => Box::new(Ws2812::new(rmt, pin)?),
+    })
+}
+
+/// Timings every [`Pattern`] is built from
+#[derive(Debug, Clone, Copy)]
+pub struct LedTimings {
+    /// a single blink (a pause has the same length)
+    pub on: Duration,
+    /// the dark gap after every blink or pause
+    pub gap: Duration,
+    /// scales every color (0-255)
+    pub brightness: u8,
+}
+
+impl Default for LedTimings {
+    fn default() -> Self {
+        LedTimings {
+            on: Duration::from_millis(500),
+            gap: Duration::from_millis(200),
+            brightness: 255,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    /// slow amber blinking until the BLE service is up
+    Booting,
+    /// a short blue flash every few seconds while nobody is connected
+    Advertising,
+    /// a blue double flash every few seconds while someone is connected
+    Connected,
+    /// five green blinks while the gate opens
+    Unlocking,
+    /// red blink, pause, three red blinks
+    Denied,
+    /// fast red blinking while the device is locked out
+    Lockout,
+    /// n red blinks followed by a pause (see [`crate::boot::BootError::blink_code`])
+    ErrorCode(u8),
+    /// the 6 digit pairing passkey, see [`crate::security::passkey_sequence`]
+    Passkey(u32),
+}
+
+impl Pattern {
+    /// a pattern only replaces a running one of the same or a lower priority
+    pub fn priority(&self) -> u8 {
+        match self {
+            Pattern::Booting | Pattern::Advertising | Pattern::Connected => 0,
+            Pattern::Lockout => 1,
+            Pattern::Unlocking | Pattern::Denied => 2,
+            Pattern::Passkey(_) => 3,
+            Pattern::ErrorCode(_) => 4,
+        }
+    }
+
+    /// how often [`StatusLed::show`] plays the pattern
+    pub fn repeat(&self) -> u8 {
+        match self {
+            Pattern::Passkey(_) => 2,
+            _ => 1,
+        }
+    }
+
+    pub fn steps(&self, timings: &LedTimings) -> Vec<(Color, Duration)> {
+        let on = timings.on;
+        let flash = on / 5;
+        let blinks = |color: Color, sequence: &[bool]| -> Vec<(Color, Duration)> {
+            sequence
+                .iter()
+                .flat_map(|x| {
+                    let color = if *x { color } else { Color::OFF };
+                    [(color, on), (Color::OFF, timings.gap)]
+                })
+                .collect()
+        };
+        match self {
+            Pattern::Booting => vec![(Color::AMBER, on), (Color::OFF, on)],
+            Pattern::Advertising => vec![(Color::BLUE, flash), (Color::OFF, on * 6)],
+            Pattern::Connected => vec![
+                (Color::BLUE, flash),
+                (Color::OFF, flash),
+                (Color::BLUE, flash),
+                (Color::OFF, on * 6),
+            ],
+            Pattern::Unlocking => blinks(Color::GREEN, &[true; 5]),
+            Pattern::Denied => blinks(Color::RED, &[true, false, true, true, true]),
+            Pattern::Lockout => vec![(Color::RED, on / 4), (Color::OFF, on / 4)],
+            Pattern::ErrorCode(n) => {
+                let mut sequence = vec![true; *n as usize];
+                sequence.extend([false; 3]);
+                blinks(Color::RED, &sequence)
+            }
+            Pattern::Passkey(x) => blinks(Color::WHITE, &crate::security::passkey_sequence(*x)),
+        }
+    }
+}
+
+enum LedCommand {
+    /// play a pattern on top of the current state
+    Show(Pattern, u8),
+    /// derive the state from the gate's events, the lockout & the connections
+    Follow {
+        events: Receiver<GateEvent>,
+        lockout: Arc<Lockout>,
+        connections: Arc<Connections>,
+    },
+    /// show this pattern forever, ignoring everything else
+    Halt(Pattern),
+}
+
+/// Handle to the single task driving the status LED
+#[derive(Debug, Clone)]
+pub struct StatusLed {
+    tx: Sender<LedCommand>,
+}
+
+impl StatusLed {
+    /// starts the LED task; it shows [`Pattern::Booting`] until [`follow`](StatusLed::follow) is
+    /// called
+    pub fn spawn(driver: Box<dyn LedDriver>, timings: LedTimings) -> std::io::Result<Self> {
+        let (tx, rx) = channel::<LedCommand>();
+        std::thread::Builder::new()
+            .stack_size(4 * 1024)
+            .spawn(move || Engine::new(driver, timings).run(rx))?;
+        Ok(StatusLed { tx })
+    }
+
+    /// plays the pattern [`Pattern::repeat`] times
+    pub fn show(&self, pattern: Pattern) {
+        self.send(LedCommand::Show(pattern, pattern.repeat()));
+    }
+
+    pub fn follow(
+        &self,
+        events: Receiver<GateEvent>,
+        lockout: Arc<Lockout>,
+        connections: Arc<Connections>,
+    ) {
+        self.send(LedCommand::Follow {
+            events,
+            lockout,
+            connections,
+        });
+    }
+
+    pub fn halt(&self, pattern: Pattern) {
+        self.send(LedCommand::Halt(pattern));
+    }
+
+    fn send(&self, command: LedCommand) {
+        if let Err(why) = self.tx.send(command) {
+            log::error!("[❌] Failed to tx to the status LED: {:?}", why);
+        }
+    }
+}
+
+struct Engine {
+    driver: Box<dyn LedDriver>,
+    timings: LedTimings,
+    follow: Option<(Receiver<GateEvent>, Arc<Lockout>, Arc<Connections>)>,
+    halted: Option<Pattern>,
+    /// the pattern shown on top of the state & how often it still has to be played
+    overlay: Option<(Pattern, u8)>,
+    /// the pattern being played, its steps & the current step
+    playing: Option<Pattern>,
+    steps: Vec<(Color, Duration)>,
+    step: usize,
+    step_end: Instant,
+}
+
+impl Engine {
+    fn new(driver: Box<dyn LedDriver>, timings: LedTimings) -> Self {
+        Engine {
+            driver,
+            timings,
+            follow: None,
+            halted: None,
+            overlay: None,
+            playing: None,
+            steps: Vec::new(),
+            step: 0,
+            step_end: Instant::now(),
+        }
+    }
+
+    fn run(mut self, rx: Receiver<LedCommand>) {
+        let watchdog = Watchdog::subscribe("status_led");
+        loop {
+            loop {
+                match rx.try_recv() {
+                    Ok(command) => self.handle(command),
+                    Err(TryRecvError::Empty) => break,
+                    // every handle is gone, keep showing the state
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+            self.handle_events();
+            self.tick();
+            watchdog.feed();
+            std::thread::sleep(TICK);
+        }
+    }
+
+    fn handle(&mut self, command: LedCommand) {
+        match command {
+            LedCommand::Show(pattern, repeat) => self.overlay(pattern, repeat),
+            LedCommand::Follow {
+                events,
+                lockout,
+                connections,
+            } => self.follow = Some((events, lockout, connections)),
+            LedCommand::Halt(pattern) => self.halted = Some(pattern),
+        }
+    }
+
+    fn handle_events(&mut self) {
+        let mut shown = Vec::new();
+        if let Some((events, _, _)) = self.follow.as_ref() {
+            while let Ok(event) = events.try_recv() {
+                match event {
+                    GateEvent::GateOpened => shown.push(Pattern::Unlocking),
+                    GateEvent::Log(entry) if matches!(entry.status, LogEntryStatus::Failed(_)) => {
+                        shown.push(Pattern::Denied)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for pattern in shown {
+            self.overlay(pattern, pattern.repeat());
+        }
+    }
+
+    fn overlay(&mut self, pattern: Pattern, repeat: u8) {
+        let current = self.overlay.map(|(x, _)| x.priority()).unwrap_or_default();
+        if repeat > 0 && pattern.priority() >= current {
+            self.overlay = Some((pattern, repeat));
+        }
+    }
+
+    /// the pattern which should be shown right now
+    fn wanted(&self) -> Pattern {
+        if let Some(pattern) = self.halted {
+            return pattern;
+        }
+        if let Some((pattern, _)) = self.overlay {
+            return pattern;
+        }
+        match self.follow.as_ref() {
+            None => Pattern::Booting,
+            Some((_, lockout, _)) if lockout.any_locked_out() => Pattern::Lockout,
+            Some((_, _, connections)) if connections.count() > 0 => Pattern::Connected,
+            Some(_) => Pattern::Advertising,
+        }
+    }
+
+    fn tick(&mut self) {
+        let now = Instant::now();
+        if self.playing == Some(self.wanted()) {
+            if now < self.step_end {
+                return;
+            }
+            self.step += 1;
+            if self.step >= self.steps.len() {
+                self.step = 0;
+                self.finished_cycle();
+            }
+        }
+        // start over if the wanted pattern changed (e.g. an overlay is done)
+        let wanted = self.wanted();
+        if self.playing != Some(wanted) {
+            self.playing = Some(wanted);
+            self.steps = wanted.steps(&self.timings);
+            self.step = 0;
+        }
+        let Some((color, duration)) = self.steps.get(self.step).copied() else {
+            return;
+        };
+        self.step_end = now + duration;
+        if let Err(why) = self.driver.set(color.scale(self.timings.brightness)) {
+            log::error!("[❌] Failed to set the status LED: {:?}", why);
+        }
+    }
+
+    fn finished_cycle(&mut self) {
+        match self.overlay {
+            Some((pattern, repeat)) if self.playing == Some(pattern) && repeat > 1 => {
+                self.overlay = Some((pattern, repeat - 1))
+            }
+            Some((pattern, _)) if self.playing == Some(pattern) => self.overlay = None,
+            _ => {}
+        }
+    }
+}
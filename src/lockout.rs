@@ -0,0 +1,114 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::challenge::Client;
+use crate::credentials::CredentialId;
+use crate::logs::LogEntryStatus;
+
+/// Number of failed attempts of a client within [`FAILURE_WINDOW`] which trigger its lockout
+const MAX_FAILURES: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// Time no opening of the client is accepted after too many failed attempts
+const LOCKOUT_TIME: Duration = Duration::from_secs(5 * 60);
+/// Upper bound for the clients with recent failures (e.g. BLE clients rotating their address)
+const MAX_TRACKED: usize = 64;
+
+#[derive(Debug, Default)]
+struct LockoutState {
+    failures: Vec<(Client, Instant)>,
+    locked: Vec<(Client, Instant)>,
+}
+
+/// Locks out a client after repeated failed attempts with a bad credential; the other clients
+/// (e.g. the residents' phones) aren't affected
+#[derive(Debug, Default)]
+pub struct Lockout {
+    state: Mutex<LockoutState>,
+}
+
+impl Lockout {
+    /// only a presented credential which has been rejected counts (a bad signature, an unknown
+    /// card or tag, a wrong PIN); malformed requests & unknown challenges can be produced without
+    /// any credential and would let anybody lock out everyone
+    fn counts(code: i32) -> bool {
+        matches!(code, 0x04 | 0x18 | 0x1A | 0x1C)
+    }
+
+    /// failures are counted per BLE address (whatever connection, so reconnecting doesn't evade
+    /// the lockout), MQTT client id or HTTP source address; a Wiegand or NFC reader counts as one client whatever is
+    /// presented to it, so trying card numbers doesn't evade it either
+    fn scope(client: &Client) -> Client {
+        match client {
+            Client::Ble(address, _) => Client::Ble(*address, u16::MAX),
+            Client::Wiegand(_) => Client::Wiegand(CredentialId::None),
+            Client::Nfc(_) => Client::Nfc(CredentialId::None),
+            x => x.clone(),
+        }
+    }
+
+    pub fn record(&self, client: &Client, status: &LogEntryStatus) {
+        let scope = Self::scope(client);
+        let mut state = self.state.lock().unwrap_or_else(|x| x.into_inner());
+        match status {
+            LogEntryStatus::Successful | LogEntryStatus::Duress => {
+                state.failures.retain(|(x, _)| *x != scope)
+            }
+            LogEntryStatus::Failed(code) if Self::counts(*code) => {
+                let now = Instant::now();
+                state.failures.retain(|(_, x)| now - *x < FAILURE_WINDOW);
+                if state.failures.len() >= MAX_TRACKED {
+                    state.failures.remove(0);
+                }
+                state.failures.push((scope.clone(), now));
+                let failures = state.failures.iter().filter(|(x, _)| *x == scope).count();
+                if failures >= MAX_FAILURES {
+                    log::error!(
+                        "[🔒] ({}) {} failed attempts, locking out for {}s",
+                        scope,
+                        failures,
+                        LOCKOUT_TIME.as_secs()
+                    );
+                    state.failures.retain(|(x, _)| *x != scope);
+                    state.locked.retain(|(x, _)| *x != scope);
+                    state.locked.push((scope, now + LOCKOUT_TIME));
+                }
+            }
+            LogEntryStatus::Failed(_) | LogEntryStatus::Restarted { .. } => {}
+        }
+    }
+
+    /// whether the requests of `client` are currently refused
+    pub fn is_locked_out(&self, client: &Client) -> bool {
+        let scope = Self::scope(client);
+        self.expire();
+        self.state
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .locked
+            .iter()
+            .any(|(x, _)| *x == scope)
+    }
+
+    /// whether any client is locked out (advertised & shown by the status LED)
+    pub fn any_locked_out(&self) -> bool {
+        self.expire();
+        !self
+            .state
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .locked
+            .is_empty()
+    }
+
+    fn expire(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|x| x.into_inner());
+        state.locked.retain(|(client, until)| {
+            let active = now < *until;
+            if !active {
+                log::info!("[🔓] ({}) Lockout expired", client);
+            }
+            active
+        });
+    }
+}
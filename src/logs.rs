@@ -0,0 +1,190 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use esp32_nimble::utilities::mutex::Mutex as NimbleMutex;
+use esp32_nimble::BLECharacteristic;
+use serde::Serialize;
+
+use crate::challenge::{Client, UnlockRequest};
+use crate::credentials::CredentialId;
+use crate::events::{EventBus, GateEvent};
+use crate::lockout::Lockout;
+use crate::stats::Stats;
+
+/// Number of entries kept in memory (and served by the logs characteristic)
+pub const MAX_LOG_ENTRIES: usize = 32;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct LogEntry {
+    // Total bytes: 15
+    pub seq: u32,                 // not encoded
+    pub time: SystemTime,         // 8byte
+    pub credential: CredentialId, // 6byte identifier, the type only in the json
+    pub status: LogEntryStatus,   // 1byte
+    pub source: LogSource,        // not encoded
+    pub user: Option<u16>,        // not encoded, see the credential for PINs
+    pub rssi: Option<i8>,         // not encoded, hands-free openings only
+    pub second_user: Option<u16>, // not encoded, dual authorizations only
+}
+impl LogEntry {
+    pub fn encode(&self) -> [u8; 15] {
+        let mut res: [u8; 15] = [0x0; 15];
+        // the clock may be stepped back by SNTP
+        let age = self.time.elapsed().unwrap_or_default().as_secs();
+        res[..8].clone_from_slice(&age.to_be_bytes());
+        res[8..14].clone_from_slice(&self.credential.encode());
+        res[14] = match self.status {
+            LogEntryStatus::Failed(x) => x as u8,
+            // the entries are notified to the connected phones: an attacker mustn't notice
+            LogEntryStatus::Successful | LogEntryStatus::Duress => 0,
+            LogEntryStatus::Restarted { .. } => 0xFF,
+        };
+        return res;
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+pub enum LogEntryStatus {
+    Successful,
+    Failed(i32),
+    /// a successful opening with a duress credential
+    Duress,
+    /// the device booted, see [`crate::device_info::reset_reason`] & the supervisor's reason for
+    /// its reboot
+    Restarted {
+        reset_reason: &'static str,
+        supervisor_reason: Option<String>,
+    },
+}
+
+/// The transport a request came in over
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum LogSource {
+    Ble,
+    Mqtt,
+    Http,
+    /// the local request-to-exit button
+    Button,
+    /// a Wiegand keypad or card reader
+    Wiegand,
+    /// an NFC reader
+    Nfc,
+    /// the device itself
+    System,
+}
+
+/// The access log shared by all transports
+pub struct LogStore {
+    entries: Mutex<Vec<LogEntry>>,
+    next_seq: AtomicU32,
+    notify: Arc<NimbleMutex<BLECharacteristic>>,
+    stats: Arc<Stats>,
+    lockout: Arc<Lockout>,
+    events: Arc<EventBus>,
+}
+
+impl LogStore {
+    pub fn new(
+        notify: Arc<NimbleMutex<BLECharacteristic>>,
+        stats: Arc<Stats>,
+        lockout: Arc<Lockout>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        LogStore {
+            entries: Mutex::new(Vec::new()),
+            next_seq: AtomicU32::new(1),
+            notify,
+            stats,
+            lockout,
+            events,
+        }
+    }
+
+    pub fn append(&self, client: &Client, status: LogEntryStatus) {
+        self.append_request(&client.clone().into(), status);
+    }
+
+    /// logs the outcome of an unlock request (with the user who made it); a successful request
+    /// made with a duress credential is logged as [`LogEntryStatus::Duress`]
+    pub fn append_request(&self, request: &UnlockRequest, status: LogEntryStatus) {
+        let status = match status {
+            LogEntryStatus::Successful if request.duress => LogEntryStatus::Duress,
+            x => x,
+        };
+        self.stats.record(&status);
+        self.lockout.record(&request.client, &status);
+        self.push(LogEntry {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            credential: request.client.credential(),
+            status,
+            time: SystemTime::now(),
+            source: request.client.source(),
+            user: request.user,
+            rssi: request.rssi,
+            second_user: request.second_user,
+        });
+    }
+
+    /// logs the cause of the last reset, once at boot
+    pub fn append_restart(&self, reset_reason: &'static str, supervisor_reason: Option<String>) {
+        self.push(LogEntry {
+            seq: self.next_seq.fetch_add(1, Ordering::Relaxed),
+            credential: CredentialId::None,
+            status: LogEntryStatus::Restarted {
+                reset_reason,
+                supervisor_reason,
+            },
+            time: SystemTime::now(),
+            source: LogSource::System,
+            user: None,
+            rssi: None,
+            second_user: None,
+        });
+    }
+
+    fn push(&self, entry: LogEntry) {
+        {
+            let mut logs = match self.entries.lock() {
+                Ok(x) => x,
+                Err(_) => {
+                    log::error!("[❌] Failed to lock logs mutex");
+                    return;
+                }
+            };
+            if logs.len() >= MAX_LOG_ENTRIES {
+                logs.remove(0);
+            }
+            logs.push(entry.clone());
+        }
+        self.notify.lock().set_value(&entry.encode());
+        self.events.publish(GateEvent::Log(entry));
+    }
+
+    /// the packed 15-byte records of all entries (oldest first)
+    pub fn encoded(&self) -> Vec<u8> {
+        match self.entries.lock() {
+            Ok(logs) => logs.iter().flat_map(|x| x.encode()).collect(),
+            Err(why) => {
+                log::error!("[❌] Failed to lock the mutex while reading logs: {why}");
+                Vec::new()
+            }
+        }
+    }
+
+    /// the sequence number of the latest entry (`0` if there is none)
+    pub fn latest_seq(&self) -> u32 {
+        self.next_seq.load(Ordering::Relaxed) - 1
+    }
+
+    /// the entries newer than `seq` (oldest first); sequence numbers start at 1 on every boot
+    pub fn since(&self, seq: u32) -> Vec<LogEntry> {
+        match self.entries.lock() {
+            Ok(logs) => logs.iter().filter(|x| x.seq > seq).cloned().collect(),
+            Err(why) => {
+                log::error!("[❌] Failed to lock the mutex while reading logs: {why}");
+                Vec::new()
+            }
+        }
+    }
+}
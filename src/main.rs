@@ -1,29 +1,101 @@
 use esp32_nimble::utilities::BleUuid;
-use esp32_nimble::{
-    BLEAddress, BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEError, NimbleProperties,
+use esp32_nimble::{BLEAdvertisementData, BLEDevice, BLEError, NimbleProperties};
+use esp_idf_svc::hal::gpio::{
+    Gpio16, Gpio17, Gpio19, Gpio21, Gpio22, Gpio23, Gpio33, Gpio4, Input, Output, Pin,
 };
-use esp_idf_svc::hal::gpio::{Output, Pin};
+use esp_idf_svc::hal::modem::Modem;
 use esp_idf_svc::hal::{gpio::PinDriver, peripherals::Peripherals};
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
 use esp_idf_svc::sys::{
     EspError, CONFIG_BT_NIMBLE_TASK_STACK_SIZE, CONFIG_NIMBLE_TASK_STACK_SIZE, NIMBLE_HS_STACK_SIZE,
 };
 use k256::ecdsa::VerifyingKey;
-use k256::ecdsa::{signature::Verifier, Signature};
 use log::LevelFilter;
-use rand::{thread_rng, Rng};
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::{
-    sync::Mutex,
-    time::{Duration, SystemTime},
-};
+use std::{sync::Mutex, time::Duration};
+
+use access::{AccessControl, OutputPolicy};
+use admin::AdminContext;
+use boot::BootError;
+use button::{ButtonSettings, LongPressAction};
+use buzzer::{Buzzer, BuzzerSettings};
+use challenge::{Challenge, Client, UnlockRequest};
+use command::{CommandContext, CommandMessage};
+use connections::Connections;
+use credentials::Credentials;
+use events::{EventBus, GateEvent};
+use factory_reset::ClaimWindow;
+use framing::Framing;
+use identity::Identity;
+use led::{LedKind, LedTimings, Pattern, StatusLed};
+use lockout::Lockout;
+use logs::{LogEntryStatus, LogSource, LogStore};
+use metadata::MetadataSource;
+use nfc::{NfcContext, NfcReader};
+use ota::OtaUpdater;
+use pn532::NfcInterface;
+use proximity::Proximity;
+use roles::{Operation, Roles};
+use security::SecurityPolicy;
+use stats::Stats;
+use supervisor::Watchdog;
+
+mod access;
+mod admin;
+mod advertising;
+mod alarm;
+mod boot;
+mod button;
+mod buzzer;
+mod challenge;
+mod command;
+mod connections;
+mod credentials;
+mod device_info;
+mod events;
+mod factory_reset;
+mod framing;
+#[cfg(feature = "ha-discovery")]
+mod ha_discovery;
+#[cfg(feature = "wifi-http")]
+mod http_api;
+mod identity;
+mod led;
+mod lockout;
+mod logs;
+mod metadata;
+#[cfg(feature = "wifi-mqtt")]
+mod mqtt;
+mod nfc;
+mod ota;
+mod pn532;
+mod proximity;
+#[cfg(any(feature = "wifi-mqtt", feature = "wifi-http"))]
+mod remote;
+mod roles;
+mod security;
+mod stats;
+mod supervisor;
+mod two_factor;
+#[cfg(feature = "webhooks")]
+mod webhooks;
+mod wiegand;
+#[cfg(feature = "wifi")]
+mod wifi;
+
+/// Version of the BLE challenge/response protocol spoken by the lock characteristic
+/// (2: all characteristics but the OTA data one are framed, see [`framing::Framing`];
+/// 3: the identifier of a log entry is the credential used, not always a BLE address;
+/// 4: reading the logs & metadata needs a session, see [`roles::Roles`];
+/// 5: the advertised status is keyed by the status key, see [`advertising`];
+/// 6: the per-function characteristics no longer claim to be the legacy v0 protocol: framing (2)
+/// and the sessions (4) broke unframed, sessionless v0 clients, they have to move to the command
+/// protocol, see [`command`])
+const PROTOCOL_VERSION: u32 = 6;
 
-#[derive(Debug, Clone)]
-struct BLEChallenge {
-    time: SystemTime,
-    challenge_bytes: [u8; 64],
-    address: BLEAddress,
-}
 #[derive(Debug, Deserialize)]
 struct DeviceConfig {
     pub ble_name: String,
@@ -31,79 +103,271 @@ struct DeviceConfig {
     pub lock_char_uuid: String,
     pub meta_char_uuid: String,
     pub logs_char_uuid: String,
+    pub admin_char_uuid: String,
+    pub ota_ctrl_char_uuid: String,
+    pub ota_data_char_uuid: String,
+    pub proximity_char_uuid: String,
+    pub command_char_uuid: String,
+    pub response_char_uuid: String,
     pub open_time_in_ms: u64,
+    pub adv_rotation_in_s: u64,
+    pub admin_requires_bond: bool,
+    pub lock_requires_encryption: bool,
+    pub status_led: LedKind,
+    pub status_led_on_time_in_ms: u64,
+    pub status_led_gap_in_ms: u64,
+    pub status_led_brightness: u8,
+    pub buzzer: bool,
+    pub buzzer_quiet_from_hour: u8,
+    pub buzzer_quiet_until_hour: u8,
+    pub buzzer_door_open_alert_in_s: u64,
+    pub buzzer_passage_alert_in_s: u64,
+    pub exit_button: bool,
+    pub exit_button_long_press: LongPressAction,
+    pub exit_button_long_press_in_ms: u64,
+    pub key_switch: bool,
+    pub door_contact: bool,
+    pub wiegand: bool,
+    pub nfc: NfcInterface,
+    pub nfc_desfire_aid: u32,
+    pub two_factor: bool,
+    pub dual_auth_window_in_s: u64,
+    pub duress_alarm: bool,
+    pub duress_alarm_time_in_s: u64,
 }
 
-#[derive(Debug, Serialize, Clone)]
-struct MetaDataStruct {
-    pub power_on_hours: f64,
-    pub trigger_pin: i32,
-    pub status_led_pin: i32,
-}
+impl DeviceConfig {
+    fn led_timings(&self) -> LedTimings {
+        LedTimings {
+            on: Duration::from_millis(self.status_led_on_time_in_ms),
+            gap: Duration::from_millis(self.status_led_gap_in_ms),
+            brightness: self.status_led_brightness,
+        }
+    }
 
-#[derive(Debug, Serialize, Clone)]
-struct LogEntry {
-    // Total bytes: 15
-    pub time: SystemTime,       // 8byte
-    pub mac: String,            // 6byte
-    pub status: LogEntryStatus, // 1byte
-}
-impl LogEntry {
-    pub fn encode(&self) -> [u8; 15] {
-        let mut res: [u8; 15] = [0x0; 15];
-        res[..8].clone_from_slice(&self.time.elapsed().unwrap().as_secs().to_be_bytes());
-        let mac: Vec<u8> = self
-            .mac
-            .split(":")
-            .map(|x| u8::from_str_radix(x, 16).unwrap())
-            .collect();
-        res[8..14].clone_from_slice(&mac);
-        res[14] = match self.status {
-            LogEntryStatus::Failed(x) => x as u8,
-            LogEntryStatus::Successful => 0,
-        };
-        return res;
+    fn buzzer_settings(&self) -> BuzzerSettings {
+        BuzzerSettings {
+            quiet_from: self.buzzer_quiet_from_hour,
+            quiet_until: self.buzzer_quiet_until_hour,
+            door_open_alert: Duration::from_secs(self.buzzer_door_open_alert_in_s),
+            passage_alert: Duration::from_secs(self.buzzer_passage_alert_in_s),
+        }
+    }
+
+    fn button_settings(&self) -> ButtonSettings {
+        ButtonSettings {
+            long_press: Duration::from_millis(self.exit_button_long_press_in_ms),
+            long_press_action: self.exit_button_long_press,
+        }
     }
 }
 
-#[derive(Debug, Serialize, Clone)]
-enum LogEntryStatus {
-    Successful,
-    Failed(i32),
+/// The optional in- & outputs enabled in the device config
+struct OptionalHardware {
+    buzzer: Option<Buzzer>,
+    exit_button: Option<PinDriver<'static, Gpio19, Input>>,
+    key_switch: Option<PinDriver<'static, Gpio21, Input>>,
+    door_contact: Option<PinDriver<'static, Gpio4, Input>>,
+    wiegand: Option<wiegand::Reader<Gpio22, Gpio23>>,
+    nfc: Option<Box<dyn NfcReader>>,
+    alarm: Option<PinDriver<'static, Gpio33, Output>>,
 }
 
 fn main() {
-    let power_on = std::time::SystemTime::now();
-    let logs: Arc<Mutex<Vec<LogEntry>>> = Arc::new(Mutex::new(Vec::new()));
+    // It is necessary to call this function once. Otherwise some patches to the runtime
+    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
+    esp_idf_svc::sys::link_patches();
+
+    // Bind the log crate to the ESP Logging facilities
+    esp_idf_svc::log::EspLogger::initialize_default();
+    if let Err(why) = esp_idf_svc::log::set_target_level("*", LevelFilter::Trace) {
+        log::error!("[❌] Failed to set the log level: {:?}", why);
+    }
 
-    let dp: Peripherals = Peripherals::take().unwrap();
+    let dp: Peripherals = match Peripherals::take() {
+        Ok(x) => x,
+        Err(why) => boot::recovery_mode::<Gpio16>(BootError::Hardware(why), None, None),
+    };
+
+    // change those PINS in order to modify the pinout
+    let trigger_pin: Gpio16 = dp.pins.gpio16;
+    let error_pin: Gpio17 = dp.pins.gpio17;
+    let pins = (trigger_pin.pin(), error_pin.pin());
+
+    // the relay is held low before anything else can fail
+    let mut led_pin = match PinDriver::output(trigger_pin) {
+        Ok(x) => x,
+        Err(why) => boot::recovery_mode::<Gpio16>(BootError::Hardware(why), None, None),
+    };
+    if let Err(why) = led_pin.set_low() {
+        boot::recovery_mode(BootError::Hardware(why), Some(&mut led_pin), None);
+    }
 
     // config
     let config: DeviceConfig =
-        serde_json::from_str(include_str!("../config_dir/device_config.json")).unwrap();
-    let ble_name: &str = &config.ble_name;
-    let service_uid: BleUuid = BleUuid::from_uuid128_string(&config.service_uuid).unwrap();
-    let lock_char_uid: BleUuid = BleUuid::from_uuid128_string(&config.lock_char_uuid).unwrap();
-    let open_time: Duration = Duration::from_millis(config.open_time_in_ms);
-    let meta_char_uid: BleUuid = BleUuid::from_uuid128_string(&config.meta_char_uuid).unwrap();
-    let logs_char_uid: BleUuid = BleUuid::from_uuid128_string(&config.logs_char_uuid).unwrap();
+        match serde_json::from_str(include_str!("../config_dir/device_config.json")) {
+            Ok(x) => x,
+            Err(why) => {
+                // without a config the status LED is treated as a plain one
+                let status_led = led::driver(
+                    LedKind::Gpio,
+                    error_pin,
+                    dp.ledc.timer0,
+                    dp.ledc.channel0,
+                    dp.rmt.channel0,
+                )
+                .ok()
+                .and_then(|x| StatusLed::spawn(x, LedTimings::default()).ok());
+                let why = BootError::Config(format!("invalid device config: {}", why));
+                boot::recovery_mode(why, Some(&mut led_pin), status_led.as_ref())
+            }
+        };
+    let status_led = led::driver(
+        config.status_led,
+        error_pin,
+        dp.ledc.timer0,
+        dp.ledc.channel0,
+        dp.rmt.channel0,
+    )
+    .map_err(BootError::Hardware)
+    .and_then(|x| StatusLed::spawn(x, config.led_timings()).map_err(BootError::System));
+    let status_led = match status_led {
+        Ok(x) => x,
+        Err(why) => boot::recovery_mode(why, Some(&mut led_pin), None),
+    };
 
-    // change those PINS in order to modify the pinout
-    let trigger_pin: esp_idf_svc::hal::gpio::Gpio16 = dp.pins.gpio16;
-    let error_pin: esp_idf_svc::hal::gpio::Gpio17 = dp.pins.gpio17;
+    // the buzzer is optional, the gate works without it
+    let buzzer = if config.buzzer {
+        Buzzer::new(dp.pins.gpio18, dp.ledc.timer1, dp.ledc.channel1)
+            .map_err(|why| log::error!("[❌] Failed to set up the buzzer: {:?}", why))
+            .ok()
+    } else {
+        None
+    };
+
+    // the local inputs are active low
+    let exit_button = config
+        .exit_button
+        .then(|| button::input(dp.pins.gpio19))
+        .transpose();
+    let key_switch = config
+        .key_switch
+        .then(|| button::input(dp.pins.gpio21))
+        .transpose();
+    let door_contact = config
+        .door_contact
+        .then(|| button::input(dp.pins.gpio4))
+        .transpose();
+    let wiegand = config
+        .wiegand
+        .then(|| {
+            wiegand::Reader::new(
+                button::input(dp.pins.gpio22)?,
+                button::input(dp.pins.gpio23)?,
+            )
+        })
+        .transpose();
 
-    let meta_data = MetaDataStruct {
-        power_on_hours: 0.,
-        trigger_pin: trigger_pin.pin(),
-        status_led_pin: error_pin.pin(),
+    // the NFC reader is optional as well
+    let nfc = match config.nfc {
+        NfcInterface::None => Ok(None),
+        NfcInterface::I2c => pn532::i2c(dp.i2c0, dp.pins.gpio25, dp.pins.gpio26)
+            .map(|x| Some(Box::new(x) as Box<dyn NfcReader>)),
+        NfcInterface::Spi => pn532::spi(
+            dp.spi2,
+            dp.pins.gpio14,
+            dp.pins.gpio13,
+            dp.pins.gpio27,
+            dp.pins.gpio32,
+        )
+        .map(|x| Some(Box::new(x) as Box<dyn NfcReader>)),
     };
-    // It is necessary to call this function once. Otherwise some patches to the runtime
-    // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
-    esp_idf_svc::sys::link_patches();
+    let nfc = nfc.unwrap_or_else(|why| {
+        log::error!("[❌] Failed to set up the NFC reader: {}", why);
+        None
+    });
 
-    // Bind the log crate to the ESP Logging facilities
-    esp_idf_svc::log::EspLogger::initialize_default();
-    esp_idf_svc::log::set_target_level("*", LevelFilter::Trace).unwrap();
+    // the silent alarm output (active high)
+    let alarm = config
+        .duress_alarm
+        .then(|| {
+            let mut pin = PinDriver::output(dp.pins.gpio33)?;
+            pin.set_low()?;
+            Ok::<_, EspError>(pin)
+        })
+        .transpose();
+
+    let inputs = exit_button.and_then(|x| Ok((x, key_switch?, door_contact?, wiegand?, alarm?)));
+    let hardware = match inputs {
+        Ok((exit_button, key_switch, door_contact, wiegand, alarm)) => OptionalHardware {
+            buzzer,
+            exit_button,
+            key_switch,
+            door_contact,
+            wiegand,
+            nfc,
+            alarm,
+        },
+        Err(why) => boot::recovery_mode(
+            BootError::Hardware(why),
+            Some(&mut led_pin),
+            Some(&status_led),
+        ),
+    };
+
+    match run(
+        config,
+        dp.modem,
+        pins,
+        &mut led_pin,
+        status_led.clone(),
+        hardware,
+    ) {
+        Ok(x) => match x {},
+        Err(why) => boot::recovery_mode(why, Some(&mut led_pin), Some(&status_led)),
+    }
+}
+
+/// Brings up the device; every failure is returned instead of panicking so that `main` can keep
+/// the relay off and enter the recovery mode.
+fn run(
+    config: DeviceConfig,
+    #[cfg_attr(not(feature = "wifi"), allow(unused_variables))] modem: Modem,
+    (trigger_pin, error_pin): (i32, i32),
+    led_pin: &mut PinDriver<'static, Gpio16, Output>,
+    status_led: StatusLed,
+    hardware: OptionalHardware,
+) -> Result<Infallible, BootError> {
+    let power_on = std::time::Instant::now();
+    let events: Arc<EventBus> = Arc::new(EventBus::default());
+
+    let nvs_partition = EspDefaultNvsPartition::take().map_err(BootError::Storage)?;
+    if let Some(button) = hardware.exit_button.as_ref() {
+        if factory_reset::held_at_boot(button) {
+            match factory_reset::wipe(nvs_partition.clone()).map_err(BootError::Storage)? {}
+        }
+    }
+
+    // config
+    let ble_name: &str = &config.ble_name;
+    let service_uid: BleUuid = boot::uuid("service_uuid", &config.service_uuid)?;
+    let lock_char_uid: BleUuid = boot::uuid("lock_char_uuid", &config.lock_char_uuid)?;
+    let open_time: Duration = Duration::from_millis(config.open_time_in_ms);
+    let adv_rotation: Option<Duration> =
+        Some(Duration::from_secs(config.adv_rotation_in_s)).filter(|x| !x.is_zero());
+    let security_policy = SecurityPolicy {
+        admin_requires_bond: config.admin_requires_bond,
+        lock_requires_encryption: config.lock_requires_encryption,
+    };
+    let meta_char_uid: BleUuid = boot::uuid("meta_char_uuid", &config.meta_char_uuid)?;
+    let logs_char_uid: BleUuid = boot::uuid("logs_char_uuid", &config.logs_char_uuid)?;
+    let admin_char_uid: BleUuid = boot::uuid("admin_char_uuid", &config.admin_char_uuid)?;
+    let ota_ctrl_char_uid: BleUuid = boot::uuid("ota_ctrl_char_uuid", &config.ota_ctrl_char_uuid)?;
+    let ota_data_char_uid: BleUuid = boot::uuid("ota_data_char_uuid", &config.ota_data_char_uuid)?;
+    let proximity_char_uid: BleUuid =
+        boot::uuid("proximity_char_uuid", &config.proximity_char_uuid)?;
+    let command_char_uid: BleUuid = boot::uuid("command_char_uuid", &config.command_char_uuid)?;
+    let response_char_uid: BleUuid = boot::uuid("response_char_uuid", &config.response_char_uuid)?;
 
     log::info!(
         "[🐛] Stack Size: {}, {}, {}",
@@ -113,17 +377,91 @@ fn main() {
     );
 
     // init config
-
-    // Change the folowing gpio pins to your desire!
-    let mut led_pin = PinDriver::output(trigger_pin).unwrap();
-    let error_pin = Arc::new(Mutex::new(PinDriver::output(error_pin).unwrap()));
+    let stats = Arc::new(Stats::load(nvs_partition.clone()).map_err(BootError::Storage)?);
+    stats::spawn_flush_task(stats.clone());
+    let proximity = Arc::new(Proximity::load(nvs_partition.clone()).map_err(BootError::Storage)?);
+    let lockout = Arc::new(Lockout::default());
+    let connections = Arc::new(Connections::default());
+    let device_id = device_info::device_id().map_err(BootError::Hardware)?;
+    let identity = Arc::new(
+        Identity::load(nvs_partition.clone(), device_id.clone()).map_err(BootError::Storage)?,
+    );
+    log::info!("[🐕] Reset reason: {}", device_info::reset_reason());
+    let supervisor_reason =
+        supervisor::take_last_reason(nvs_partition.clone()).unwrap_or_else(|why| {
+            log::error!(
+                "[❌] Failed to read the supervisor's reboot reason: {:?}",
+                why
+            );
+            None
+        });
+    if let Some(reason) = supervisor_reason.as_ref() {
+        log::warn!("[🐕] The supervisor rebooted the device: {}", reason);
+    }
+    let verifying_key = VerifyingKey::from_sec1_bytes(include_bytes!("../config_dir/public.bin"))
+        .map_err(|why| BootError::Config(format!("invalid public key: {}", why)))?;
+    // after a factory reset the built-in key has lost the ownership until it claims the device
+    let unclaimed = factory_reset::unclaimed(nvs_partition.clone()).map_err(BootError::Storage)?;
+    let exit_button_held = Arc::new(AtomicBool::new(false));
+    let credentials = Arc::new(
+        Credentials::load(nvs_partition.clone(), (!unclaimed).then_some(verifying_key))
+            .map_err(BootError::Storage)?,
+    );
+    let roles = Arc::new(Roles::new(credentials.clone()));
+    let access = Arc::new(AccessControl::new(
+        OutputPolicy {
+            two_factor: config.two_factor,
+            dual_authorization: Duration::from_secs(config.dual_auth_window_in_s),
+        },
+        roles.clone(),
+    ));
+    let metadata = Arc::new(MetadataSource::new(
+        (trigger_pin, error_pin),
+        identity.clone(),
+        supervisor_reason.clone(),
+        power_on,
+        stats.clone(),
+        connections.clone(),
+        access.clone(),
+    ));
 
     let mut ble_device = BLEDevice::take();
-    let challenge: Arc<Mutex<Vec<BLEChallenge>>> = Arc::new(Mutex::new(Vec::new()));
+    let passkey = if security_policy.enabled() {
+        Some(security::enable(&mut ble_device))
+    } else {
+        None
+    };
+    let challenge: Arc<Mutex<Vec<Challenge>>> = Arc::new(Mutex::new(Vec::new()));
     let server = ble_device.get_server();
     let challenge_disconnect = challenge.clone();
-    server.on_connect(|_server, desc| {
+    let proximity_disconnect = proximity.clone();
+    let roles_disconnect = roles.clone();
+    let connect_status_led = status_led.clone();
+    let connect_exit_button_held = exit_button_held.clone();
+    let connect_connections = connections.clone();
+    let disconnect_connections = connections.clone();
+    server.on_connect(move |server, desc| {
         log::info!("[🔌] Device '{}' connected", desc.address());
+        if !connect_connections.connected(desc) {
+            if let Err(why) = server.disconnect(desc.conn_handle()) {
+                log::error!("[❌] ({}) Failed to disconnect: {:?}", desc.address(), why);
+            }
+            return;
+        }
+        // keep the remaining slots reachable
+        connections::ensure_advertising();
+        if let Some(passkey) = passkey.filter(|_| !desc.bonded()) {
+            // show the passkey for the pairing, only to somebody inside holding the exit button:
+            // anybody seeing the LED could pair otherwise
+            if connect_exit_button_held.load(Ordering::Relaxed) {
+                connect_status_led.show(Pattern::Passkey(passkey));
+            } else {
+                log::info!(
+                    "[🔑] ({}) Hold the exit button while connecting to see the passkey",
+                    desc.address()
+                );
+            }
+        }
     });
     server.on_disconnect(move |desc, reason| {
         log::info!(
@@ -131,26 +469,13 @@ fn main() {
             desc.address(),
             reason
         );
-        {
-            let mut challenges = match challenge_disconnect.lock() {
-                Ok(x) => x,
-                Err(why) => {
-                    log::error!(
-                        "[❌] Mutex Lock Error while trying to clean challenges: {:?}",
-                        why
-                    );
-
-                    return;
-                }
-            };
-            *challenges = challenges
-                .clone()
-                .into_iter()
-                .filter(|x| x.address != desc.address())
-                .collect();
-            log::info!("[♻️] Cleaned up challenges: {} remaining", challenges.len());
-        }
+        challenge::clean_up_client(&challenge_disconnect, &Client::ble(desc));
+        proximity_disconnect.disconnected(desc.conn_handle());
+        roles_disconnect.close_session(desc.conn_handle());
+        disconnect_connections.disconnected(desc, &reason);
+        connections::ensure_advertising();
     });
+    connections::spawn_idle_task(connections.clone(), roles.clone());
 
     let service = server.create_service(service_uid);
 
@@ -159,28 +484,30 @@ fn main() {
         logs_char_uid,
         NimbleProperties::READ | NimbleProperties::BROADCAST | NimbleProperties::NOTIFY,
     );
+    let logs = Arc::new(LogStore::new(
+        logs_char.clone(),
+        stats.clone(),
+        lockout.clone(),
+        events.clone(),
+    ));
+    logs.append_restart(device_info::reset_reason(), supervisor_reason);
     let logs_char_logs = logs.clone();
+    let logs_char_roles = roles.clone();
+    let logs_char_framing = Framing::new(connections.clone());
     logs_char.lock().on_read(move |attr, ble_con_desc| {
-        let logs = logs_char_logs.clone();
-        let mut logs = match logs.lock() {
-            Ok(x) => x,
-            Err(why) => {
-                log::error!("[❌] Failed to lock the mutex while reading logs: {why}");
-                attr.set_value(&[]);
-                return;
-            }
-        };
-        // ensure only 32 elements are present
-        logs.reverse();
-        logs.truncate(32);
-        logs.reverse();
-
-        let logs: Vec<u8> = logs.iter().flat_map(|x| x.encode()).collect();
-        attr.set_value(&logs);
-        log::info!(
-            "[✏️] ({}) requested the logs",
-            ble_con_desc.address().to_string()
-        );
+        attr.set_value(&logs_char_framing.read(ble_con_desc, || {
+            log::info!(
+                "[✏️] ({}) requested the logs",
+                ble_con_desc.address().to_string()
+            );
+            let client = Client::ble(ble_con_desc);
+            logs_char_roles
+                .dispatch(&client, None, Operation::ReadLogs, |_| {
+                    Ok(logs_char_logs.encoded())
+                })
+                // a single byte can't be mistaken for log entries
+                .unwrap_or_else(|code| vec![code as u8])
+        }));
     });
 
     // metadata characteristic
@@ -188,275 +515,453 @@ fn main() {
         .lock()
         .create_characteristic(meta_char_uid, NimbleProperties::READ);
 
-    meta_char.lock().on_read(move |attr, _ble_con_desc| {
-        let mut meta = (&meta_data).clone();
-        meta.power_on_hours = match power_on.elapsed() {
-            Ok(e) => e.as_secs_f64() / (60. * 60.),
-            Err(why) => {
-                log::error!("[❌] Failed to read power on hours: {}", why.to_string());
-                -1.
-            }
-        };
-        let res = match serde_json::to_string(&meta) {
-            Ok(x) => x,
-            Err(why) => {
-                log::error!("[❌] Failed to prepare json: {}", why.to_string());
-                "".to_owned()
-            }
-        };
-        attr.set_value(res.as_bytes());
-        log::info!(
-            "[ℹ️] ({}) requested the metadata",
-            _ble_con_desc.address().to_string()
-        )
+    let meta_char_metadata = metadata.clone();
+    let meta_char_roles = roles.clone();
+    let meta_char_framing = Framing::new(connections.clone());
+    meta_char.lock().on_read(move |attr, ble_con_desc| {
+        attr.set_value(&meta_char_framing.read(ble_con_desc, || {
+            log::info!(
+                "[ℹ️] ({}) requested the metadata",
+                ble_con_desc.address().to_string()
+            );
+            let client = Client::ble(ble_con_desc);
+            meta_char_roles
+                .dispatch(&client, None, Operation::ReadMetadata, |_| {
+                    Ok(meta_char_metadata.to_json().into_bytes())
+                })
+                .unwrap_or_else(|code| {
+                    serde_json::json!({ "error": code })
+                        .to_string()
+                        .into_bytes()
+                })
+        }));
     });
 
     let lock_char = service.lock().create_characteristic(
         lock_char_uid,
-        NimbleProperties::READ | NimbleProperties::WRITE,
+        security_policy.lock_properties(NimbleProperties::READ | NimbleProperties::WRITE),
     );
-    let verifying_key = VerifyingKey::from_sec1_bytes(include_bytes!("../config_dir/public.bin"))
-        .expect("[❌] Failed to parse Sec1-Bytes public key");
-    let verifying_key = Arc::new(verifying_key);
     let read_challenge = challenge.clone();
-    let (tx, rx) = std::sync::mpsc::channel();
+    let (tx, rx) = std::sync::mpsc::channel::<UnlockRequest>();
+    let lock_char_tx = tx.clone();
     let lock_char_logs = logs.clone();
-    let lock_char_power_on = power_on.clone();
-    let lock_char_logs_char = logs_char.clone();
+    let lock_char_challenge = challenge.clone();
+    let lock_char_credentials = credentials.clone();
+    let lock_char_framing = Framing::new(connections.clone());
+    let lock_char_read_framing = lock_char_framing.clone();
     lock_char
         .lock()
         .on_read(move |attr, _ble_con_desc| {
-            let mut challenge_bytes: [u8; 64] = [Default::default(); 64];
-            thread_rng().fill(&mut challenge_bytes);
-            log::info!(
-                "[🎲] ({}) Sending challenge bytes '{}'",
-                _ble_con_desc.address(),
-                bytes_to_hex_string(&challenge_bytes)
-            );
-            {
-                // log::info!("Lock MUTEX");
-                let mut challengens = match read_challenge.lock() {
-                    Ok(x) => x,
-                    Err(why) => {
-                        log::error!(
-                            "[❌] ({}) Mutex lock error: {:?}",
-                            _ble_con_desc.address(),
-                            why
-                        );
-                        return;
-                    }
-                };
-                challengens.push(BLEChallenge {
-                    time: SystemTime::now(),
-                    challenge_bytes: challenge_bytes.clone(),
-                    address: _ble_con_desc.address().clone(),
-                })
-            }
-            attr.set_value(&challenge_bytes);
+            attr.set_value(&lock_char_read_framing.read(_ble_con_desc, || {
+                challenge::issue_challenge(&read_challenge, Client::ble(_ble_con_desc))
+                    .map(|x| x.to_vec())
+                    .unwrap_or_default()
+            }));
         })
         .on_write(move |args| {
-            let logs = lock_char_logs.clone();
-            let log_char = lock_char_logs_char.clone();
-            let power_on = lock_char_power_on;
-            let data = args.recv_data();
+            let data = match lock_char_framing.receive(args) {
+                Some(x) => x,
+                None => return,
+            };
+            let client = Client::ble(args.desc());
             log::info!(
                 "[👀] ({}) Got challenge response '{}'",
-                args.desc().address(),
+                client,
                 bytes_to_hex_string(&data)
             );
-            if data.len() < 64 {
-                log::error!(
-                    "[❌] ({}) Got only {} bytes, expected 65",
+            let result = challenge::verify_unlock(
+                &lock_char_challenge,
+                &lock_char_credentials,
+                &data,
+                &client,
+            )
+            .and_then(|request| {
+                lock_char_tx.send(request).map_err(|why| {
+                    log::error!("[❌] Failed to tx: {:?}", why);
+                    0x08
+                })
+            });
+            if let Err(code) = result {
+                args.reject_with_error_code(code as u8);
+                lock_char_logs.append(&client, LogEntryStatus::Failed(code));
+            }
+        });
+
+    // admin characteristic
+    let admin_char = service.lock().create_characteristic(
+        admin_char_uid,
+        security_policy.admin_properties(NimbleProperties::READ | NimbleProperties::WRITE),
+    );
+    let admin_ctx = Arc::new(AdminContext {
+        challenges: challenge.clone(),
+        credentials: credentials.clone(),
+        roles: roles.clone(),
+        stats: stats.clone(),
+        proximity: proximity.clone(),
+        nvs_partition: nvs_partition.clone(),
+    });
+    let admin_char_ctx = admin_ctx.clone();
+    let admin_char_challenge = challenge.clone();
+    let admin_char_framing = Framing::new(connections.clone());
+    let admin_char_read_framing = admin_char_framing.clone();
+    admin_char
+        .lock()
+        .on_read(move |attr, ble_con_desc| {
+            attr.set_value(&admin_char_read_framing.read(ble_con_desc, || {
+                challenge::issue_challenge(&admin_char_challenge, Client::ble(ble_con_desc))
+                    .map(|x| x.to_vec())
+                    .unwrap_or_default()
+            }));
+        })
+        .on_write(move |args| {
+            let data = match admin_char_framing.receive(args) {
+                Some(x) => x,
+                None => return,
+            };
+            let client = Client::ble(args.desc());
+            if let Err(code) = admin::handle_admin_write(&admin_char_ctx, &data, &client) {
+                args.reject_with_error_code(code as u8);
+            }
+        });
+
+    // command protocol characteristics (the ones above are the framed per-function ones)
+    let command_char = service
+        .lock()
+        .create_characteristic(command_char_uid, NimbleProperties::WRITE);
+    let response_char = service
+        .lock()
+        .create_characteristic(response_char_uid, NimbleProperties::NOTIFY);
+    let command_tx = command::spawn(
+        CommandContext {
+            challenges: challenge.clone(),
+            credentials: credentials.clone(),
+            unlock: tx.clone(),
+            logs: logs.clone(),
+            metadata: metadata.clone(),
+            admin: admin_ctx.clone(),
+            roles: roles.clone(),
+            identity: identity.clone(),
+            security: security_policy,
+            claim_window: ClaimWindow::new(power_on, unclaimed, exit_button_held.clone()),
+        },
+        response_char,
+    )
+    .map_err(BootError::System)?;
+    let command_char_framing = Framing::new(connections.clone());
+    command_char.lock().on_write(move |args| {
+        let data = match command_char_framing.receive(args) {
+            Some(x) => x,
+            None => return,
+        };
+        let message = CommandMessage {
+            address: args.desc().address(),
+            conn_handle: args.desc().conn_handle(),
+            mtu: args.desc().mtu(),
+            link: args.desc().into(),
+            data,
+        };
+        if let Err(why) = command_tx.send(message) {
+            log::error!("[❌] Failed to tx: {:?}", why);
+            args.reject_with_error_code(0x08);
+        }
+    });
+
+    // hands-free (proximity) characteristic
+    let proximity_char = service.lock().create_characteristic(
+        proximity_char_uid,
+        security_policy.lock_properties(NimbleProperties::READ | NimbleProperties::WRITE),
+    );
+    let proximity_char_read_challenge = challenge.clone();
+    let proximity_char_challenge = challenge.clone();
+    let proximity_char_credentials = credentials.clone();
+    let proximity_char_logs = logs.clone();
+    let proximity_char_proximity = proximity.clone();
+    let proximity_char_connections = connections.clone();
+    let proximity_char_framing = Framing::new(connections.clone());
+    let proximity_char_read_framing = proximity_char_framing.clone();
+    proximity_char
+        .lock()
+        .on_read(move |attr, ble_con_desc| {
+            attr.set_value(&proximity_char_read_framing.read(ble_con_desc, || {
+                challenge::issue_challenge(
+                    &proximity_char_read_challenge,
+                    Client::ble(ble_con_desc),
+                )
+                .map(|x| x.to_vec())
+                .unwrap_or_default()
+            }));
+        })
+        .on_write(move |args| {
+            let data = match proximity_char_framing.receive(args) {
+                Some(x) => x,
+                None => return,
+            };
+            let client = Client::ble(args.desc());
+            let result = challenge::verify_response(
+                &proximity_char_challenge,
+                &proximity_char_credentials.keys(),
+                &data,
+                0,
+                &client,
+            )
+            .and_then(|signer| {
+                proximity_char_proximity.arm(
                     args.desc().address(),
-                    data.len()
-                );
-                args.reject_with_error_code(0x01);
-                append_logs(
-                    args.desc().address().to_string(),
-                    LogEntryStatus::Failed(0x01),
-                    power_on,
-                    logs,
-                    log_char,
-                );
-                return;
+                    args.desc().conn_handle(),
+                    &signer,
+                )
+            })
+            .map(|_| {
+                // wait for the phone to come close
+                proximity_char_connections
+                    .keep_alive(args.desc().address(), challenge::CHALLENGE_TIMEOUT)
+            });
+            if let Err(code) = result {
+                args.reject_with_error_code(code as u8);
+                proximity_char_logs.append(&client, LogEntryStatus::Failed(code));
             }
-            let challenge_data = &data[..64];
-
-            // check if challenge exists & is in time
-            {
-                let mut challenges = match challenge.lock() {
-                    Ok(x) => x,
-                    Err(why) => {
-                        log::error!(
-                            "[❌] ({}) Mutex lock error: {:?}",
-                            args.desc().address(),
-                            why
-                        );
-                        args.reject_with_error_code(0x05);
-                        append_logs(
-                            args.desc().address().to_string(),
-                            LogEntryStatus::Failed(0x05),
-                            power_on,
-                            logs,
-                            log_char,
-                        );
-                        return;
-                    }
-                };
-                let challenge_result = match challenges.iter().find(|x| {
-                    x.address == args.desc().address() && x.challenge_bytes == challenge_data
-                }) {
-                    Some(x) => x,
-                    None => {
-                        log::error!(
-                            "[⛔] ({}) Opening-Request denied: couldn't find challenge: '{}'",
-                            args.desc().address(),
-                            bytes_to_hex_string(challenge_data)
-                        );
-                        args.reject_with_error_code(0x06);
-                        append_logs(
-                            args.desc().address().to_string(),
-                            LogEntryStatus::Failed(0x06),
-                            power_on,
-                            logs,
-                            log_char,
-                        );
-                        return;
-                    }
+        });
+    proximity::spawn_monitor(proximity.clone(), tx.clone());
+
+    // firmware update characteristics
+    let release_key =
+        VerifyingKey::from_sec1_bytes(include_bytes!("../config_dir/release_public.bin"))
+            .map_err(|why| BootError::Config(format!("invalid release key: {}", why)))?;
+    let ota_updater = Arc::new(OtaUpdater::new(release_key));
+    let ota_ctrl_char = service.lock().create_characteristic(
+        ota_ctrl_char_uid,
+        security_policy.admin_properties(NimbleProperties::READ | NimbleProperties::WRITE),
+    );
+    let ota_data_char = service.lock().create_characteristic(
+        ota_data_char_uid,
+        security_policy.admin_properties(NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP),
+    );
+    let ota_ctrl_updater = ota_updater.clone();
+    let ota_ctrl_framing = Framing::new(connections.clone());
+    let ota_ctrl_read_framing = ota_ctrl_framing.clone();
+    ota_ctrl_char.lock().on_read(move |attr, _ble_con_desc| {
+        attr.set_value(
+            &ota_ctrl_read_framing.read(_ble_con_desc, || ota_ctrl_updater.status().to_vec()),
+        );
+    });
+    let ota_ctrl_updater = ota_updater.clone();
+    let ota_ctrl_roles = roles.clone();
+    ota_ctrl_char.lock().on_write(move |args| {
+        let data = match ota_ctrl_framing.receive(args) {
+            Some(x) => x,
+            None => return,
+        };
+        let client = Client::ble(args.desc());
+        let result = ota_ctrl_roles.dispatch(&client, None, Operation::OtaUpdate, |_| {
+            ota_ctrl_updater.handle_control(&data)
+        });
+        if let Err(code) = result {
+            log::error!(
+                "[❌] ({}) Firmware update command failed: {:#04x}",
+                args.desc().address(),
+                code
+            );
+            args.reject_with_error_code(code as u8);
+        }
+    });
+    let ota_data_updater = ota_updater.clone();
+    let ota_data_connections = connections.clone();
+    let ota_data_roles = roles.clone();
+    ota_data_char.lock().on_write(move |args| {
+        ota_data_connections.touch(args.desc().address());
+        let client = Client::ble(args.desc());
+        let result = ota_data_roles.dispatch(&client, None, Operation::OtaUpdate, |_| {
+            ota_data_updater.handle_data(args.recv_data())
+        });
+        if let Err(code) = result {
+            args.reject_with_error_code(code as u8);
+        }
+    });
+
+    // subscribed before the door contact publishes its initial state
+    if let Some(buzzer) = hardware.buzzer {
+        if let Err(why) = buzzer::spawn(buzzer, config.buzzer_settings(), events.subscribe()) {
+            log::error!("[❌] Failed to start the buzzer: {:?}", why);
+        }
+    }
+    if let Some(pin) = hardware.alarm {
+        let duration = Duration::from_secs(config.duress_alarm_time_in_s);
+        alarm::spawn(pin, duration, events.subscribe()).map_err(BootError::System)?;
+    }
+    let ble_unlock_disabled = Arc::new(AtomicBool::new(false));
+    if hardware.exit_button.is_some()
+        || hardware.key_switch.is_some()
+        || hardware.door_contact.is_some()
+    {
+        let inputs = button::Inputs {
+            button: hardware.exit_button,
+            key_switch: hardware.key_switch,
+            door_contact: hardware.door_contact,
+        };
+        button::spawn(
+            inputs,
+            config.button_settings(),
+            tx.clone(),
+            ble_unlock_disabled.clone(),
+            exit_button_held.clone(),
+            stats.clone(),
+            events.clone(),
+        )
+        .map_err(BootError::System)?;
+    }
+    if let Some(reader) = hardware.wiegand {
+        wiegand::spawn(reader, credentials.clone(), tx.clone(), logs.clone())
+            .map_err(BootError::System)?;
+    }
+    if let Some(reader) = hardware.nfc {
+        let ctx = NfcContext {
+            credentials: credentials.clone(),
+            challenges: challenge.clone(),
+            unlock: tx.clone(),
+            logs: logs.clone(),
+            desfire_aid: config.nfc_desfire_aid,
+        };
+        nfc::spawn(reader, ctx).map_err(BootError::System)?;
+    }
+    led_pin.set_low().map_err(BootError::Hardware)?;
+    // everything fallible is set up before BLE comes up and the image is confirmed: a freshly
+    // updated image failing any of it (or BLE itself) is rolled back by the recovery mode
+    if adv_rotation.is_some() {
+        advertising::enable_privacy(&mut ble_device, &identity.irk).map_err(BootError::Ble)?;
+    }
+    setup_ble(&mut ble_device, ble_name, service_uid).map_err(BootError::Ble)?;
+    if let Err(why) = ota::confirm_running_image() {
+        log::error!(
+            "[❌] Failed to confirm the running firmware image: {:?}",
+            why
+        );
+    }
+    advertising::spawn_status_task(
+        identity.clone(),
+        adv_rotation,
+        logs.clone(),
+        lockout.clone(),
+        events.subscribe(),
+    );
+    status_led.follow(events.subscribe(), lockout.clone(), connections.clone());
+    log::info!("[🚋] Starting BLE Server");
+
+    #[cfg(feature = "wifi")]
+    match wifi::NetworkConfig::load(nvs_partition.clone()) {
+        Ok(Some(network_config)) => match esp_idf_svc::eventloop::EspSystemEventLoop::take()
+            .and_then(|sysloop| {
+                wifi::connect(modem, sysloop, nvs_partition.clone(), &network_config)
+            }) {
+            Ok(_) => {
+                #[cfg(any(feature = "wifi-mqtt", feature = "wifi-http"))]
+                let remote_ctx = remote::RemoteContext {
+                    device_id: device_id.clone(),
+                    challenges: challenge.clone(),
+                    credentials: credentials.clone(),
+                    unlock: tx.clone(),
+                    logs: logs.clone(),
+                    metadata: metadata.clone(),
+                    roles: roles.clone(),
                 };
-                if challenge_result.time.elapsed().expect("Time ran backwards")
-                    > Duration::from_secs(90)
+                #[cfg(feature = "wifi-mqtt")]
+                if let Err(why) =
+                    mqtt::start(&network_config, remote_ctx.clone(), events.subscribe())
                 {
-                    log::error!(
-                        "[⛔] ({}) Opening-Request denied: challenge expired",
-                        args.desc().address()
-                    );
-                    clean_up_challenges(&mut challenges, challenge_data, &args.desc().address());
-                    args.reject_with_error_code(0x07);
-                    append_logs(
-                        args.desc().address().to_string(),
-                        LogEntryStatus::Failed(0x07),
-                        power_on,
-                        logs,
-                        log_char,
-                    );
-                    return;
+                    log::error!("[❌] Failed to start the MQTT bridge: {:?}", why);
                 }
-                clean_up_challenges(&mut challenges, challenge_data, &args.desc().address());
-            }
-
-            assert_eq!(challenge_data.len(), 64);
-            let signature = &data[64..]; // created from a SHA256 digest of the challenge
-            let signature = match Signature::from_der(signature) {
-                Ok(x) => x,
-                Err(why) => {
-                    log::error!(
-                        "[❌] ({}) Invalid DER signature: {:?}",
-                        args.desc().address(),
-                        why
-                    );
-                    args.reject_with_error_code(0x02);
-                    append_logs(
-                        args.desc().address().to_string(),
-                        LogEntryStatus::Failed(0x02),
-                        power_on,
-                        logs,
-                        log_char,
-                    );
-                    return;
+                #[cfg(feature = "wifi-http")]
+                if let Err(why) = http_api::start(remote_ctx) {
+                    log::error!("[❌] Failed to start the HTTP API: {:?}", why);
                 }
-            };
-            match verifying_key.verify(&challenge_data, &signature) {
-                Ok(_) => match tx.send(args.desc().address()) {
-                    // Ok(_) => match open_door(&args.desc().address()) {
-                    Ok(_) => {}
-                    Err(why) => {
-                        log::error!("[❌] Failed to tx: {:?}", why);
-                        args.reject_with_error_code(0x08);
-                        append_logs(
-                            args.desc().address().to_string(),
-                            LogEntryStatus::Failed(0x08),
-                            power_on,
-                            logs,
-                            log_char,
-                        );
-                        return;
-                    }
-                },
-                Err(why) => {
-                    log::error!(
-                        "[❌] ({}) Signature verification failed: {:?}",
-                        args.desc().address(),
-                        why
-                    );
-                    args.reject_with_error_code(0x04);
-                    append_logs(
-                        args.desc().address().to_string(),
-                        LogEntryStatus::Failed(0x04),
-                        power_on,
-                        logs,
-                        log_char,
-                    );
-                    return;
+                #[cfg(feature = "webhooks")]
+                if let Err(why) = webhooks::start(
+                    network_config.webhooks.clone(),
+                    device_id.clone(),
+                    nvs_partition.clone(),
+                    events.subscribe(),
+                ) {
+                    log::error!("[❌] Failed to start the webhooks: {:?}", why);
                 }
             }
-        });
-    setup_ble(&mut ble_device, ble_name, service_uid).unwrap();
-    led_pin.set_low().unwrap();
-    log::info!("[🚋] Starting BLE Server");
+            Err(why) => log::error!("[❌] Failed to start the wifi: {:?}", why),
+        },
+        Ok(None) => log::info!("[📶] No network configured"),
+        Err(why) => log::error!("[❌] Failed to load the network config: {:?}", why),
+    }
+
+    if let Err(why) = supervisor::spawn(nvs_partition.clone(), stats.clone(), connections.clone()) {
+        log::error!("[❌] Failed to start the supervisor: {:?}", why);
+    }
+    let watchdog = Watchdog::subscribe("main");
+    // the gate is held open in passage mode
+    let mut passage = false;
     loop {
-        // std::thread::sleep(Duration::from_secs(2))
-        let res = match rx.recv() {
+        watchdog.feed();
+        let res = match rx.recv_timeout(supervisor::FEED_INTERVAL) {
             Ok(x) => x,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => continue,
             Err(why) => {
                 log::error!("[❌] Failed to rx: {:?}", why);
                 continue;
             }
         };
-        match open_door(&res, &mut led_pin, open_time) {
+        if res.client.source() == LogSource::Ble && ble_unlock_disabled.load(Ordering::Relaxed) {
+            log::error!("[⛔] ({}) Request denied: BLE unlock disabled", res.client);
+            logs.append(&res.client, LogEntryStatus::Failed(0x17));
+            continue;
+        }
+        // the exit button is inside, it isn't affected by a lockout
+        if res.client != Client::Button && lockout.is_locked_out(&res.client) {
+            log::error!("[⛔] ({}) Request denied: locked out", res.client);
+            logs.append(&res.client, LogEntryStatus::Failed(0x0E));
+            continue;
+        }
+        let res = match access.authorize(res.clone()) {
+            Ok(Some(x)) => x,
+            Ok(None) => continue,
+            Err(code) => {
+                logs.append_request(&res, LogEntryStatus::Failed(code));
+                continue;
+            }
+        };
+        if let Some(hold) = res.hold {
+            if hold == passage {
+                continue;
+            }
+            passage = hold;
+            events.publish(GateEvent::PassageMode(hold));
+            events.publish(if hold {
+                GateEvent::GateOpened
+            } else {
+                GateEvent::GateClosed
+            });
+            match hold_door(&res.client, led_pin, hold) {
+                Ok(_) => logs.append_request(&res, LogEntryStatus::Successful),
+                Err(why) => log::error!("[❌] Failed to switch the passage mode: {:?}", why),
+            }
+            continue;
+        }
+        if passage {
+            log::info!("[🚪] ({}) The gate is held open", res.client);
+            logs.append_request(&res, LogEntryStatus::Successful);
+            continue;
+        }
+        events.publish(GateEvent::GateOpened);
+        let result = open_door(&res.client, led_pin, open_time);
+        events.publish(GateEvent::GateClosed);
+        match result {
             Ok(_) => {
-                let error = error_pin.clone();
-
-                append_logs(
-                    res.to_string(),
-                    LogEntryStatus::Successful,
-                    power_on,
-                    logs.clone(),
-                    logs_char.clone(),
-                );
-                std::thread::spawn(|| {
-                    blink_in_sequence(error, &[true, true, true, true, true])
-                        .expect("Failed to show success sequence -> critical hardware issue");
-                });
+                stats.record_relay_cycle();
+                logs.append_request(&res, LogEntryStatus::Successful);
             }
             Err(why) => {
-                log::error!("[❌] ({}) Failed to open door: {:?}", &res, why);
-                let error = error_pin.clone();
-                std::thread::spawn(|| {
-                    blink_in_sequence(error, &[true, false, true, true, true])
-                        .expect("Failed to show error sequence -> criticial hardware issue");
-                });
+                log::error!("[❌] ({}) Failed to open door: {:?}", &res.client, why);
+                stats.session.failed.fetch_add(1, Ordering::Relaxed);
+                status_led.show(Pattern::Denied);
             }
         }
     }
 }
-fn blink_in_sequence<T: esp_idf_svc::hal::gpio::Pin>(
-    led: Arc<Mutex<PinDriver<T, Output>>>,
-    sequence: &[bool],
-) -> Result<(), EspError> {
-    let mut led = led.lock().expect("Unable to lock MUTEX");
-    for x in sequence {
-        if *x {
-            led.set_high()?;
-            std::thread::sleep(Duration::from_millis(500));
-            led.set_low()?;
-        } else {
-            std::thread::sleep(Duration::from_millis(500));
-        }
-        std::thread::sleep(Duration::from_millis(200));
-    }
-    Ok(())
-}
 fn bytes_to_hex_string(bytes: &[u8]) -> String {
     let mut str = String::new();
     bytes.into_iter().for_each(|x| {
@@ -464,30 +969,46 @@ fn bytes_to_hex_string(bytes: &[u8]) -> String {
     });
     str
 }
-
-fn clean_up_challenges(
-    challenges: &mut Vec<BLEChallenge>,
-    challenge_data: &[u8],
-    addr: &BLEAddress,
-) {
-    *challenges = challenges
-        .clone()
-        .into_iter()
-        .filter(|x| !(x.address == *addr && x.challenge_bytes == challenge_data))
-        .collect();
+fn hex_string_to_bytes(str: &str) -> Option<Vec<u8>> {
+    if str.len() % 2 != 0 {
+        return None;
+    }
+    (0..str.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(str.get(x..x + 2)?, 16).ok())
+        .collect()
 }
+
 fn open_door<T: esp_idf_svc::hal::gpio::Pin>(
-    addr: &BLEAddress,
+    addr: &Client,
     door: &mut PinDriver<T, Output>,
     open_time: Duration,
 ) -> Result<(), EspError> {
     log::info!("[✔️] ({}) opening gate", addr);
 
-    door.set_high()?;
-    std::thread::sleep(open_time);
-    door.set_low()?;
-
-    Ok(())
+    let opened = door.set_high();
+    if opened.is_ok() {
+        std::thread::sleep(open_time);
+    }
+    // always try to release the relay, even if raising it failed halfway
+    opened.and(door.set_low())
+}
+/// holds the gate open (passage mode) or releases it
+fn hold_door<T: esp_idf_svc::hal::gpio::Pin>(
+    addr: &Client,
+    door: &mut PinDriver<T, Output>,
+    hold: bool,
+) -> Result<(), EspError> {
+    log::info!(
+        "[🚪] ({}) passage mode {}",
+        addr,
+        if hold { "on" } else { "off" }
+    );
+    if hold {
+        door.set_high()
+    } else {
+        door.set_low()
+    }
 }
 fn setup_ble(device: &mut BLEDevice, ble_name: &str, service_uid: BleUuid) -> Result<(), BLEError> {
     BLEDevice::set_device_name(ble_name)?;
@@ -500,33 +1021,3 @@ fn setup_ble(device: &mut BLEDevice, ble_name: &str, service_uid: BleUuid) -> Re
     device.get_advertising().lock().start()?;
     Ok(())
 }
-fn append_logs(
-    mac: String,
-    status: LogEntryStatus,
-    _start: SystemTime,
-    logs: Arc<Mutex<Vec<LogEntry>>>,
-    notify: Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>, // ) -> Result<(), PoisonError<std::sync::MutexGuard<'static, Vec<LogEntry>>>> {
-) {
-    let logs = logs.clone();
-    let entry = LogEntry {
-        mac,
-        status,
-        time: SystemTime::now(),
-    };
-    let mut logs = match logs.lock() {
-        Ok(x) => x,
-        Err(_) => {
-            log::error!("[❌] Failed to lock logs mutex");
-            return;
-        }
-    };
-    if logs.len() >= 32 {
-        logs.reverse();
-        logs.pop();
-        logs.reverse();
-    }
-    logs.push(entry.clone());
-    notify.lock().set_value(&entry.encode());
-
-    return;
-}
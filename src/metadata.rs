@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::access::{AccessControl, PendingAuthorization};
+use crate::connections::Connections;
+use crate::device_info;
+use crate::identity::Identity;
+use crate::stats::{LifetimeCounters, Stats};
+
+/// Version of the json layout served by the metadata characteristic
+pub const META_SCHEMA_VERSION: u32 = 7;
+
+#[derive(Debug, Serialize, Clone)]
+pub struct MetaDataStruct {
+    pub schema_version: u32,
+    pub power_on_hours: f64,
+    pub trigger_pin: i32,
+    pub status_led_pin: i32,
+    pub firmware_version: String,
+    pub git_hash: String,
+    pub protocol_version: u32,
+    pub idf_version: String,
+    pub chip_model: String,
+    pub chip_revision: u16,
+    pub device_id: String,
+    /// the public identity key (compressed SEC1, hex), regenerated by a factory reset
+    pub device_key: String,
+    pub reset_reason: String,
+    /// why the supervisor rebooted the device the last time (if it did)
+    pub supervisor_reason: Option<String>,
+    pub free_heap: u32,
+    pub min_free_heap: u32,
+    pub successful_openings: u32,
+    pub failed_openings: u32,
+    pub lifetime: LifetimeCounters,
+    pub connections: u32,
+    /// disconnects since boot by their NimBLE reason code
+    pub disconnect_reasons: BTreeMap<String, u32>,
+    /// unlocks have to carry the user's PIN (see [`crate::two_factor::TwoFactor`])
+    pub two_factor: bool,
+    /// seconds two users have to sign within to open the gate, 0 if one is enough
+    pub dual_authorization: u64,
+    /// a dual authorization waiting for its second user
+    pub pending_authorization: Option<PendingAuthorization>,
+    /// the device has been reset and waits for a new owner to claim it
+    pub enrollment_mode: bool,
+}
+
+/// Builds the current metadata for every transport
+pub struct MetadataSource {
+    base: MetaDataStruct,
+    power_on: Instant,
+    stats: Arc<Stats>,
+    connections: Arc<Connections>,
+    access: Arc<AccessControl>,
+}
+
+impl MetadataSource {
+    pub fn new(
+        (trigger_pin, status_led_pin): (i32, i32),
+        identity: Arc<Identity>,
+        supervisor_reason: Option<String>,
+        power_on: Instant,
+        stats: Arc<Stats>,
+        connections: Arc<Connections>,
+        access: Arc<AccessControl>,
+    ) -> Self {
+        let (chip_model, chip_revision) = device_info::chip_info();
+        let policy = access.gate_policy();
+        MetadataSource {
+            base: MetaDataStruct {
+                schema_version: META_SCHEMA_VERSION,
+                power_on_hours: 0.,
+                trigger_pin,
+                status_led_pin,
+                firmware_version: device_info::FIRMWARE_VERSION.to_owned(),
+                git_hash: device_info::GIT_HASH.to_owned(),
+                protocol_version: crate::PROTOCOL_VERSION,
+                idf_version: device_info::idf_version(),
+                chip_model,
+                chip_revision,
+                device_id: identity.device_id.clone(),
+                device_key: identity.public_key(),
+                reset_reason: device_info::reset_reason().to_owned(),
+                supervisor_reason,
+                free_heap: 0,
+                min_free_heap: 0,
+                successful_openings: 0,
+                failed_openings: 0,
+                lifetime: LifetimeCounters::default(),
+                connections: 0,
+                disconnect_reasons: BTreeMap::new(),
+                two_factor: policy.two_factor,
+                dual_authorization: policy.dual_authorization.as_secs(),
+                pending_authorization: None,
+                enrollment_mode: false,
+            },
+            power_on,
+            stats,
+            connections,
+            access,
+        }
+    }
+
+    pub fn current(&self) -> MetaDataStruct {
+        let mut meta = self.base.clone();
+        meta.power_on_hours = self.power_on.elapsed().as_secs_f64() / (60. * 60.);
+        meta.free_heap = device_info::free_heap();
+        meta.min_free_heap = device_info::min_free_heap();
+        meta.successful_openings = self.stats.session.successful.load(Ordering::Relaxed);
+        meta.failed_openings = self.stats.session.failed.load(Ordering::Relaxed);
+        meta.lifetime = self.stats.snapshot();
+        meta.connections = self.connections.count() as u32;
+        meta.disconnect_reasons = self.connections.disconnect_reasons();
+        meta.pending_authorization = self.access.pending();
+        meta.enrollment_mode = self.access.enrollment_mode();
+        meta
+    }
+
+    pub fn to_json(&self) -> String {
+        match serde_json::to_string(&self.current()) {
+            Ok(x) => x,
+            Err(why) => {
+                log::error!("[❌] Failed to prepare json: {}", why.to_string());
+                "".to_owned()
+            }
+        }
+    }
+}
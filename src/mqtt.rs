@@ -0,0 +1,179 @@
+use std::sync::mpsc::{channel, Receiver};
+
+use esp_idf_svc::mqtt::client::{
+    EspMqttClient, EventPayload, LwtConfiguration, MqttClientConfiguration, QoS,
+};
+use esp_idf_svc::sys::EspError;
+use gax_mqtt::{Bridge, Event, MqttClient, Task};
+
+use crate::challenge::Client;
+use crate::events::GateEvent;
+#[cfg(feature = "ha-discovery")]
+use crate::ha_discovery;
+use crate::logs::LogEntryStatus;
+use crate::remote::RemoteContext;
+use crate::wifi::NetworkConfig;
+
+/// The client unsigned Home Assistant commands are recorded for
+const HOME_ASSISTANT: &str = "homeassistant";
+
+/// Connects to the configured broker and bridges the gate to it; the topics are documented at
+/// [`gax_mqtt::Bridge`]
+pub fn start(
+    config: &NetworkConfig,
+    ctx: RemoteContext,
+    events: Receiver<GateEvent>,
+) -> Result<(), EspError> {
+    let url = match config.mqtt_url.as_ref() {
+        Some(x) => x.clone(),
+        None => {
+            log::info!("[📡] No MQTT broker configured");
+            return Ok(());
+        }
+    };
+    let prefix = config
+        .mqtt_topic_prefix
+        .clone()
+        .unwrap_or_else(|| format!("gax/{}", ctx.device_id));
+    let status_topic = format!("{}/status", prefix);
+    let client_id = format!("gax-{}", ctx.device_id);
+
+    let (client, mut connection) = EspMqttClient::new(
+        &url,
+        &MqttClientConfiguration {
+            client_id: Some(&client_id),
+            username: config.mqtt_user.as_deref(),
+            password: config.mqtt_password.as_deref(),
+            lwt: Some(LwtConfiguration {
+                topic: &status_topic,
+                payload: b"offline",
+                qos: QoS::AtLeastOnce,
+                retain: true,
+            }),
+            ..Default::default()
+        },
+    )?;
+    log::info!("[📡] Connecting to the MQTT broker '{}'", url);
+
+    let (tx, rx) = channel();
+    let connection_tx = tx.clone();
+    std::thread::Builder::new()
+        .stack_size(6 * 1024)
+        .spawn(move || {
+            while let Ok(event) = connection.next() {
+                let task = match event.payload() {
+                    EventPayload::Connected(_) => Task::Connected,
+                    EventPayload::Received {
+                        topic: Some(topic),
+                        data,
+                        ..
+                    } => Task::Received(topic.to_owned(), data.to_vec()),
+                    EventPayload::Disconnected => {
+                        log::info!("[📡] Disconnected from the MQTT broker");
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if connection_tx.send(task).is_err() {
+                    break;
+                }
+            }
+            log::info!("[📡] MQTT connection closed");
+        })
+        .map_err(|_| EspError::from_infallible::<{ esp_idf_svc::sys::ESP_ERR_NO_MEM }>())?;
+
+    std::thread::spawn(move || {
+        for event in events.iter() {
+            let Some(event) = bridged(event) else {
+                continue;
+            };
+            if tx.send(Task::Event(event)).is_err() {
+                break;
+            }
+        }
+    });
+
+    #[cfg(feature = "ha-discovery")]
+    let home_assistant = Some(gax_mqtt::HomeAssistant {
+        discovery_prefix: config
+            .ha_discovery_prefix
+            .clone()
+            .unwrap_or_else(|| ha_discovery::DEFAULT_DISCOVERY_PREFIX.to_owned()),
+        trusted_commands: config.ha_trusted_commands,
+    });
+    #[cfg(not(feature = "ha-discovery"))]
+    let home_assistant = None;
+    let bridge = Bridge::new(EspClient(client), ctx, prefix, home_assistant);
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || bridge.run(rx))
+        .map_err(|_| EspError::from_infallible::<{ esp_idf_svc::sys::ESP_ERR_NO_MEM }>())?;
+    Ok(())
+}
+
+/// the gate events the bridge publishes
+fn bridged(event: GateEvent) -> Option<Event> {
+    match event {
+        GateEvent::Log(entry) => Some(Event::Log {
+            entry: serde_json::to_string(&entry).unwrap_or_default(),
+            duress: matches!(entry.status, LogEntryStatus::Duress),
+        }),
+        GateEvent::GateOpened => Some(Event::GateOpened),
+        GateEvent::GateClosed => Some(Event::GateClosed),
+        GateEvent::PassageMode(_) | GateEvent::DoorOpen(_) => None,
+    }
+}
+
+struct EspClient(EspMqttClient<'static>);
+
+impl MqttClient for EspClient {
+    type Error = EspError;
+
+    fn subscribe(&mut self, topic: &str) -> Result<(), EspError> {
+        self.0.subscribe(topic, QoS::AtLeastOnce).map(|_| ())
+    }
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &[u8]) -> Result<(), EspError> {
+        self.0
+            .enqueue(topic, QoS::AtLeastOnce, retain, payload)
+            .map(|_| ())
+    }
+}
+
+impl gax_mqtt::Device for RemoteContext {
+    fn issue_challenge(&self, peer: &str) -> Option<String> {
+        RemoteContext::issue_challenge(self, Client::Mqtt(peer.to_owned()))
+    }
+
+    fn unlock(&self, peer: &str, response: &[u8]) -> Result<(), i32> {
+        RemoteContext::unlock(self, &Client::Mqtt(peer.to_owned()), response)
+    }
+
+    fn unlock_unsigned(&self) -> Result<(), i32> {
+        let client = Client::Mqtt(HOME_ASSISTANT.to_owned());
+        self.unlock.send(client.clone().into()).map_err(|why| {
+            log::error!("[❌] Failed to tx: {:?}", why);
+            self.logs.append(&client, LogEntryStatus::Failed(0x08));
+            0x08
+        })
+    }
+
+    fn deny(&self, code: i32) {
+        self.logs.append(
+            &Client::Mqtt(HOME_ASSISTANT.to_owned()),
+            LogEntryStatus::Failed(code),
+        );
+    }
+
+    fn metadata(&self) -> String {
+        self.metadata.to_json()
+    }
+
+    #[cfg_attr(not(feature = "ha-discovery"), allow(unused_variables))]
+    fn discovery_configs(&self, discovery_prefix: &str, prefix: &str) -> Vec<(String, String)> {
+        #[cfg(feature = "ha-discovery")]
+        return ha_discovery::configs(discovery_prefix, prefix, &self.metadata.current());
+        #[cfg(not(feature = "ha-discovery"))]
+        Vec::new()
+    }
+}
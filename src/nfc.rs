@@ -0,0 +1,120 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use gax_nfc::{authenticate_desfire, phone_signature};
+pub use gax_nfc::{NfcError, NfcReader, Tag};
+
+use crate::bytes_to_hex_string;
+use crate::challenge::{self, Challenge, Client, Factor, UnlockRequest};
+use crate::credentials::{CredentialId, Credentials};
+use crate::logs::{LogEntryStatus, LogStore};
+use crate::supervisor::Watchdog;
+
+/// The reader is asked for a tag this often
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub struct NfcContext {
+    pub credentials: Arc<Credentials>,
+    pub challenges: Arc<Mutex<Vec<Challenge>>>,
+    pub unlock: Sender<UnlockRequest>,
+    pub logs: Arc<LogStore>,
+    /// the DESFire application holding the key of enrolled cards
+    pub desfire_aid: u32,
+}
+
+/// the user presenting the tag, how the tag proved it and whether it has been a duress key
+///
+/// Errors with the code logged:
+/// - `0x18` the tag isn't enrolled (and isn't a phone with an enrolled key)
+/// - see [`NfcError::code`] & [`challenge::verify_response`]
+fn identify(
+    reader: &mut dyn NfcReader,
+    tag: &Tag,
+    client: &Client,
+    ctx: &NfcContext,
+) -> Result<(u16, Factor, bool), i32> {
+    let failed = |why: NfcError| {
+        log::error!("[❌] ({}) {}", client, why);
+        why.code()
+    };
+    match ctx.credentials.find_tag(&bytes_to_hex_string(&tag.uid)) {
+        Some((user, None)) => Ok((user, Factor::Card, false)),
+        Some((user, Some(key))) => authenticate_desfire(reader, ctx.desfire_aid, &key)
+            .map(|_| (user, Factor::Card, false))
+            .map_err(failed),
+        None if tag.iso_dep => {
+            let challenge =
+                challenge::issue_challenge(&ctx.challenges, client.clone()).ok_or(0x05)?;
+            match phone_signature(reader, &challenge).map_err(failed) {
+                Ok(Some(signature)) => {
+                    let data: Vec<u8> = challenge.iter().chain(signature.iter()).copied().collect();
+                    challenge::verify_response(
+                        &ctx.challenges,
+                        &ctx.credentials.keys(),
+                        &data,
+                        0,
+                        client,
+                    )
+                    .map(|x| (x.user, Factor::Signature, x.duress))
+                }
+                result => {
+                    challenge::clean_up_client(&ctx.challenges, client);
+                    result.and(Err(0x18))
+                }
+            }
+        }
+        None => Err(0x18),
+    }
+}
+
+/// Polls the reader; enrolled tags (DESFire cards have to pass the mutual authentication) and
+/// phones signing a challenge with an enrolled key open the gate
+pub fn spawn(mut reader: Box<dyn NfcReader>, ctx: NfcContext) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            let watchdog = Watchdog::subscribe("nfc");
+            // a tag is only read once while it stays in the field
+            let mut present: Option<Vec<u8>> = None;
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+                watchdog.feed();
+                let tag = match reader.poll() {
+                    Ok(Some(x)) => x,
+                    Ok(None) => {
+                        present = None;
+                        continue;
+                    }
+                    Err(why) => {
+                        log::error!("[❌] Failed to poll the NFC reader: {}", why);
+                        continue;
+                    }
+                };
+                if present.as_ref() == Some(&tag.uid) {
+                    continue;
+                }
+                present = Some(tag.uid.clone());
+                let client = Client::Nfc(CredentialId::Tag(bytes_to_hex_string(&tag.uid)));
+                match identify(reader.as_mut(), &tag, &client, &ctx) {
+                    Ok((user, factor, duress)) => {
+                        log::info!("[📡] ({}) User {} identified", client, user);
+                        let request = UnlockRequest {
+                            duress,
+                            ..UnlockRequest::for_user(client, user, factor)
+                        };
+                        if let Err(why) = ctx.unlock.send(request) {
+                            log::error!("[❌] Failed to tx: {:?}", why);
+                        }
+                    }
+                    // the tag may be presented again
+                    Err(0x1B) => present = None,
+                    Err(code) => {
+                        log::error!("[⛔] ({}) Tag rejected: {:#04x}", client, code);
+                        ctx.logs.append(&client, LogEntryStatus::Failed(code));
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
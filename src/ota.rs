@@ -0,0 +1,266 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use esp_idf_svc::ota::EspOta;
+use esp_idf_svc::sys::{self, esp, EspError};
+use k256::ecdsa::{signature::DigestVerifier, Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+// Control characteristic commands
+const CMD_BEGIN: u8 = 0x01; // | image size (u32 BE) | sha256 of the image (32 byte)
+const CMD_FINISH: u8 = 0x02; // | DER signature over the image (release key)
+const CMD_ABORT: u8 = 0x03;
+
+/// State reported by reading the control characteristic
+/// (`state (1 byte) | offset (u32 BE) | image size (u32 BE)`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaState {
+    Idle = 0,
+    Receiving = 1,
+    Done = 2,
+    Failed = 3,
+}
+
+struct OtaSession {
+    handle: sys::esp_ota_handle_t,
+    partition: *const sys::esp_partition_t,
+    image_size: u32,
+    image_hash: [u8; 32],
+    offset: u32,
+    hasher: Sha256,
+}
+// the partition pointer points into the static partition table
+unsafe impl Send for OtaSession {}
+
+/// Streams a signed firmware image into the inactive OTA partition
+///
+/// The session outlives the BLE connection: after a disconnect the client reads the status,
+/// sends `BEGIN` with the same size & hash and continues writing at the reported offset.
+pub struct OtaUpdater {
+    release_key: VerifyingKey,
+    session: Mutex<Option<OtaSession>>,
+    state: Mutex<OtaState>,
+}
+
+impl OtaUpdater {
+    pub fn new(release_key: VerifyingKey) -> Self {
+        OtaUpdater {
+            release_key,
+            session: Mutex::new(None),
+            state: Mutex::new(OtaState::Idle),
+        }
+    }
+
+    pub fn status(&self) -> [u8; 9] {
+        let state = *self.state.lock().unwrap_or_else(|x| x.into_inner());
+        let session = self.session.lock().unwrap_or_else(|x| x.into_inner());
+        let (offset, size) = match session.as_ref() {
+            Some(x) => (x.offset, x.image_size),
+            None => (0, 0),
+        };
+        let mut res: [u8; 9] = [0x0; 9];
+        res[0] = state as u8;
+        res[1..5].clone_from_slice(&offset.to_be_bytes());
+        res[5..9].clone_from_slice(&size.to_be_bytes());
+        res
+    }
+
+    fn set_state(&self, state: OtaState) {
+        *self.state.lock().unwrap_or_else(|x| x.into_inner()) = state;
+    }
+
+    /// handles a write to the control characteristic
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x10` no update in progress
+    /// - `0x12` the image doesn't fit into the partition
+    /// - `0x13` the image hash doesn't match
+    /// - `0x14` the release signature is invalid
+    /// - `0x15` flash error
+    /// - `0x16` malformed command
+    pub fn handle_control(&self, data: &[u8]) -> Result<(), i32> {
+        match data.first() {
+            Some(&CMD_BEGIN) if data.len() == 1 + 4 + 32 => {
+                let image_size = u32::from_be_bytes(data[1..5].try_into().unwrap());
+                let image_hash: [u8; 32] = data[5..37].try_into().unwrap();
+                self.begin(image_size, image_hash)
+            }
+            Some(&CMD_FINISH) => self.finish(&data[1..]),
+            Some(&CMD_ABORT) => {
+                self.abort();
+                Ok(())
+            }
+            _ => Err(0x16),
+        }
+    }
+
+    fn begin(&self, image_size: u32, image_hash: [u8; 32]) -> Result<(), i32> {
+        let mut session = self.session.lock().unwrap_or_else(|x| x.into_inner());
+        if let Some(x) = session.as_ref() {
+            if x.image_size == image_size && x.image_hash == image_hash {
+                log::info!("[📦] Resuming firmware update at offset {}", x.offset);
+                self.set_state(OtaState::Receiving);
+                return Ok(());
+            }
+        }
+        if let Some(x) = session.take() {
+            unsafe { sys::esp_ota_abort(x.handle) };
+        }
+
+        let partition = unsafe { sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+        if partition.is_null() {
+            log::error!("[❌] No OTA partition available");
+            return Err(0x15);
+        }
+        if image_size > unsafe { (*partition).size } {
+            log::error!("[❌] Firmware image of {} bytes is too large", image_size);
+            return Err(0x12);
+        }
+        let mut handle: sys::esp_ota_handle_t = 0;
+        esp!(unsafe { sys::esp_ota_begin(partition, image_size as usize, &mut handle) }).map_err(
+            |why| {
+                log::error!("[❌] Failed to begin the firmware update: {:?}", why);
+                0x15
+            },
+        )?;
+        log::info!("[📦] Receiving firmware image of {} bytes", image_size);
+        *session = Some(OtaSession {
+            handle,
+            partition,
+            image_size,
+            image_hash,
+            offset: 0,
+            hasher: Sha256::new(),
+        });
+        self.set_state(OtaState::Receiving);
+        Ok(())
+    }
+
+    /// handles a write to the data characteristic (`offset (u32 BE) | chunk`)
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x10` no update in progress
+    /// - `0x11` the offset doesn't match (the client should re-read the status)
+    /// - `0x12` the chunk exceeds the announced image size
+    /// - `0x15` flash error
+    /// - `0x16` malformed chunk
+    pub fn handle_data(&self, data: &[u8]) -> Result<(), i32> {
+        if data.len() < 5 {
+            return Err(0x16);
+        }
+        let offset = u32::from_be_bytes(data[..4].try_into().unwrap());
+        let chunk = &data[4..];
+
+        let mut session = self.session.lock().unwrap_or_else(|x| x.into_inner());
+        let session = session.as_mut().ok_or(0x10)?;
+        if offset != session.offset {
+            return Err(0x11);
+        }
+        if session.offset as usize + chunk.len() > session.image_size as usize {
+            return Err(0x12);
+        }
+        esp!(unsafe {
+            sys::esp_ota_write(session.handle, chunk.as_ptr() as *const _, chunk.len())
+        })
+        .map_err(|why| {
+            log::error!("[❌] Failed to write firmware chunk: {:?}", why);
+            0x15
+        })?;
+        session.hasher.update(chunk);
+        session.offset += chunk.len() as u32;
+        Ok(())
+    }
+
+    fn finish(&self, signature: &[u8]) -> Result<(), i32> {
+        let session = self
+            .session
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .take()
+            .ok_or(0x10)?;
+        let res = self.verify_and_activate(session, signature);
+        self.set_state(if res.is_ok() {
+            OtaState::Done
+        } else {
+            OtaState::Failed
+        });
+        res?;
+
+        log::info!("[📦] Firmware update complete, rebooting");
+        std::thread::spawn(|| {
+            // give the client the chance to read the result
+            std::thread::sleep(Duration::from_secs(2));
+            esp_idf_svc::hal::reset::restart();
+        });
+        Ok(())
+    }
+
+    fn verify_and_activate(&self, session: OtaSession, signature: &[u8]) -> Result<(), i32> {
+        if session.offset != session.image_size {
+            log::error!(
+                "[❌] Firmware image incomplete: {}/{} bytes",
+                session.offset,
+                session.image_size
+            );
+            unsafe { sys::esp_ota_abort(session.handle) };
+            return Err(0x12);
+        }
+        if session.hasher.clone().finalize().as_slice() != session.image_hash {
+            log::error!("[❌] Firmware image hash mismatch");
+            unsafe { sys::esp_ota_abort(session.handle) };
+            return Err(0x13);
+        }
+        let valid = Signature::from_der(signature)
+            .map(|x| self.release_key.verify_digest(session.hasher, &x).is_ok())
+            .unwrap_or(false);
+        if !valid {
+            log::error!("[⛔] Firmware image isn't signed by the release key");
+            unsafe { sys::esp_ota_abort(session.handle) };
+            return Err(0x14);
+        }
+
+        // esp_ota_end validates the image structure
+        esp!(unsafe { sys::esp_ota_end(session.handle) })
+            .and_then(|_| esp!(unsafe { sys::esp_ota_set_boot_partition(session.partition) }))
+            .map_err(|why| {
+                log::error!("[❌] Failed to activate the firmware image: {:?}", why);
+                0x15
+            })
+    }
+
+    pub fn abort(&self) {
+        if let Some(x) = self
+            .session
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .take()
+        {
+            log::info!("[📦] Firmware update aborted at offset {}", x.offset);
+            unsafe { sys::esp_ota_abort(x.handle) };
+        }
+        self.set_state(OtaState::Idle);
+    }
+}
+
+/// Marks the running image as valid, cancelling the rollback of a freshly updated image.
+/// Has to be called once the BLE service is up.
+pub fn confirm_running_image() -> Result<(), EspError> {
+    let mut ota = EspOta::new()?;
+    ota.mark_running_slot_valid()
+}
+
+/// Rolls back to the previous image (only possible while the running image is unconfirmed)
+pub fn reject_running_image() -> EspError {
+    match EspOta::new() {
+        Ok(mut ota) => ota.mark_running_slot_invalid_and_reboot(),
+        Err(why) => why,
+    }
+}
+
+/// `true` while a freshly updated image hasn't been confirmed yet (a rollback is possible)
+pub fn running_image_unconfirmed() -> bool {
+    let mut state: sys::esp_ota_img_states_t = 0;
+    let partition = unsafe { sys::esp_ota_get_running_partition() };
+    esp!(unsafe { sys::esp_ota_get_state_partition(partition, &mut state) }).is_ok()
+        && state == sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
@@ -0,0 +1,251 @@
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::delay::TickType;
+use esp_idf_svc::hal::gpio::{InputPin, OutputPin};
+use esp_idf_svc::hal::i2c::{I2c, I2cConfig, I2cDriver};
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::hal::spi::config::{BitOrder, Config as SpiConfig, DriverConfig};
+use esp_idf_svc::hal::spi::{SpiAnyPins, SpiDeviceDriver, SpiDriver};
+use esp_idf_svc::hal::units::FromValueType;
+use esp_idf_svc::sys::EspError;
+use serde::Deserialize;
+
+use crate::nfc::{NfcError, NfcReader, Tag};
+
+const I2C_ADDRESS: u8 = 0x24;
+const I2C_TIMEOUT_MS: u64 = 50;
+/// The PN532 has to acknowledge a command within this time
+const ACK_TIMEOUT: Duration = Duration::from_millis(50);
+/// A tag has this long to answer an APDU (DESFire & phones can be slow)
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(500);
+const ACK: [u8; 6] = [0x00, 0x00, 0xFF, 0x00, 0xFF, 0x00];
+
+const HOST_TO_PN532: u8 = 0xD4;
+const PN532_TO_HOST: u8 = 0xD5;
+const CMD_GET_FIRMWARE_VERSION: u8 = 0x02;
+const CMD_SAM_CONFIGURATION: u8 = 0x14;
+const CMD_RF_CONFIGURATION: u8 = 0x32;
+const CMD_IN_DATA_EXCHANGE: u8 = 0x40;
+const CMD_IN_LIST_PASSIVE_TARGET: u8 = 0x4A;
+
+/// How the PN532 is wired (set by its I0/I1 switches)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NfcInterface {
+    /// no reader
+    None,
+    I2c,
+    Spi,
+}
+
+impl From<EspError> for NfcError {
+    fn from(value: EspError) -> Self {
+        NfcError::Reader(value.to_string())
+    }
+}
+
+/// The host interface the PN532 is talked to with
+pub trait Interface: Send {
+    fn write(&mut self, frame: &[u8]) -> Result<(), EspError>;
+    /// whether the PN532 has a frame to read
+    fn ready(&mut self) -> Result<bool, EspError>;
+    /// reads the next frame into `buf`
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), EspError>;
+}
+
+impl Interface for I2cDriver<'static> {
+    fn write(&mut self, frame: &[u8]) -> Result<(), EspError> {
+        I2cDriver::write(
+            self,
+            I2C_ADDRESS,
+            frame,
+            TickType::new_millis(I2C_TIMEOUT_MS).ticks(),
+        )
+    }
+
+    fn ready(&mut self) -> Result<bool, EspError> {
+        let mut status: [u8; 1] = [0x0];
+        I2cDriver::read(
+            self,
+            I2C_ADDRESS,
+            &mut status,
+            TickType::new_millis(I2C_TIMEOUT_MS).ticks(),
+        )?;
+        Ok(status[0] & 0x01 != 0)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), EspError> {
+        // every read over I2C starts with the status byte
+        let mut data = vec![0x0; buf.len() + 1];
+        I2cDriver::read(
+            self,
+            I2C_ADDRESS,
+            &mut data,
+            TickType::new_millis(I2C_TIMEOUT_MS).ticks(),
+        )?;
+        buf.copy_from_slice(&data[1..]);
+        Ok(())
+    }
+}
+
+/// The SPI device has to be configured LSB first
+impl Interface for SpiDeviceDriver<'static, SpiDriver<'static>> {
+    fn write(&mut self, frame: &[u8]) -> Result<(), EspError> {
+        // data write
+        let data: Vec<u8> = [0x01].iter().chain(frame).copied().collect();
+        SpiDeviceDriver::write(self, &data)
+    }
+
+    fn ready(&mut self) -> Result<bool, EspError> {
+        // status read
+        let mut status: [u8; 2] = [0x0; 2];
+        self.transfer(&mut status, &[0x02, 0x00])?;
+        Ok(status[1] & 0x01 != 0)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), EspError> {
+        // data read
+        let mut write = vec![0x0; buf.len() + 1];
+        write[0] = 0x03;
+        let mut data = vec![0x0; buf.len() + 1];
+        self.transfer(&mut data, &write)?;
+        buf.copy_from_slice(&data[1..]);
+        Ok(())
+    }
+}
+
+/// An NXP PN532 reading ISO 14443A tags
+pub struct Pn532<I: Interface> {
+    interface: I,
+}
+
+/// a PN532 on the I2C bus
+pub fn i2c(
+    i2c: impl Peripheral<P = impl I2c> + 'static,
+    sda: impl Peripheral<P = impl InputPin + OutputPin> + 'static,
+    scl: impl Peripheral<P = impl InputPin + OutputPin> + 'static,
+) -> Result<Pn532<I2cDriver<'static>>, NfcError> {
+    let config = I2cConfig::new().baudrate(100.kHz().into());
+    Pn532::new(I2cDriver::new(i2c, sda, scl, &config)?)
+}
+
+/// a PN532 on the SPI bus
+pub fn spi(
+    spi: impl Peripheral<P = impl SpiAnyPins> + 'static,
+    sclk: impl Peripheral<P = impl OutputPin> + 'static,
+    mosi: impl Peripheral<P = impl OutputPin> + 'static,
+    miso: impl Peripheral<P = impl InputPin> + 'static,
+    cs: impl Peripheral<P = impl OutputPin> + 'static,
+) -> Result<Pn532<SpiDeviceDriver<'static, SpiDriver<'static>>>, NfcError> {
+    let driver = SpiDriver::new(spi, sclk, mosi, Some(miso), &DriverConfig::new())?;
+    let config = SpiConfig::new()
+        .baudrate(1.MHz().into())
+        .bit_order(BitOrder::LsbFirst);
+    Pn532::new(SpiDeviceDriver::new(driver, Some(cs), &config)?)
+}
+
+impl<I: Interface> Pn532<I> {
+    pub fn new(interface: I) -> Result<Self, NfcError> {
+        let mut pn532 = Pn532 { interface };
+        let version = pn532.command(CMD_GET_FIRMWARE_VERSION, &[], ACK_TIMEOUT)?;
+        if let [ic, version, revision, ..] = version[..] {
+            log::info!("[📡] PN5{:02x} firmware {}.{} found", ic, version, revision);
+        }
+        // normal mode (no SAM), the IRQ pin isn't used
+        pn532.command(CMD_SAM_CONFIGURATION, &[0x01, 0x14, 0x00], ACK_TIMEOUT)?;
+        // MxRtyATR, MxRtyPSL & MxRtyPassiveActivation: a poll returns right away without a tag
+        pn532.command(CMD_RF_CONFIGURATION, &[0x05, 0xFF, 0x01, 0x02], ACK_TIMEOUT)?;
+        Ok(pn532)
+    }
+
+    /// sends `cmd` and returns the parameters of the response
+    fn command(&mut self, cmd: u8, params: &[u8], timeout: Duration) -> Result<Vec<u8>, NfcError> {
+        let len = params.len() + 2;
+        if len > 0xFF {
+            return Err(NfcError::Protocol("command too long"));
+        }
+        let mut frame = vec![0x00, 0x00, 0xFF, len as u8, (len as u8).wrapping_neg()];
+        frame.push(HOST_TO_PN532);
+        frame.push(cmd);
+        frame.extend_from_slice(params);
+        let sum = frame[5..].iter().fold(0u8, |x, y| x.wrapping_add(*y));
+        frame.push(sum.wrapping_neg());
+        frame.push(0x00);
+        self.interface.write(&frame)?;
+
+        self.wait_ready(ACK_TIMEOUT)?;
+        let mut ack: [u8; 6] = [0x0; 6];
+        self.interface.read(&mut ack)?;
+        if ack != ACK {
+            return Err(NfcError::Protocol("missing ack"));
+        }
+
+        self.wait_ready(timeout)?;
+        let mut buf: [u8; 128] = [0x0; 128];
+        self.interface.read(&mut buf)?;
+        let start = buf
+            .windows(2)
+            .position(|x| x == [0x00, 0xFF])
+            .ok_or(NfcError::Protocol("missing preamble"))?
+            + 2;
+        // the preamble may sit right at the end of the buffer
+        if start + 2 > buf.len() {
+            return Err(NfcError::Protocol("truncated frame"));
+        }
+        let (len, lcs) = (buf[start] as usize, buf[start + 1]);
+        if (len as u8).wrapping_add(lcs) != 0 || len < 2 || start + 3 + len > buf.len() {
+            return Err(NfcError::Protocol("invalid length"));
+        }
+        let body = &buf[start + 2..start + 2 + len];
+        let dcs = buf[start + 2 + len];
+        if body.iter().fold(dcs, |x, y| x.wrapping_add(*y)) != 0 {
+            return Err(NfcError::Protocol("invalid checksum"));
+        }
+        if body[0] != PN532_TO_HOST || body[1] != cmd + 1 {
+            return Err(NfcError::Protocol("unexpected response"));
+        }
+        Ok(body[2..].to_vec())
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> Result<(), NfcError> {
+        let start = Instant::now();
+        while !self.interface.ready()? {
+            if start.elapsed() > timeout {
+                return Err(NfcError::Timeout);
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        Ok(())
+    }
+}
+
+impl<I: Interface> NfcReader for Pn532<I> {
+    fn poll(&mut self) -> Result<Option<Tag>, NfcError> {
+        // a single ISO 14443A target at 106 kbps
+        let res = self.command(CMD_IN_LIST_PASSIVE_TARGET, &[0x01, 0x00], RESPONSE_TIMEOUT)?;
+        // NbTg | Tg | SENS_RES (2) | SEL_RES | NFCIDLength | NFCID1 | ATS
+        match res[..] {
+            [0, ..] => Ok(None),
+            [1, _, _, _, sel_res, uid_len, ref rest @ ..] if rest.len() >= uid_len as usize => {
+                Ok(Some(Tag {
+                    uid: rest[..uid_len as usize].to_vec(),
+                    iso_dep: sel_res & 0x20 != 0,
+                }))
+            }
+            _ => Err(NfcError::Protocol("invalid target")),
+        }
+    }
+
+    fn transceive(&mut self, apdu: &[u8]) -> Result<Vec<u8>, NfcError> {
+        let params: Vec<u8> = [0x01].iter().chain(apdu).copied().collect();
+        let res = self.command(CMD_IN_DATA_EXCHANGE, &params, RESPONSE_TIMEOUT)?;
+        match res.split_first() {
+            Some((0x00, data)) => Ok(data.to_vec()),
+            Some((status, _)) => Err(NfcError::Reader(format!(
+                "data exchange failed: {:#04x}",
+                status
+            ))),
+            None => Err(NfcError::Protocol("empty response")),
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp32_nimble::BLEAddress;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{ble_gap_conn_rssi, EspError};
+use serde::{Deserialize, Serialize};
+
+use crate::challenge::{Client, Factor, UnlockRequest, CHALLENGE_TIMEOUT};
+use crate::credentials::Signer;
+use crate::supervisor::Watchdog;
+
+const NVS_NAMESPACE: &str = "gax_prox";
+const NVS_KEY: &str = "settings";
+/// The RSSI of armed connections is sampled this often
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Hands-free opening, configured through the admin characteristic and kept in NVS
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProximitySettings {
+    #[serde(default)]
+    pub enabled: bool,
+    /// the gate opens once the connection RSSI reaches this (dBm)
+    #[serde(default = "default_rssi_threshold")]
+    pub rssi_threshold: i8,
+    /// a phone has to drop this far below the threshold (or disconnect) before it can open the
+    /// gate hands-free again
+    #[serde(default = "default_hysteresis")]
+    pub hysteresis: u8,
+    /// the users allowed to open hands-free (whichever phone signs with their key)
+    #[serde(default)]
+    pub auto_open_users: Vec<u16>,
+}
+impl Default for ProximitySettings {
+    fn default() -> Self {
+        ProximitySettings {
+            enabled: false,
+            rssi_threshold: default_rssi_threshold(),
+            hysteresis: default_hysteresis(),
+            auto_open_users: Vec::new(),
+        }
+    }
+}
+fn default_rssi_threshold() -> i8 {
+    -60
+}
+fn default_hysteresis() -> u8 {
+    10
+}
+
+/// A verified challenge response waiting for the phone to come close enough
+struct Armed {
+    address: BLEAddress,
+    conn_handle: u16,
+    /// the user who signed the challenge
+    user: u16,
+    /// the challenge has been signed with a duress key
+    duress: bool,
+    time: Instant,
+}
+
+pub struct Proximity {
+    settings: Mutex<ProximitySettings>,
+    armed: Mutex<Vec<Armed>>,
+    /// connections which opened the gate and haven't moved away since
+    disarmed: Mutex<Vec<u16>>,
+    nvs: Mutex<EspNvs<NvsDefault>>,
+}
+
+impl Proximity {
+    pub fn load(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let mut buf: [u8; 1024] = [0x0; 1024];
+        let settings = match nvs.get_raw(NVS_KEY, &mut buf)? {
+            Some(x) => match serde_json::from_slice(x) {
+                Ok(x) => x,
+                Err(why) => {
+                    log::error!("[❌] Failed to parse the proximity settings: {}", why);
+                    ProximitySettings::default()
+                }
+            },
+            None => ProximitySettings::default(),
+        };
+        Ok(Proximity {
+            settings: Mutex::new(settings),
+            armed: Mutex::new(Vec::new()),
+            disarmed: Mutex::new(Vec::new()),
+            nvs: Mutex::new(nvs),
+        })
+    }
+
+    /// validates, stores & applies json encoded [`ProximitySettings`] (admin command)
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x0A` the settings couldn't be stored
+    /// - `0x0B` the settings are invalid
+    pub fn configure(&self, data: &[u8]) -> Result<(), i32> {
+        let settings: ProximitySettings = serde_json::from_slice(data).map_err(|why| {
+            log::error!("[❌] Got invalid proximity settings: {}", why);
+            0x0B
+        })?;
+        self.nvs
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .set_raw(NVS_KEY, data)
+            .map_err(|why| {
+                log::error!("[❌] Failed to store the proximity settings: {:?}", why);
+                0x0A
+            })?;
+        log::info!(
+            "[🚗] Hands-free opening {} (threshold {} dBm, {} users)",
+            if settings.enabled {
+                "enabled"
+            } else {
+                "disabled"
+            },
+            settings.rssi_threshold,
+            settings.auto_open_users.len()
+        );
+        *self.settings.lock().unwrap_or_else(|x| x.into_inner()) = settings;
+        Ok(())
+    }
+
+    /// arms a hands-free opening for the connection after a verified challenge response; the
+    /// gate opens as soon as the RSSI reaches the threshold
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x0D` hands-free opening is disabled or not permitted for the signing user
+    pub fn arm(&self, address: BLEAddress, conn_handle: u16, signer: &Signer) -> Result<(), i32> {
+        {
+            let settings = self.settings.lock().unwrap_or_else(|x| x.into_inner());
+            if !settings.enabled || !settings.auto_open_users.contains(&signer.user) {
+                log::error!(
+                    "[⛔] ({}) Hands-free opening not permitted for user {}",
+                    address,
+                    signer.user
+                );
+                return Err(0x0D);
+            }
+        }
+        log::info!(
+            "[🚗] ({}) Armed hands-free opening of user {}",
+            address,
+            signer.user
+        );
+        let mut armed = self.armed.lock().unwrap_or_else(|x| x.into_inner());
+        armed.retain(|x| x.conn_handle != conn_handle);
+        armed.push(Armed {
+            address,
+            conn_handle,
+            user: signer.user,
+            duress: signer.duress,
+            time: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// forgets the connection; a phone which drove away is allowed to open again
+    pub fn disconnected(&self, conn_handle: u16) {
+        self.armed
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .retain(|x| x.conn_handle != conn_handle);
+        self.disarmed
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .retain(|x| *x != conn_handle);
+    }
+
+    fn poll(&self, unlock: &Sender<UnlockRequest>) {
+        let (threshold, hysteresis) = {
+            let settings = self.settings.lock().unwrap_or_else(|x| x.into_inner());
+            (settings.rssi_threshold, settings.hysteresis)
+        };
+        let mut armed = self.armed.lock().unwrap_or_else(|x| x.into_inner());
+        let mut disarmed = self.disarmed.lock().unwrap_or_else(|x| x.into_inner());
+        armed.retain(|x| {
+            if x.time.elapsed() > CHALLENGE_TIMEOUT {
+                log::info!("[🚗] ({}) Hands-free opening expired", x.address);
+                return false;
+            }
+            let rssi = match connection_rssi(x.conn_handle) {
+                Some(x) => x,
+                None => return true,
+            };
+            if disarmed.contains(&x.conn_handle) {
+                if (rssi as i16) < threshold as i16 - hysteresis as i16 {
+                    // moved away far enough
+                    disarmed.retain(|y| *y != x.conn_handle);
+                }
+                return true;
+            }
+            if rssi < threshold {
+                return true;
+            }
+            log::info!("[🚗] ({}) In range with {} dBm", x.address, rssi);
+            let request = UnlockRequest {
+                client: Client::Ble(x.address, x.conn_handle),
+                rssi: Some(rssi),
+                hold: None,
+                user: Some(x.user),
+                factor: Factor::Signature,
+                duress: x.duress,
+                second_user: None,
+            };
+            if let Err(why) = unlock.send(request) {
+                log::error!("[❌] Failed to tx: {:?}", why);
+            }
+            disarmed.push(x.conn_handle);
+            false
+        });
+    }
+}
+
+fn connection_rssi(conn_handle: u16) -> Option<i8> {
+    let mut rssi: i8 = 0;
+    match unsafe { ble_gap_conn_rssi(conn_handle, &mut rssi) } {
+        0 => Some(rssi),
+        _ => None,
+    }
+}
+
+/// samples the RSSI of the armed connections & opens the gate once a phone is close enough
+pub fn spawn_monitor(proximity: Arc<Proximity>, unlock: Sender<UnlockRequest>) {
+    std::thread::spawn(move || {
+        let watchdog = Watchdog::subscribe("proximity");
+        loop {
+            std::thread::sleep(POLL_INTERVAL);
+            proximity.poll(&unlock);
+            watchdog.feed();
+        }
+    });
+}
@@ -0,0 +1,76 @@
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use crate::challenge::{self, Challenge, Client, UnlockRequest};
+use crate::credentials::Credentials;
+use crate::logs::{LogEntryStatus, LogStore};
+use crate::metadata::MetadataSource;
+use crate::roles::{Operation, Roles};
+use crate::{bytes_to_hex_string, hex_string_to_bytes};
+
+/// State shared between the BLE service and the network transports (MQTT, HTTP), so all of
+/// them see the same challenges and logs
+#[derive(Clone)]
+pub struct RemoteContext {
+    pub device_id: String,
+    pub challenges: Arc<Mutex<Vec<Challenge>>>,
+    pub credentials: Arc<Credentials>,
+    pub unlock: Sender<UnlockRequest>,
+    pub logs: Arc<LogStore>,
+    pub metadata: Arc<MetadataSource>,
+    pub roles: Arc<Roles>,
+}
+
+impl RemoteContext {
+    /// a new hex encoded challenge for `client`
+    pub fn issue_challenge(&self, client: Client) -> Option<String> {
+        challenge::issue_challenge(&self.challenges, client).map(|x| bytes_to_hex_string(&x))
+    }
+
+    /// verifies a `challenge | DER signature` (answering a challenge of `client`) and dispatches
+    /// `operation` for the signer, see [`Roles::dispatch`]
+    ///
+    /// Errors with `0x01` if the response isn't hex, the codes of [`challenge::verify_response`]
+    /// and those of [`Roles::dispatch`]
+    #[cfg_attr(not(feature = "wifi-http"), allow(dead_code))]
+    pub fn dispatch<T>(
+        &self,
+        client: &Client,
+        response: Option<&str>,
+        operation: Operation,
+        handler: impl FnOnce() -> T,
+    ) -> Result<T, i32> {
+        let response = response.and_then(hex_string_to_bytes).ok_or(0x01)?;
+        let signer = challenge::verify_response(
+            &self.challenges,
+            &self.credentials.keys(),
+            &response,
+            0,
+            client,
+        )?;
+        self.roles
+            .dispatch(client, Some(signer.user), operation, |_| Ok(handler()))
+    }
+
+    /// verifies `challenge | [PIN] | DER signature` exactly like the lock characteristic and
+    /// requests the opening; failures are logged (see [`challenge::verify_unlock`] for the codes)
+    pub fn unlock(&self, client: &Client, response: &[u8]) -> Result<(), i32> {
+        log::info!(
+            "[👀] ({}) Got challenge response '{}'",
+            client,
+            bytes_to_hex_string(response)
+        );
+        let result =
+            challenge::verify_unlock(&self.challenges, &self.credentials, response, client)
+                .and_then(|request| {
+                    self.unlock.send(request).map_err(|why| {
+                        log::error!("[❌] Failed to tx: {:?}", why);
+                        0x08
+                    })
+                });
+        if let Err(code) = result {
+            self.logs.append(client, LogEntryStatus::Failed(code));
+        }
+        result
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+
+use esp32_nimble::BLEAddress;
+use serde::{Deserialize, Serialize};
+
+use crate::admin::AdminCommand;
+use crate::challenge::Client;
+use crate::credentials::Credentials;
+
+/// The role of an enrolled user, see [`Role::allows`] for what it may do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// the holder of the built-in key or the key which claimed the device (user `0`)
+    Owner,
+    Admin,
+    #[default]
+    User,
+    Guest,
+    Auditor,
+}
+
+/// The operations governed by the roles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Unlock,
+    ReadLogs,
+    ReadMetadata,
+    /// network & hands-free settings, lifetime counters and bonds
+    ChangeConfig,
+    /// enrolling, removing & listing users
+    EnrollUsers,
+    OtaUpdate,
+    FactoryReset,
+}
+
+/// What a client asks the device to do, whatever transport the request came in over
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Unlock,
+    ReadLogs,
+    ReadMetadata,
+    Admin(AdminCommand),
+    /// a write to one of the firmware update characteristics
+    OtaUpdate,
+}
+
+impl Operation {
+    /// what the requester's role has to allow
+    pub fn permission(self) -> Permission {
+        match self {
+            Operation::Unlock => Permission::Unlock,
+            Operation::ReadLogs => Permission::ReadLogs,
+            Operation::ReadMetadata => Permission::ReadMetadata,
+            Operation::Admin(
+                AdminCommand::EnrollUser | AdminCommand::RemoveUser | AdminCommand::ListUsers,
+            ) => Permission::EnrollUsers,
+            Operation::Admin(AdminCommand::FactoryReset) => Permission::FactoryReset,
+            Operation::Admin(_) => Permission::ChangeConfig,
+            Operation::OtaUpdate => Permission::OtaUpdate,
+        }
+    }
+}
+
+impl Role {
+    /// the permission matrix
+    ///
+    /// | | owner | admin | user | guest | auditor |
+    /// |---|---|---|---|---|---|
+    /// | unlock | ✓ | ✓ | ✓ | ✓ | |
+    /// | read logs | ✓ | ✓ | | | ✓ |
+    /// | read metadata | ✓ | ✓ | ✓ | | ✓ |
+    /// | change config | ✓ | ✓ | | | |
+    /// | enroll users | ✓ | ✓ | | | |
+    /// | OTA update | ✓ | ✓ | | | |
+    /// | factory reset | ✓ | | | | |
+    pub fn allows(self, permission: Permission) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Admin => permission != Permission::FactoryReset,
+            Role::User => matches!(permission, Permission::Unlock | Permission::ReadMetadata),
+            Role::Guest => permission == Permission::Unlock,
+            Role::Auditor => matches!(permission, Permission::ReadLogs | Permission::ReadMetadata),
+        }
+    }
+}
+
+/// The central permission check every operation goes through (see [`Roles::dispatch`]); BLE clients prove their key once
+/// per connection (a session) to read the logs & metadata or update the firmware
+pub struct Roles {
+    credentials: Arc<Credentials>,
+    /// the connections (handles) which signed a challenge and their users
+    sessions: Mutex<Vec<(u16, u16)>>,
+}
+
+impl Roles {
+    pub fn new(credentials: Arc<Credentials>) -> Self {
+        Roles {
+            credentials,
+            sessions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// binds the connection to `user` until it closes
+    pub fn open_session(&self, address: BLEAddress, conn_handle: u16, user: u16) {
+        log::info!("[🎫] ({}) Authenticated as user {}", address, user);
+        let mut sessions = self.sessions.lock().unwrap_or_else(|x| x.into_inner());
+        sessions.retain(|(x, _)| *x != conn_handle);
+        sessions.push((conn_handle, user));
+    }
+
+    pub fn close_session(&self, conn_handle: u16) {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .retain(|(x, _)| *x != conn_handle);
+    }
+
+    /// the device waits for a new owner to claim it after a factory reset
+    pub fn enrollment_mode(&self) -> bool {
+        !self.credentials.has_owner()
+    }
+
+    /// the user the client authenticated as (BLE sessions only)
+    fn session(&self, client: &Client) -> Option<u16> {
+        let Client::Ble(_, conn_handle) = client else {
+            return None;
+        };
+        self.session_user(*conn_handle)
+    }
+
+    fn session_user(&self, conn_handle: u16) -> Option<u16> {
+        self.sessions
+            .lock()
+            .unwrap_or_else(|x| x.into_inner())
+            .iter()
+            .find(|(x, _)| *x == conn_handle)
+            .map(|(_, user)| *user)
+    }
+
+    /// the connection authenticated as the owner or an admin
+    pub fn admin_session(&self, conn_handle: u16) -> bool {
+        self.session_user(conn_handle)
+            .and_then(|x| self.credentials.role(x))
+            .is_some_and(|x| matches!(x, Role::Owner | Role::Admin))
+    }
+
+    /// the dispatcher every operation goes through, from any characteristic or transport: resolves
+    /// the requester (the signer of a signed request, otherwise the client's session), checks its
+    /// role against the operation's [`Permission`] and only then runs `handler` with the role
+    ///
+    /// Unlocks without a user (the exit button, trusted Home Assistant commands) aren't subject to
+    /// a role, `handler` gets `None` for them.
+    ///
+    /// Errors with `0x19` if the role doesn't allow the operation (or if there is no such user)
+    /// and with the codes of `handler`
+    pub fn dispatch<T>(
+        &self,
+        client: &Client,
+        signer: Option<u16>,
+        operation: Operation,
+        handler: impl FnOnce(Option<Role>) -> Result<T, i32>,
+    ) -> Result<T, i32> {
+        if operation == Operation::Unlock
+            && signer.is_none()
+            && matches!(client, Client::Button | Client::Mqtt(_))
+        {
+            return handler(None);
+        }
+        let user = signer.or_else(|| self.session(client));
+        match user.and_then(|x| self.credentials.role(x)) {
+            Some(role) if role.allows(operation.permission()) => handler(Some(role)),
+            role => {
+                log::error!(
+                    "[⛔] ({}) {:?} denied for user {:?} ({:?})",
+                    client,
+                    operation,
+                    user,
+                    role
+                );
+                Err(0x19)
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use esp32_nimble::enums::{AuthReq, SecurityIOCap};
+use esp32_nimble::{BLEConnDesc, BLEDevice, BLEError, NimbleProperties};
+use rand::{thread_rng, Rng};
+
+/// Link requirements of the characteristics, see `ADMIN_REQUIRE_BOND` & `LOCK_REQUIRE_ENCRYPTION`
+/// in `build.rs`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SecurityPolicy {
+    /// admin & firmware update operations need an authenticated (passkey), bonded LESC link
+    pub admin_requires_bond: bool,
+    /// unlocking needs an encrypted link
+    pub lock_requires_encryption: bool,
+}
+
+impl SecurityPolicy {
+    pub fn enabled(&self) -> bool {
+        self.admin_requires_bond || self.lock_requires_encryption
+    }
+
+    /// `properties` with the encryption & authentication flags required for admin operations
+    pub fn admin_properties(&self, properties: NimbleProperties) -> NimbleProperties {
+        if !self.admin_requires_bond {
+            return properties;
+        }
+        let mut res = properties;
+        if properties.contains(NimbleProperties::READ) {
+            res |= NimbleProperties::READ_ENC | NimbleProperties::READ_AUTHEN;
+        }
+        if properties.intersects(NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP) {
+            res |= NimbleProperties::WRITE_ENC | NimbleProperties::WRITE_AUTHEN;
+        }
+        res
+    }
+
+    /// `properties` with the encryption flags required for unlocking
+    pub fn lock_properties(&self, properties: NimbleProperties) -> NimbleProperties {
+        if !self.lock_requires_encryption {
+            return properties;
+        }
+        let mut res = properties;
+        if properties.contains(NimbleProperties::READ) {
+            res |= NimbleProperties::READ_ENC;
+        }
+        if properties.contains(NimbleProperties::WRITE) {
+            res |= NimbleProperties::WRITE_ENC;
+        }
+        res
+    }
+
+    /// checks the link of a command protocol request
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x0F` the link doesn't meet the policy
+    pub fn check(&self, link: &LinkSecurity, admin: bool) -> Result<(), i32> {
+        let sufficient = if admin {
+            !self.admin_requires_bond || (link.authenticated && link.bonded)
+        } else {
+            !self.lock_requires_encryption || link.encrypted
+        };
+        if !sufficient {
+            log::error!("[⛔] Request denied: insufficient link security {:?}", link);
+            return Err(0x0F);
+        }
+        Ok(())
+    }
+}
+
+/// The security state of a connection
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkSecurity {
+    pub encrypted: bool,
+    pub authenticated: bool,
+    pub bonded: bool,
+}
+impl From<&BLEConnDesc> for LinkSecurity {
+    fn from(desc: &BLEConnDesc) -> Self {
+        LinkSecurity {
+            encrypted: desc.encrypted(),
+            authenticated: desc.authenticated(),
+            bonded: desc.bonded(),
+        }
+    }
+}
+
+/// enables LESC bonding with a random passkey (the device has no display, the passkey is shown
+/// through the status LED, see [`passkey_sequence`])
+pub fn enable(device: &mut BLEDevice) -> u32 {
+    let passkey = thread_rng().gen_range(0..1_000_000);
+    device
+        .security()
+        .set_auth(AuthReq::Bond | AuthReq::Mitm | AuthReq::Sc)
+        .set_passkey(passkey)
+        .set_io_cap(SecurityIOCap::DisplayOnly)
+        .resolve_rpa();
+    passkey
+}
+
+/// the blink sequence of the 6 passkey digits: each digit is blinked n times (10 times for a 0)
+/// followed by a pause
+pub fn passkey_sequence(passkey: u32) -> Vec<bool> {
+    format!("{:06}", passkey)
+        .chars()
+        .filter_map(|x| x.to_digit(10))
+        .flat_map(|x| {
+            let count = if x == 0 { 10 } else { x as usize };
+            std::iter::repeat(true)
+                .take(count)
+                .chain([false, false, false])
+        })
+        .collect()
+}
+
+/// the addresses of all bonded peers
+pub fn bonds() -> Result<Vec<String>, BLEError> {
+    Ok(BLEDevice::take()
+        .bonded_addresses()?
+        .iter()
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// deletes the bond with `address` or all bonds
+pub fn clear_bonds(address: Option<&str>) -> Result<(), BLEError> {
+    let device = BLEDevice::take();
+    match address {
+        Some(address) => {
+            for bond in device.bonded_addresses()? {
+                if bond.to_string().eq_ignore_ascii_case(address) {
+                    device.delete_bond(&bond)?;
+                }
+            }
+            Ok(())
+        }
+        None => device.delete_all_bonds(),
+    }
+}
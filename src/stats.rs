@@ -0,0 +1,162 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use serde::{Deserialize, Serialize};
+
+use crate::logs::LogEntryStatus;
+
+const NVS_NAMESPACE: &str = "gax_stats";
+const NVS_KEY: &str = "lifetime";
+/// The lifetime counters are only written back this often to limit the flash wear
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// The counters take a few hundred bytes; a larger blob is corrupt and resets them
+const MAX_BLOB_LEN: usize = 4 * 1024;
+
+/// Counts of the opening attempts since boot
+#[derive(Debug, Default)]
+pub struct OpeningCounters {
+    pub successful: AtomicU32,
+    pub failed: AtomicU32,
+}
+
+/// Counters which survive a reboot (persisted in NVS)
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LifetimeCounters {
+    pub power_on_seconds: u64,
+    /// number of times the relay has been switched on (for wear planning)
+    pub relay_cycles: u32,
+    pub successful_unlocks: u32,
+    /// failed unlocks keyed by their error code
+    pub failed_unlocks: BTreeMap<i32, u32>,
+    pub boot_count: u32,
+    pub brownout_count: u32,
+}
+
+struct LifetimeState {
+    counters: LifetimeCounters,
+    last_update: Instant,
+}
+impl LifetimeState {
+    /// moves the power on time since the last update into the counters
+    fn update_power_on(&mut self) {
+        let elapsed = self.last_update.elapsed().as_secs();
+        self.counters.power_on_seconds += elapsed;
+        // keep the fraction of a second for the next update
+        self.last_update += Duration::from_secs(elapsed);
+    }
+}
+
+pub struct Stats {
+    pub session: OpeningCounters,
+    lifetime: Mutex<LifetimeState>,
+    nvs: Mutex<EspNvs<NvsDefault>>,
+}
+
+impl Stats {
+    /// loads the lifetime counters and records the current boot; counters which can't be read are
+    /// reset instead of failing the boot
+    pub fn load(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let mut counters = match read_counters(&nvs) {
+            Ok(x) => x.unwrap_or_default(),
+            Err(why) => {
+                log::error!("[❌] Resetting the lifetime counters: {}", why);
+                LifetimeCounters::default()
+            }
+        };
+        counters.boot_count += 1;
+        if crate::device_info::reset_reason() == "brownout" {
+            counters.brownout_count += 1;
+        }
+        let stats = Stats {
+            session: OpeningCounters::default(),
+            lifetime: Mutex::new(LifetimeState {
+                counters,
+                last_update: Instant::now(),
+            }),
+            nvs: Mutex::new(nvs),
+        };
+        stats.flush()?;
+        Ok(stats)
+    }
+
+    pub fn record(&self, status: &LogEntryStatus) {
+        let mut lifetime = self.lifetime.lock().unwrap_or_else(|x| x.into_inner());
+        match status {
+            LogEntryStatus::Successful | LogEntryStatus::Duress => {
+                self.session.successful.fetch_add(1, Ordering::Relaxed);
+                lifetime.counters.successful_unlocks += 1;
+            }
+            LogEntryStatus::Failed(code) => {
+                self.session.failed.fetch_add(1, Ordering::Relaxed);
+                *lifetime.counters.failed_unlocks.entry(*code).or_default() += 1;
+            }
+            LogEntryStatus::Restarted { .. } => {}
+        }
+    }
+
+    pub fn record_relay_cycle(&self) {
+        let mut lifetime = self.lifetime.lock().unwrap_or_else(|x| x.into_inner());
+        lifetime.counters.relay_cycles += 1;
+    }
+
+    /// the current lifetime counters, including the not yet flushed power on time
+    pub fn snapshot(&self) -> LifetimeCounters {
+        let mut lifetime = self.lifetime.lock().unwrap_or_else(|x| x.into_inner());
+        lifetime.update_power_on();
+        lifetime.counters.clone()
+    }
+
+    pub fn flush(&self) -> Result<(), EspError> {
+        let data = {
+            let mut lifetime = self.lifetime.lock().unwrap_or_else(|x| x.into_inner());
+            lifetime.update_power_on();
+            serde_json::to_vec(&lifetime.counters).expect("LifetimeCounters are serializable")
+        };
+        let mut nvs = self.nvs.lock().unwrap_or_else(|x| x.into_inner());
+        nvs.set_raw(NVS_KEY, &data)?;
+        Ok(())
+    }
+
+    /// resets all lifetime counters (admin command)
+    pub fn reset(&self) -> Result<(), EspError> {
+        {
+            let mut lifetime = self.lifetime.lock().unwrap_or_else(|x| x.into_inner());
+            lifetime.counters = LifetimeCounters::default();
+            lifetime.last_update = Instant::now();
+        }
+        self.flush()
+    }
+}
+
+/// the persisted counters; errors with the reason they are unusable
+fn read_counters(nvs: &EspNvs<NvsDefault>) -> Result<Option<LifetimeCounters>, String> {
+    let len = match nvs.blob_len(NVS_KEY).map_err(|why| format!("{:?}", why))? {
+        Some(x) if x > MAX_BLOB_LEN => return Err(format!("{} bytes are too many", x)),
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let mut buf = vec![0x0; len];
+    match nvs.get_raw(NVS_KEY, &mut buf) {
+        Ok(Some(x)) => serde_json::from_slice(x)
+            .map(Some)
+            .map_err(|why| why.to_string()),
+        Ok(None) => Ok(None),
+        Err(why) => Err(format!("{:?}", why)),
+    }
+}
+
+/// periodically writes the lifetime counters to NVS
+pub fn spawn_flush_task(stats: Arc<Stats>) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(FLUSH_INTERVAL);
+        match stats.flush() {
+            Ok(_) => log::info!("[💾] Flushed lifetime counters"),
+            Err(why) => log::error!("[❌] Failed to flush lifetime counters: {:?}", why),
+        }
+    });
+}
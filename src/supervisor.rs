@@ -0,0 +1,179 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp32_nimble::BLEDevice;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::{
+    ble_hs_sched_reset, ble_hs_synced, esp, esp_restart, esp_task_wdt_add, esp_task_wdt_delete,
+    esp_task_wdt_reset, EspError, BLE_HS_ETIMEOUT, CONFIG_BT_NIMBLE_MAX_CONNECTIONS,
+};
+
+use crate::connections::{self, Connections};
+use crate::stats::Stats;
+
+const NVS_NAMESPACE: &str = "gax_super";
+const NVS_KEY: &str = "reason";
+/// Watched loops have to feed the watchdog at least this often (the task watchdog fires after
+/// `CONFIG_ESP_TASK_WDT_TIMEOUT_S`)
+pub const FEED_INTERVAL: Duration = Duration::from_secs(5);
+/// Advertising is restarted if it stopped while there are free slots for this long
+const ADVERTISING_GRACE: Duration = Duration::from_secs(15);
+/// The BLE host is reset (it drops all connections and resyncs with the controller) if it stays
+/// unsynced or advertising can't be restarted this long
+const HOST_RESET_AFTER: Duration = Duration::from_secs(30);
+/// The device reboots if the BLE host stays unsynced or advertising can't be restarted this long
+const STUCK_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Subscribes the calling thread to the ESP-IDF task watchdog, which reboots the device if the
+/// thread doesn't [`feed`](Watchdog::feed) it in time
+pub struct Watchdog {
+    name: &'static str,
+    subscribed: bool,
+}
+
+impl Watchdog {
+    /// a failed subscription is logged; the thread keeps running unwatched
+    pub fn subscribe(name: &'static str) -> Self {
+        let subscribed = match esp!(unsafe { esp_task_wdt_add(std::ptr::null_mut()) }) {
+            Ok(_) => {
+                log::info!("[🐕] Watching '{}'", name);
+                true
+            }
+            Err(why) => {
+                log::error!("[❌] Failed to watch '{}': {:?}", name, why);
+                false
+            }
+        };
+        Watchdog { name, subscribed }
+    }
+
+    pub fn feed(&self) {
+        if !self.subscribed {
+            return;
+        }
+        if let Err(why) = esp!(unsafe { esp_task_wdt_reset() }) {
+            log::error!(
+                "[❌] Failed to feed the watchdog of '{}': {:?}",
+                self.name,
+                why
+            );
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if self.subscribed {
+            unsafe { esp_task_wdt_delete(std::ptr::null_mut()) };
+        }
+    }
+}
+
+/// the reason of the last reboot triggered by the supervisor (cleared once read)
+pub fn take_last_reason(partition: EspDefaultNvsPartition) -> Result<Option<String>, EspError> {
+    let mut nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut buf: [u8; 64] = [0x0; 64];
+    let reason = nvs.get_str(NVS_KEY, &mut buf)?.map(|x| x.to_owned());
+    if reason.is_some() {
+        nvs.remove(NVS_KEY)?;
+    }
+    Ok(reason)
+}
+
+struct Supervisor {
+    nvs: EspNvs<NvsDefault>,
+    stats: Arc<Stats>,
+    connections: Arc<Connections>,
+    unsynced_since: Option<Instant>,
+    not_advertising_since: Option<Instant>,
+    /// the BLE host has been reset since it stopped working
+    host_reset: bool,
+}
+
+impl Supervisor {
+    fn check(&mut self) {
+        let now = Instant::now();
+        if unsafe { ble_hs_synced() } == 0 {
+            let since = *self.unsynced_since.get_or_insert(now);
+            if now - since > STUCK_TIMEOUT {
+                self.reboot("ble_host_unsynced");
+            } else if now - since > HOST_RESET_AFTER {
+                self.reset_host("unsynced");
+            }
+            // advertising can't work without the host
+            return;
+        }
+        self.unsynced_since = None;
+
+        let slots_free = self.connections.count() < CONFIG_BT_NIMBLE_MAX_CONNECTIONS as usize;
+        let advertising = BLEDevice::take().get_advertising().lock().is_advertising();
+        if advertising || !slots_free {
+            self.not_advertising_since = None;
+            self.host_reset = false;
+            return;
+        }
+        let since = *self.not_advertising_since.get_or_insert(now);
+        if now - since > STUCK_TIMEOUT {
+            self.reboot("advertising_stuck");
+        }
+        if now - since > ADVERTISING_GRACE {
+            log::error!("[🐕] Advertising stopped, restarting it");
+            connections::ensure_advertising();
+        }
+        if now - since > HOST_RESET_AFTER {
+            self.reset_host("advertising_stuck");
+        }
+    }
+
+    /// has the NimBLE host reset the stack and resync with the controller, once until it works
+    /// again; the connections are closed (their disconnect handlers run)
+    fn reset_host(&mut self, reason: &str) {
+        if self.host_reset {
+            return;
+        }
+        self.host_reset = true;
+        log::error!("[🐕] Resetting the BLE host: {}", reason);
+        unsafe { ble_hs_sched_reset(BLE_HS_ETIMEOUT as i32) };
+    }
+
+    /// records `reason` & reboots
+    fn reboot(&mut self, reason: &str) -> ! {
+        log::error!("[🐕] Rebooting: {}", reason);
+        if let Err(why) = self.nvs.set_str(NVS_KEY, reason) {
+            log::error!("[❌] Failed to store the reboot reason: {:?}", why);
+        }
+        if let Err(why) = self.stats.flush() {
+            log::error!("[❌] Failed to flush lifetime counters: {:?}", why);
+        }
+        unsafe { esp_restart() }
+    }
+}
+
+/// watches the BLE host & advertising, resets the host or reboots if they stop working; the
+/// supervisor itself is guarded by the task watchdog
+pub fn spawn(
+    partition: EspDefaultNvsPartition,
+    stats: Arc<Stats>,
+    connections: Arc<Connections>,
+) -> Result<(), EspError> {
+    let mut supervisor = Supervisor {
+        nvs: EspNvs::new(partition, NVS_NAMESPACE, true)?,
+        stats,
+        connections,
+        unsynced_since: None,
+        not_advertising_since: None,
+        host_reset: false,
+    };
+    std::thread::Builder::new()
+        .stack_size(6 * 1024)
+        .spawn(move || {
+            let watchdog = Watchdog::subscribe("supervisor");
+            loop {
+                std::thread::sleep(FEED_INTERVAL);
+                supervisor.check();
+                watchdog.feed();
+            }
+        })
+        .map_err(|_| EspError::from_infallible::<{ esp_idf_svc::sys::ESP_ERR_NO_MEM }>())?;
+    Ok(())
+}
@@ -0,0 +1,91 @@
+use std::time::{Duration, Instant};
+
+use crate::challenge::{Client, Factor, UnlockRequest};
+
+/// After a signed request the user has this long to enter their PIN on the keypad
+pub const PIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The two-factor policy: a signed request only opens the gate together with the user's PIN,
+/// entered in the app (sent along with the signature) or on the keypad afterwards; a card or tag
+/// needs the PIN on the keypad as well. Only the exit button opens without any factor, unsigned
+/// remote requests (trusted Home Assistant commands) are rejected
+pub struct TwoFactor {
+    enabled: bool,
+    /// a signed request waiting for the PIN
+    pending: Option<(UnlockRequest, Instant)>,
+}
+
+impl TwoFactor {
+    pub fn new(enabled: bool) -> Self {
+        TwoFactor {
+            enabled,
+            pending: None,
+        }
+    }
+
+    /// the request to carry out, `None` if it waits for the PIN
+    ///
+    /// Errors with the code the request is logged with:
+    /// - `0x1C` the PIN entered on the keypad isn't the one of the waiting user
+    /// - `0x1D` a PIN has been entered without a signed request (or a card)
+    /// - `0x25` a remote request isn't signed by a user (a trusted Home Assistant command)
+    pub fn check(&mut self, request: UnlockRequest) -> Result<Option<UnlockRequest>, i32> {
+        if !self.enabled {
+            return Ok(Some(request));
+        }
+        match request.factor {
+            Factor::Signature | Factor::Card => {
+                log::info!(
+                    "[🔢] ({}) Waiting for the PIN of user {:?}",
+                    request.client,
+                    request.user
+                );
+                self.pending = Some((request, Instant::now()));
+                Ok(None)
+            }
+            Factor::Pin => {
+                let pending = self.pending.take().filter(|(_, since)| {
+                    let expired = since.elapsed() > PIN_TIMEOUT;
+                    if expired {
+                        log::info!("[🔢] The signed request expired");
+                    }
+                    !expired
+                });
+                match pending {
+                    Some((pending, _)) if pending.user == request.user => Ok(Some(UnlockRequest {
+                        factor: match pending.factor {
+                            Factor::Card => Factor::CardAndPin,
+                            _ => Factor::SignatureAndPin,
+                        },
+                        duress: pending.duress || request.duress,
+                        ..pending
+                    })),
+                    Some(pending) => {
+                        log::error!(
+                            "[⛔] ({}) Request denied: wrong PIN of user {:?}",
+                            pending.0.client,
+                            pending.0.user
+                        );
+                        self.pending = Some(pending);
+                        Err(0x1C)
+                    }
+                    None => {
+                        log::error!(
+                            "[⛔] ({}) Request denied: no signed request or card",
+                            request.client
+                        );
+                        Err(0x1D)
+                    }
+                }
+            }
+            Factor::None if request.client != Client::Button => {
+                log::error!(
+                    "[⛔] ({}) Request denied: unsigned requests need a PIN as well",
+                    request.client
+                );
+                Err(0x25)
+            }
+            Factor::None | Factor::SignatureAndPin | Factor::CardAndPin => Ok(Some(request)),
+        }
+    }
+}
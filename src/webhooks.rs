@@ -0,0 +1,107 @@
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::{Duration, UNIX_EPOCH};
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use gax_webhooks::{Entry, Notifier, QueueStore, Status, Transport, RETRY_INTERVAL};
+use serde_json::json;
+
+pub use gax_webhooks::WebhookTarget;
+
+use crate::events::GateEvent;
+use crate::logs::{LogEntry, LogEntryStatus};
+
+const NVS_NAMESPACE: &str = "gax_hooks";
+const NVS_KEY: &str = "queue";
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// POSTs every matching log entry as json to the configured targets:
+/// `{"device_id", "credential", "user", "second_user", "status", "source", "timestamp"}`
+///
+/// Failed deliveries are kept in a bounded queue (persisted in NVS) and retried periodically;
+/// see [`gax_webhooks::Notifier`].
+pub fn start(
+    targets: Vec<WebhookTarget>,
+    device_id: String,
+    partition: EspDefaultNvsPartition,
+    events: Receiver<GateEvent>,
+) -> Result<(), EspError> {
+    if targets.is_empty() {
+        log::info!("[🪝] No webhooks configured");
+        return Ok(());
+    }
+    let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+    let mut notifier = Notifier::new(targets, device_id, EspTransport, NvsQueue(nvs))?;
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || loop {
+            match events.recv_timeout(RETRY_INTERVAL) {
+                Ok(GateEvent::Log(entry)) => notifier.notify(&notified(&entry)),
+                Ok(_) => continue,
+                Err(RecvTimeoutError::Timeout) => notifier.retry(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        })
+        .map_err(|_| EspError::from_infallible::<{ esp_idf_svc::sys::ESP_ERR_NO_MEM }>())?;
+    Ok(())
+}
+
+/// the log entry as the notifier sees it
+fn notified(entry: &LogEntry) -> Entry {
+    Entry {
+        status: match entry.status {
+            LogEntryStatus::Successful => Status::Successful,
+            LogEntryStatus::Failed(x) => Status::Failed(x),
+            LogEntryStatus::Duress => Status::Duress,
+            LogEntryStatus::Restarted { .. } => Status::Other,
+        },
+        fields: json!({
+            "credential": entry.credential,
+            "user": entry.user,
+            "second_user": entry.second_user,
+            "status": entry.status,
+            "source": entry.source,
+        }),
+        timestamp: entry
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs())
+            .unwrap_or(0),
+    }
+}
+
+struct EspTransport;
+
+impl Transport for EspTransport {
+    type Error = EspError;
+
+    fn post(&mut self, url: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<u16, EspError> {
+        let mut conn = EspHttpConnection::new(&Configuration {
+            timeout: Some(HTTP_TIMEOUT),
+            crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+        conn.initiate_request(Method::Post, url, headers)?;
+        conn.write_all(body)?;
+        conn.initiate_response()?;
+        Ok(conn.status())
+    }
+}
+
+struct NvsQueue(EspNvs<NvsDefault>);
+
+impl QueueStore for NvsQueue {
+    type Error = EspError;
+
+    fn load(&mut self) -> Result<Option<Vec<u8>>, EspError> {
+        let mut buf = vec![0x0; self.0.blob_len(NVS_KEY)?.unwrap_or(0)];
+        Ok(self.0.get_raw(NVS_KEY, &mut buf)?.map(|x| x.to_vec()))
+    }
+
+    fn store(&mut self, data: &[u8]) -> Result<(), EspError> {
+        self.0.set_raw(NVS_KEY, data).map(|_| ())
+    }
+}
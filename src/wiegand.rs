@@ -0,0 +1,199 @@
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use esp_idf_svc::hal::gpio::{Input, InputPin, InterruptType, PinDriver};
+use esp_idf_svc::sys::{
+    esp, esp_timer_get_time, gpio_install_isr_service, gpio_intr_enable, gpio_isr_handler_add,
+    EspError, ESP_ERR_INVALID_STATE, ESP_OK,
+};
+
+use crate::challenge::{Client, Factor, UnlockRequest};
+use crate::credentials::{CredentialId, Credentials};
+use crate::logs::{LogEntryStatus, LogStore};
+use crate::supervisor::Watchdog;
+
+/// A frame is complete once no pulse arrived for this long
+const FRAME_GAP: Duration = Duration::from_millis(25);
+/// Digits of a PIN entry are dropped after this long without a key press
+const PIN_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PIN_LEN: usize = 8;
+const KEY_CLEAR: u8 = 10; // '*'
+const KEY_ENTER: u8 = 11; // '#'
+
+// the bits received since the last frame (written by the ISR, MSB first)
+static BITS_HI: AtomicU32 = AtomicU32::new(0);
+static BITS_LO: AtomicU32 = AtomicU32::new(0);
+static BIT_COUNT: AtomicU32 = AtomicU32::new(0);
+static LAST_PULSE: AtomicU32 = AtomicU32::new(0);
+
+/// ISR of both data lines; `arg` is the bit the line stands for (D0: 0, D1: 1)
+unsafe extern "C" fn on_pulse(arg: *mut c_void) {
+    let count = BIT_COUNT.load(Ordering::Relaxed);
+    if count < 64 {
+        let (hi, lo) = (
+            BITS_HI.load(Ordering::Relaxed),
+            BITS_LO.load(Ordering::Relaxed),
+        );
+        BITS_HI.store(hi << 1 | lo >> 31, Ordering::Relaxed);
+        BITS_LO.store(lo << 1 | arg as u32, Ordering::Relaxed);
+        BIT_COUNT.store(count + 1, Ordering::Relaxed);
+    }
+    LAST_PULSE.store(esp_timer_get_time() as u32, Ordering::Relaxed);
+}
+
+/// the received frame `(bits, bit count)` if the reader finished sending it
+fn take_frame() -> Option<(u64, u32)> {
+    let count = BIT_COUNT.load(Ordering::Relaxed);
+    let since =
+        (unsafe { esp_timer_get_time() } as u32).wrapping_sub(LAST_PULSE.load(Ordering::Relaxed));
+    if count == 0 || since < FRAME_GAP.as_micros() as u32 {
+        return None;
+    }
+    let bits =
+        (BITS_HI.load(Ordering::Relaxed) as u64) << 32 | BITS_LO.load(Ordering::Relaxed) as u64;
+    BITS_HI.store(0, Ordering::Relaxed);
+    BITS_LO.store(0, Ordering::Relaxed);
+    BIT_COUNT.store(0, Ordering::Relaxed);
+    Some((bits, count))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frame {
+    /// a keypad key (0-9, `*` is 10, `#` is 11)
+    Key(u8),
+    /// `facility << 16 | card`
+    Card(u32),
+}
+
+/// the leading parity bit makes the first half even, the trailing one the second half odd
+fn parity_ok(bits: u64, len: u32) -> bool {
+    let half = len / 2;
+    let first = bits >> half;
+    let second = bits & ((1 << half) - 1);
+    first.count_ones() % 2 == 0 && second.count_ones() % 2 == 1
+}
+
+fn decode(bits: u64, len: u32) -> Option<Frame> {
+    match len {
+        // keypads sending every key as a 4-bit burst
+        4 => Some(Frame::Key(bits as u8 & 0x0F)),
+        // keypads sending every key as `!key | key`
+        8 => {
+            let key = bits as u8 & 0x0F;
+            (bits as u8 >> 4 == !key & 0x0F).then_some(Frame::Key(key))
+        }
+        // 26-bit: P | 8-bit facility | 16-bit card | P, 34-bit: P | 16-bit facility | 16-bit card | P
+        26 | 34 if parity_ok(bits, len) => {
+            // drop both parity bits, the leading one would end up in bit 24 of a 26-bit card
+            let data = (bits >> 1) & ((1 << (len - 2)) - 1);
+            Some(Frame::Card(data as u32))
+        }
+        _ => None,
+    }
+}
+
+/// A Wiegand reader on the data lines D0 & D1
+pub struct Reader<P0: InputPin, P1: InputPin> {
+    // the drivers have to stay alive, dropping them resets the pins
+    _d0: PinDriver<'static, P0, Input>,
+    _d1: PinDriver<'static, P1, Input>,
+}
+
+impl<P0: InputPin, P1: InputPin> Reader<P0, P1> {
+    pub fn new(
+        mut d0: PinDriver<'static, P0, Input>,
+        mut d1: PinDriver<'static, P1, Input>,
+    ) -> Result<Self, EspError> {
+        // the service might have been installed already
+        let installed = unsafe { gpio_install_isr_service(0) };
+        if installed != ESP_OK && installed != ESP_ERR_INVALID_STATE {
+            esp!(installed)?;
+        }
+        for (pin, bit) in [(d0.pin(), 0usize), (d1.pin(), 1usize)] {
+            esp!(unsafe { gpio_isr_handler_add(pin, Some(on_pulse), bit as *mut c_void) })?;
+        }
+        d0.set_interrupt_type(InterruptType::NegEdge)?;
+        d1.set_interrupt_type(InterruptType::NegEdge)?;
+        esp!(unsafe { gpio_intr_enable(d0.pin()) })?;
+        esp!(unsafe { gpio_intr_enable(d1.pin()) })?;
+        Ok(Reader { _d0: d0, _d1: d1 })
+    }
+}
+
+/// Reads 26/34-bit cards and keypads (4- or 8-bit keys, a PIN is entered with `#` and cleared with
+/// `*`); enrolled cards & PINs open the gate, unknown ones are logged with `0x18`
+pub fn spawn<P0: InputPin, P1: InputPin>(
+    reader: Reader<P0, P1>,
+    credentials: Arc<Credentials>,
+    unlock: Sender<UnlockRequest>,
+    logs: Arc<LogStore>,
+) -> std::io::Result<()> {
+    std::thread::Builder::new()
+        .stack_size(4 * 1024)
+        .spawn(move || {
+            let _reader = reader;
+            let watchdog = Watchdog::subscribe("wiegand");
+            let mut pin = String::new();
+            let mut last_key = Instant::now();
+            loop {
+                std::thread::sleep(FRAME_GAP / 5);
+                watchdog.feed();
+                if !pin.is_empty() && last_key.elapsed() > PIN_TIMEOUT {
+                    log::info!("[⌨️] PIN entry timed out");
+                    pin.clear();
+                }
+                let Some((bits, len)) = take_frame() else {
+                    continue;
+                };
+                // the user & whether it's their duress PIN
+                let (credential, found, factor) = match decode(bits, len) {
+                    Some(Frame::Key(KEY_CLEAR)) => {
+                        pin.clear();
+                        continue;
+                    }
+                    Some(Frame::Key(KEY_ENTER)) => {
+                        let found = credentials.find_pin(&pin);
+                        pin.clear();
+                        (CredentialId::Pin(found.map(|x| x.0)), found, Factor::Pin)
+                    }
+                    Some(Frame::Key(x)) if x < 10 => {
+                        last_key = Instant::now();
+                        if pin.len() < MAX_PIN_LEN {
+                            pin.push(char::from(b'0' + x));
+                        }
+                        continue;
+                    }
+                    Some(Frame::Card(x)) => (
+                        CredentialId::Card(x),
+                        credentials.find_card(x).map(|x| (x, false)),
+                        Factor::Card,
+                    ),
+                    _ => {
+                        log::error!("[❌] Invalid Wiegand frame ({} bits): {:#x}", len, bits);
+                        continue;
+                    }
+                };
+                let client = Client::Wiegand(credential);
+                match found {
+                    Some((user, duress)) => {
+                        log::info!("[⌨️] ({}) User {} identified", client, user);
+                        let request = UnlockRequest {
+                            duress,
+                            ..UnlockRequest::for_user(client, user, factor)
+                        };
+                        if let Err(why) = unlock.send(request) {
+                            log::error!("[❌] Failed to tx: {:?}", why);
+                        }
+                    }
+                    None => {
+                        log::error!("[⛔] ({}) Unknown credential", client);
+                        logs.append(&client, LogEntryStatus::Failed(0x18));
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs};
+use esp_idf_svc::sntp::EspSntp;
+use esp_idf_svc::sys::EspError;
+use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use serde::{Deserialize, Serialize};
+
+const NVS_NAMESPACE: &str = "gax_net";
+const NVS_KEY: &str = "config";
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+/// Upper bound of a stored (json encoded) config
+const MAX_CONFIG_LEN: usize = 4 * 1024;
+
+/// Network credentials, provisioned through the admin characteristic and kept in NVS
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub wifi_ssid: String,
+    pub wifi_password: String,
+    #[serde(default)]
+    pub mqtt_url: Option<String>,
+    #[serde(default)]
+    pub mqtt_user: Option<String>,
+    #[serde(default)]
+    pub mqtt_password: Option<String>,
+    /// defaults to `gax/<device id>`
+    #[serde(default)]
+    pub mqtt_topic_prefix: Option<String>,
+    /// defaults to `homeassistant`
+    #[serde(default)]
+    pub ha_discovery_prefix: Option<String>,
+    /// accept Home Assistant's (unsigned) unlock commands; only enable this on a trusted broker
+    #[serde(default)]
+    pub ha_trusted_commands: bool,
+    #[cfg(feature = "webhooks")]
+    #[serde(default)]
+    pub webhooks: Vec<crate::webhooks::WebhookTarget>,
+}
+
+impl NetworkConfig {
+    pub fn load(partition: EspDefaultNvsPartition) -> Result<Option<Self>, EspError> {
+        let nvs = EspNvs::new(partition, NVS_NAMESPACE, true)?;
+        let len = nvs.blob_len(NVS_KEY)?.unwrap_or(0);
+        if len > MAX_CONFIG_LEN {
+            log::error!("[❌] The stored network config is too large: {} bytes", len);
+            return Ok(None);
+        }
+        let mut buf = vec![0x0; len];
+        Ok(match nvs.get_raw(NVS_KEY, &mut buf)? {
+            Some(x) => match serde_json::from_slice(x) {
+                Ok(x) => Some(x),
+                Err(why) => {
+                    log::error!("[❌] Failed to parse the network config: {}", why);
+                    None
+                }
+            },
+            None => None,
+        })
+    }
+
+    /// validates & stores a json encoded config (admin command)
+    ///
+    /// Errors with the code reported to the client:
+    /// - `0x0A` the config couldn't be stored
+    /// - `0x0B` the config is invalid or longer than 4 KiB
+    pub fn store(partition: EspDefaultNvsPartition, data: &[u8]) -> Result<(), i32> {
+        if data.len() > MAX_CONFIG_LEN {
+            log::error!("[❌] Got a network config of {} bytes", data.len());
+            return Err(0x0B);
+        }
+        if let Err(why) = serde_json::from_slice::<NetworkConfig>(data) {
+            log::error!("[❌] Got an invalid network config: {}", why);
+            return Err(0x0B);
+        }
+        EspNvs::new(partition, NVS_NAMESPACE, true)
+            .and_then(|mut nvs| nvs.set_raw(NVS_KEY, data))
+            .map_err(|why| {
+                log::error!("[❌] Failed to store the network config: {:?}", why);
+                0x0A
+            })?;
+        log::info!("[📶] Stored a new network config, reboot to apply it");
+        Ok(())
+    }
+}
+
+/// connects to the configured access point and keeps the connection alive
+pub fn connect(
+    modem: Modem,
+    sysloop: EspSystemEventLoop,
+    partition: EspDefaultNvsPartition,
+    config: &NetworkConfig,
+) -> Result<(), EspError> {
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::new(modem, sysloop.clone(), Some(partition))?,
+        sysloop,
+    )?;
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: config.wifi_ssid.as_str().try_into().unwrap_or_default(),
+        password: config.wifi_password.as_str().try_into().unwrap_or_default(),
+        auth_method: if config.wifi_password.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    log::info!("[📶] Connecting to '{}'", config.wifi_ssid);
+    if let Err(why) = wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+        // the reconnect task keeps trying
+        log::error!("[❌] Failed to connect to the wifi: {:?}", why);
+    }
+
+    // sets the clock (log timestamps, the buzzer's quiet hours)
+    let sntp = EspSntp::new_default()
+        .map_err(|why| log::error!("[❌] Failed to start SNTP: {:?}", why))
+        .ok();
+
+    std::thread::spawn(move || loop {
+        let _sntp = &sntp;
+        std::thread::sleep(RECONNECT_INTERVAL);
+        if wifi.is_connected().unwrap_or(false) {
+            continue;
+        }
+        log::info!("[📶] Wifi connection lost, reconnecting");
+        if let Err(why) = wifi.connect().and_then(|_| wifi.wait_netif_up()) {
+            log::error!("[❌] Failed to reconnect to the wifi: {:?}", why);
+        }
+    });
+    Ok(())
+}